@@ -1,6 +1,4 @@
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
-use rand::prelude::*;
-use serde_json::Value;
 use std::collections::HashSet;
 use std::env;
 use std::fs::File;
@@ -37,27 +35,27 @@ fn load_embeddings(c: &mut Criterion) {
         b.iter(|| {
             let file = File::open(embedding_file).unwrap();
             let reader = BufReader::new(file);
-
-            for (i, line) in reader.lines().enumerate() {
-                if i >= NUM_ELEMENTS {
-                    break;
-                }
-                let line = line.unwrap();
-                let mut child = Command::new("./target/release/vekta")
-                    .arg("add")
-                    .stdin(Stdio::piped())
-                    .spawn()
-                    .expect("Failed to spawn command");
-
-                let mut stdin = child.stdin.take().expect("Failed to open stdin");
-                stdin
-                    .write_all(line.as_bytes())
-                    .expect("Failed to write to stdin");
-                drop(stdin);
-
-                let output = child.wait_with_output().expect("Failed to read stdout");
-                assert!(output.status.success());
+            let lines: Vec<String> = reader
+                .lines()
+                .take(NUM_ELEMENTS)
+                .map(|l| l.unwrap())
+                .collect();
+
+            // One process reads every line, as a bulk load would
+            let mut child = Command::new("./target/release/vekta")
+                .arg("add")
+                .stdin(Stdio::piped())
+                .spawn()
+                .expect("Failed to spawn command");
+
+            let mut stdin = child.stdin.take().expect("Failed to open stdin");
+            for line in &lines {
+                writeln!(stdin, "{}", line).expect("Failed to write to stdin");
             }
+            drop(stdin);
+
+            let output = child.wait_with_output().expect("Failed to read stdout");
+            assert!(output.status.success());
         })
     });
 }
@@ -81,12 +79,16 @@ fn search_embeddings(c: &mut Criterion) {
 
     let file = File::open(query_file).unwrap();
     let reader = BufReader::new(file);
-    let query_embeddings: Vec<String> = reader.lines().take(NUM_QUERIES).map(|l| l.unwrap()).collect();
+    let query_embeddings: Vec<String> = reader
+        .lines()
+        .take(NUM_QUERIES)
+        .map(|l| l.unwrap())
+        .collect();
 
     let mut group = c.benchmark_group("search_embeddings");
     for search_method in ["exact", "ann", "hybrid"] {
         group.bench_with_input(
-            BenchmarkId::new(&format!("search_{}_queries", NUM_QUERIES), search_method),
+            BenchmarkId::new(format!("search_{}_queries", NUM_QUERIES), search_method),
             &search_method,
             |b, &search_method| {
                 b.iter(|| {
@@ -148,8 +150,8 @@ fn compare_results(exact_results: &str, ann_results: &str, hybrid_results: &str)
         ("ANN", ann_results),
         ("Hybrid", hybrid_results),
     ] {
-        let json: serde_json::Value =
-            serde_json::from_str(results).expect(&format!("Failed to parse {} JSON", method));
+        let json: serde_json::Value = serde_json::from_str(results)
+            .unwrap_or_else(|_| panic!("Failed to parse {} JSON", method));
 
         assert!(
             json["query"]["label"].is_string(),
@@ -1,10 +1,8 @@
 use rand::prelude::*;
 use rand_distr::{Distribution, Normal};
-use std::collections::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
 
-use crate::config::Number;
-use crate::vector_ops::compute_cosine_similarity_simd;
-use crate::database::VectorDatabase;
+use crate::config::{verbose_print, Number};
 
 const SEED: u64 = 42;
 const MIN_PROJECTIONS: usize = 2;
@@ -12,21 +10,23 @@ const MAX_PROJECTIONS: usize = 16;
 const MIN_TABLES: usize = 1;
 const MAX_TABLES: usize = 8;
 
+/// Random-hyperplane LSH. The index itself only holds the projection matrix;
+/// the hash tables live in LMDB as `bucket key -> row` pairs, so they can be
+/// updated in the same transaction as the entries they point to.
+#[derive(Serialize, Deserialize)]
 pub struct RandomProjectionIndex {
     random_vectors: Vec<Vec<Number>>,
-    hash_tables: Vec<HashMap<u64, Vec<usize>>>,
     num_tables: usize,
     num_projections: usize,
-    db: VectorDatabase,
 }
 
 impl RandomProjectionIndex {
-    pub fn new(dim: usize, data_size: usize, db: &VectorDatabase) -> Self {
+    pub fn new(dim: usize, data_size: usize) -> Self {
         let (num_projections, num_tables) = Self::calculate_params(data_size);
-        println!(
-            "Debug: Using {} projections and {} tables",
+        verbose_print(&format!(
+            "Using {} projections and {} tables",
             num_projections, num_tables
-        );
+        ));
 
         let mut rng = StdRng::seed_from_u64(SEED);
         let normal = Normal::new(0.0, 1.0).unwrap();
@@ -37,10 +37,8 @@ impl RandomProjectionIndex {
 
         RandomProjectionIndex {
             random_vectors,
-            hash_tables: vec![HashMap::new(); num_tables],
             num_tables,
             num_projections,
-            db: db.clone(),
         }
     }
 
@@ -55,118 +53,52 @@ impl RandomProjectionIndex {
         let start = table_index * self.num_projections;
         let end = start + self.num_projections;
 
-        if self.hash_tables[0].len() <= 4 {
-            // For very small datasets
-            self.random_vectors[start..end]
-                .iter()
-                .map(|rv| {
-                    rv.iter()
-                        .zip(vector.iter())
-                        .map(|(&a, &b)| a * b)
-                        .sum::<Number>()
-                })
-                .enumerate()
-                .fold(0u64, |acc, (i, proj)| {
-                    if proj >= -0.1 {
+        self.random_vectors[start..end]
+            .iter()
+            .map(|rv| {
+                rv.iter()
+                    .zip(vector.iter())
+                    .map(|(&a, &b)| a * b)
+                    .sum::<Number>()
+            })
+            .enumerate()
+            .fold(
+                0u64,
+                |acc, (i, proj)| {
+                    if proj >= 0.0 {
                         acc | (1 << i)
                     } else {
                         acc
-                    } // More lenient threshold
-                })
-        } else {
-            // Original hashing for larger datasets
-            self.random_vectors[start..end]
-                .iter()
-                .map(|rv| {
-                    rv.iter()
-                        .zip(vector.iter())
-                        .map(|(&a, &b)| a * b)
-                        .sum::<Number>()
-                })
-                .enumerate()
-                .fold(
-                    0u64,
-                    |acc, (i, proj)| {
-                        if proj >= 0.0 {
-                            acc | (1 << i)
-                        } else {
-                            acc
-                        }
-                    },
-                )
-        }
+                    }
+                },
+            )
     }
 
-    pub fn add(&mut self, vector: Vec<Number>, index: usize) {
-        for i in 0..self.num_tables {
-            let hash = self.hash_vector(&vector, i);
-            println!(
-                "Debug: Adding vector {} to table {}, hash: {}",
-                index, i, hash
-            );
-            self.hash_tables[i]
-                .entry(hash)
-                .or_insert_with(Vec::new)
-                .push(index);
-        }
+    /// The bucket a vector belongs to in each table.
+    pub fn bucket_keys(&self, vector: &[Number]) -> Vec<[u8; 9]> {
+        (0..self.num_tables)
+            .map(|i| bucket_key(i, self.hash_vector(vector, i)))
+            .collect()
     }
 
-    pub fn search(&self, query: Vec<Number>, k: usize) -> Vec<usize> {
-        let mut candidates = HashSet::new();
-        let similarity_threshold = 0.5; // Adjust this value as needed
-
+    /// The buckets to probe for a query: its own bucket in every table plus
+    /// the neighbouring buckets one bit flip away (multi-probe).
+    pub fn probe_keys(&self, query: &[Number]) -> Vec<[u8; 9]> {
+        let mut keys = Vec::with_capacity(self.num_tables * (self.num_projections + 1));
         for i in 0..self.num_tables {
-            let query_hash = self.hash_vector(&query, i);
-            println!(
-                "Debug: Checking hash table {}, query hash: {}",
-                i, query_hash
-            );
-
-            // Check the exact hash bucket
-            if let Some(bucket) = self.hash_tables[i].get(&query_hash) {
-                println!("Debug: Found {} candidates in bucket", bucket.len());
-                candidates.extend(bucket);
-            }
-
-            // Check neighboring buckets (multi-probe)
+            let query_hash = self.hash_vector(query, i);
+            keys.push(bucket_key(i, query_hash));
             for j in 0..self.num_projections {
-                for flip in 0..2 {
-                    let neighbor_hash = query_hash ^ (flip << j);
-                    if let Some(bucket) = self.hash_tables[i].get(&neighbor_hash) {
-                        println!(
-                            "Debug: Found {} candidates in neighboring bucket (hash: {})",
-                            bucket.len(),
-                            neighbor_hash
-                        );
-                        candidates.extend(bucket);
-                    }
-                }
-            }
-        }
-
-        // Filter candidates based on similarity
-        let filtered_candidates: Vec<usize> = candidates
-            .into_iter()
-            .filter(|&index| {
-                if let Ok(Some(entry)) = self.db.get_entry_by_index(index) {
-                    let similarity = compute_cosine_similarity_simd(&query, &entry.vector);
-                    similarity >= similarity_threshold
-                } else {
-                    false
-                }
-            })
-            .collect();
-
-        filtered_candidates.into_iter().take(k).collect()
-    }
-
-    pub fn print_hash_tables(&self) {
-        for (i, table) in self.hash_tables.iter().enumerate() {
-            println!("Hash Table {}:", i);
-            for (hash, indices) in table {
-                println!("  Hash {}: {:?}", hash, indices);
+                keys.push(bucket_key(i, query_hash ^ (1 << j)));
             }
         }
+        keys
     }
 }
 
+fn bucket_key(table: usize, hash: u64) -> [u8; 9] {
+    let mut key = [0u8; 9];
+    key[0] = table as u8;
+    key[1..].copy_from_slice(&hash.to_be_bytes());
+    key
+}
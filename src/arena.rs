@@ -0,0 +1,227 @@
+use anyhow::{Context, Result};
+use half::{bf16, f16};
+use memmap2::Mmap;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::config::Number;
+use crate::vector_ops::{
+    dequantize_int8, quantize_int8, DimScaledInt8, Precision, ScaledInt8, StoredVector,
+};
+
+pub const ARENA_FILE_NAME: &str = "vectors.bin";
+
+/// Flat, fixed-stride vector file: row `i` starts at byte `i * stride`, where
+/// the stride depends on the storage precision (`dimensions * 4` bytes for
+/// f32). Numbers are stored little-endian.
+/// Which rows are live is tracked in LMDB; the arena only stores the numbers.
+pub struct VectorArena {
+    file: File,
+    dimensions: usize,
+    precision: Precision,
+    /// Per-dimension ranges for `Precision::Int8Dim`, empty otherwise
+    absmax: Vec<Number>,
+    steps: Vec<Number>,
+    stride: usize,
+    map: OnceLock<Option<Mmap>>,
+}
+
+impl VectorArena {
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        dimensions: usize,
+        precision: Precision,
+        absmax: Vec<Number>,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        if precision == Precision::Int8Dim && absmax.len() != dimensions {
+            anyhow::bail!(
+                "int8-dim storage needs {} per-dimension scales, got {}",
+                dimensions,
+                absmax.len()
+            );
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Failed to open vector arena at '{}'", path.display()))?;
+
+        Ok(Self {
+            file,
+            dimensions,
+            precision,
+            steps: absmax.iter().map(|&a| a / 127.0).collect(),
+            absmax,
+            stride: precision.row_bytes(dimensions),
+            map: OnceLock::new(),
+        })
+    }
+
+    pub fn precision(&self) -> Precision {
+        self.precision
+    }
+
+    /// Encode a vector in the arena's precision.
+    fn encode(&self, vector: &[Number]) -> Vec<u8> {
+        match self.precision {
+            Precision::F32 => vector.iter().flat_map(|x| x.to_le_bytes()).collect(),
+            Precision::F16 => vector
+                .iter()
+                .flat_map(|&x| f16::from_f32(x).to_le_bytes())
+                .collect(),
+            Precision::Bf16 => vector
+                .iter()
+                .flat_map(|&x| bf16::from_f32(x).to_le_bytes())
+                .collect(),
+            Precision::Int8 => {
+                let absmax = vector_absmax(vector);
+                let mut bytes = absmax.to_le_bytes().to_vec();
+                bytes.extend(vector.iter().map(|&x| quantize_int8(x, absmax) as u8));
+                bytes
+            }
+            Precision::Int8Dim => vector
+                .iter()
+                .zip(&self.absmax)
+                .map(|(&x, &absmax)| quantize_int8(x, absmax) as u8)
+                .collect(),
+        }
+    }
+
+    /// Fail if the vector has a value that int8-dim storage would clamp to
+    /// its dimension's range. The other precisions store any value.
+    pub fn check_range(&self, vector: &[Number]) -> Result<()> {
+        if self.precision != Precision::Int8Dim {
+            return Ok(());
+        }
+        if let Some((d, (x, absmax))) = vector
+            .iter()
+            .zip(&self.absmax)
+            .enumerate()
+            .find(|(_, (x, absmax))| x.abs() > **absmax)
+        {
+            anyhow::bail!(
+                "Value {} in dimension {} is outside the int8-dim range ±{}; convert the collection to another precision with `vekta quantize` to store it",
+                x,
+                d,
+                absmax
+            );
+        }
+        Ok(())
+    }
+
+    /// The vector as it reads back after being stored. Storing the result
+    /// again gives the same bytes.
+    pub fn round_trip(&self, vector: &[Number]) -> Vec<Number> {
+        match self.precision {
+            Precision::F32 => vector.to_vec(),
+            Precision::F16 => vector.iter().map(|&x| f16::from_f32(x).to_f32()).collect(),
+            Precision::Bf16 => vector.iter().map(|&x| bf16::from_f32(x).to_f32()).collect(),
+            Precision::Int8 => {
+                let absmax = vector_absmax(vector);
+                vector
+                    .iter()
+                    .map(|&x| dequantize_int8(quantize_int8(x, absmax), absmax))
+                    .collect()
+            }
+            Precision::Int8Dim => vector
+                .iter()
+                .zip(&self.absmax)
+                .map(|(&x, &absmax)| dequantize_int8(quantize_int8(x, absmax), absmax))
+                .collect(),
+        }
+    }
+
+    pub fn write(&mut self, row: usize, vector: &[Number]) -> Result<()> {
+        if vector.len() != self.dimensions {
+            anyhow::bail!(
+                "Vector dimension mismatch: expected {}, got {}",
+                self.dimensions,
+                vector.len()
+            );
+        }
+
+        self.file
+            .write_all_at(&self.encode(vector), (row * self.stride) as u64)
+            .with_context(|| format!("Failed to write row {} to vector arena", row))?;
+
+        // The file may have grown, so the next read has to map it again
+        self.map = OnceLock::new();
+        Ok(())
+    }
+
+    /// Flush written rows to disk, so a record committed afterwards never
+    /// points at a row that is lost in a crash.
+    pub fn sync(&self) -> Result<()> {
+        self.file
+            .sync_data()
+            .context("Failed to flush vector arena")
+    }
+
+    /// The whole arena as one contiguous byte slice.
+    fn bytes(&self) -> Result<&[u8]> {
+        if self.map.get().is_none() {
+            let map = if self.file.metadata()?.len() == 0 {
+                None
+            } else {
+                Some(unsafe { Mmap::map(&self.file) }.context("Failed to map vector arena")?)
+            };
+            let _ = self.map.set(map);
+        }
+
+        Ok(self.map.get().and_then(Option::as_deref).unwrap_or(&[]))
+    }
+
+    /// Borrow one encoded row as a typed vector.
+    fn view<'a>(&'a self, bytes: &'a [u8]) -> StoredVector<'a> {
+        // Mappings are page aligned and every stride keeps rows aligned to
+        // their element type; rows are stored in native (little-endian) order
+        unsafe fn cast<T>(bytes: &[u8]) -> &[T] {
+            std::slice::from_raw_parts(
+                bytes.as_ptr() as *const T,
+                bytes.len() / std::mem::size_of::<T>(),
+            )
+        }
+
+        match self.precision {
+            Precision::F32 => StoredVector::F32(unsafe { cast(bytes) }),
+            Precision::F16 => StoredVector::F16(unsafe { cast(bytes) }),
+            Precision::Bf16 => StoredVector::Bf16(unsafe { cast(bytes) }),
+            Precision::Int8 => {
+                let (absmax, codes) = bytes.split_at(4);
+                StoredVector::Int8(ScaledInt8 {
+                    codes: unsafe { cast(codes) },
+                    absmax: Number::from_le_bytes(absmax.try_into().unwrap()),
+                })
+            }
+            Precision::Int8Dim => StoredVector::Int8Dim(DimScaledInt8 {
+                codes: unsafe { cast(bytes) },
+                absmax: &self.absmax,
+                steps: &self.steps,
+            }),
+        }
+    }
+
+    pub fn row(&self, row: usize) -> Result<StoredVector<'_>> {
+        let start = row * self.stride;
+        let bytes = self
+            .bytes()?
+            .get(start..start + self.stride)
+            .with_context(|| format!("Row {} is outside the vector arena", row))?;
+        Ok(self.view(bytes))
+    }
+
+    /// A row converted back to full precision.
+    pub fn decode(&self, row: usize) -> Result<Vec<Number>> {
+        Ok(self.row(row)?.to_vec())
+    }
+}
+
+fn vector_absmax(vector: &[Number]) -> Number {
+    vector.iter().fold(0.0, |max, x| max.max(x.abs()))
+}
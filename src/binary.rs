@@ -0,0 +1,128 @@
+use anyhow::{Context, Result};
+use memmap2::Mmap;
+use rayon::prelude::*;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::config::Number;
+use crate::top_k::TopK;
+
+pub const CODES_FILE_NAME: &str = "codes.bin";
+pub const DEFAULT_OVERSAMPLE: usize = 10;
+
+/// Sign-bit codes for every row, one bit per dimension, in a flat file laid
+/// out like the vector arena: row `i` starts at byte `i * dimensions / 8`.
+/// Rows are only written, never cleared; which rows are live is tracked in
+/// LMDB, and a reused row simply gets a new code.
+pub struct BinaryCodes {
+    file: File,
+    row_bytes: usize,
+    map: OnceLock<Option<Mmap>>,
+}
+
+impl BinaryCodes {
+    pub fn open<P: AsRef<Path>>(path: P, dimensions: usize) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Failed to open binary codes at '{}'", path.display()))?;
+
+        Ok(Self {
+            file,
+            row_bytes: dimensions / 8,
+            map: OnceLock::new(),
+        })
+    }
+
+    pub fn write(&mut self, row: usize, vector: &[Number]) -> Result<()> {
+        self.file
+            .write_all_at(&encode(vector), (row * self.row_bytes) as u64)
+            .with_context(|| format!("Failed to write binary code for row {}", row))?;
+        self.map = OnceLock::new();
+        Ok(())
+    }
+
+    /// Flush written codes to disk before the records they belong to commit.
+    pub fn sync(&self) -> Result<()> {
+        self.file
+            .sync_data()
+            .context("Failed to flush binary codes")
+    }
+
+    /// Drop every code, before the file is rebuilt.
+    pub fn clear(&mut self) -> Result<()> {
+        self.file
+            .set_len(0)
+            .context("Failed to truncate binary codes")?;
+        self.map = OnceLock::new();
+        Ok(())
+    }
+
+    fn bytes(&self) -> Result<&[u8]> {
+        if self.map.get().is_none() {
+            let map = if self.file.metadata()?.len() == 0 {
+                None
+            } else {
+                Some(unsafe { Mmap::map(&self.file) }.context("Failed to map binary codes")?)
+            };
+            let _ = self.map.set(map);
+        }
+
+        Ok(self.map.get().and_then(Option::as_deref).unwrap_or(&[]))
+    }
+
+    /// The `depth` rows among `rows` whose codes are closest to the query's,
+    /// scored by negated Hamming distance. Runs on the current rayon pool.
+    pub fn nearest(&self, query: &[Number], rows: &[usize], depth: usize) -> Result<TopK> {
+        let bytes = self.bytes()?;
+        let query = encode(query);
+        Ok(rows
+            .par_iter()
+            .fold(
+                || TopK::new(depth, None),
+                |mut best, &row| {
+                    let start = row * self.row_bytes;
+                    if let Some(code) = bytes.get(start..start + self.row_bytes) {
+                        best.push(row, -(hamming_distance(&query, code) as Number));
+                    }
+                    best
+                },
+            )
+            .reduce(|| TopK::new(depth, None), TopK::merge))
+    }
+}
+
+/// Pack the sign of every dimension into bits, lowest dimension first.
+pub fn encode(vector: &[Number]) -> Vec<u8> {
+    vector
+        .chunks(8)
+        .map(|chunk| {
+            chunk.iter().enumerate().fold(
+                0u8,
+                |byte, (bit, &x)| if x > 0.0 { byte | (1 << bit) } else { byte },
+            )
+        })
+        .collect()
+}
+
+/// Number of differing bits, a word at a time.
+pub fn hamming_distance(a: &[u8], b: &[u8]) -> u32 {
+    let mut words_a = a.chunks_exact(8);
+    let mut words_b = b.chunks_exact(8);
+    let mut distance = 0;
+    for (x, y) in (&mut words_a).zip(&mut words_b) {
+        let x = u64::from_le_bytes(x.try_into().unwrap());
+        let y = u64::from_le_bytes(y.try_into().unwrap());
+        distance += (x ^ y).count_ones();
+    }
+    for (x, y) in words_a.remainder().iter().zip(words_b.remainder()) {
+        distance += (x ^ y).count_ones();
+    }
+    distance
+}
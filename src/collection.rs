@@ -0,0 +1,62 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+/// The collection every database starts with. It keeps the LMDB database
+/// names and file layout of databases created before collections existed.
+pub const DEFAULT_COLLECTION: &str = "default";
+
+const MAX_NAME_SIZE: usize = 64;
+
+/// What the collection registry records about a named collection. The
+/// metric and storage precision live in the collection's own meta database,
+/// like the default collection's.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CollectionSchema {
+    pub dimensions: usize,
+}
+
+pub fn is_default(name: &str) -> bool {
+    name == DEFAULT_COLLECTION
+}
+
+/// Collection names become part of LMDB database names and directory names,
+/// so they are limited to ASCII letters, digits, `-` and `_`.
+pub fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > MAX_NAME_SIZE {
+        anyhow::bail!(
+            "Collection names must be 1 to {} characters long",
+            MAX_NAME_SIZE
+        );
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        anyhow::bail!(
+            "Invalid collection name '{}': use letters, digits, '-' and '_'",
+            name
+        );
+    }
+    Ok(())
+}
+
+/// The LMDB database holding `name` for a collection: `name` itself for the
+/// default collection, `collection/name` for the others.
+pub fn db_name<'a>(collection: &str, name: &'a str) -> Cow<'a, str> {
+    if is_default(collection) {
+        Cow::Borrowed(name)
+    } else {
+        Cow::Owned(format!("{}/{}", collection, name))
+    }
+}
+
+/// Where a collection keeps its vector arena and binary codes.
+pub fn collection_dir(path: &Path, collection: &str) -> PathBuf {
+    if is_default(collection) {
+        path.to_path_buf()
+    } else {
+        path.join("collections").join(collection)
+    }
+}
@@ -4,6 +4,11 @@ use serde::Deserialize;
 use std::env;
 use std::mem::size_of;
 
+use crate::collection::DEFAULT_COLLECTION;
+use crate::database::{DEFAULT_BATCH_SIZE, DEFAULT_MAP_SIZE};
+use crate::vector_ops::{Metric, Precision};
+use crate::{binary, hnsw, ivf, pq};
+
 pub type Number = f32;
 
 pub const EPSILON: f32 = 1e-6;
@@ -15,6 +20,22 @@ pub struct VektaConfig {
     pub label_size: Option<usize>,
     pub top_k: Option<usize>,
     pub search_method: Option<String>,
+    pub threads: Option<usize>,
+    pub similarity_threshold: Option<Number>,
+    pub metric: Option<String>,
+    pub precision: Option<String>,
+    pub hnsw_m: Option<usize>,
+    pub hnsw_ef_construction: Option<usize>,
+    pub hnsw_ef_search: Option<usize>,
+    pub ivf_nlist: Option<usize>,
+    pub ivf_nprobe: Option<usize>,
+    pub ivf_pq_subquantizers: Option<usize>,
+    pub ivf_pq_bits: Option<usize>,
+    pub ivf_pq_recall_target: Option<Number>,
+    pub binary_oversample: Option<usize>,
+    pub indexed_fields: Option<Vec<String>>,
+    pub map_size: Option<usize>,
+    pub batch_size: Option<usize>,
     //pub ann_num_projections: Option<usize>,
     //pub ann_num_tables: Option<usize>,
 }
@@ -27,25 +48,85 @@ impl VektaConfig {
             label_size: config.get("label_size").ok(),
             top_k: config.get("top_k").ok(),
             search_method: config.get("search_method").ok(),
+            threads: config.get("threads").ok(),
+            similarity_threshold: config.get("similarity_threshold").ok(),
+            metric: config.get("metric").ok(),
+            precision: config.get("precision").ok(),
+            hnsw_m: config.get("hnsw_m").ok(),
+            hnsw_ef_construction: config.get("hnsw_ef_construction").ok(),
+            hnsw_ef_search: config.get("hnsw_ef_search").ok(),
+            ivf_nlist: config.get("ivf_nlist").ok(),
+            ivf_nprobe: config.get("ivf_nprobe").ok(),
+            ivf_pq_subquantizers: config.get("ivf_pq_subquantizers").ok(),
+            ivf_pq_bits: config.get("ivf_pq_bits").ok(),
+            ivf_pq_recall_target: config.get("ivf_pq_recall_target").ok(),
+            binary_oversample: config.get("binary_oversample").ok(),
+            indexed_fields: config
+                .get::<Vec<String>>("indexed_fields")
+                .ok()
+                .or_else(|| {
+                    config
+                        .get::<String>("indexed_fields")
+                        .ok()
+                        .map(|s| split_fields(&s))
+                }),
+            map_size: config.get("map_size").ok(),
+            batch_size: config.get("batch_size").ok(),
             //ann_num_projections: config.get("ann_num_projections").ok(),
             //ann_num_tables: config.get("ann_num_tables").ok(),
         })
     }
 }
 
+#[derive(Clone)]
 pub struct State {
     pub path: String,
+    /// Collection the command works on
+    pub collection: String,
+    /// `dimensions` from the configuration, used by the default collection
+    pub configured_dimensions: Option<usize>,
+    /// Dimensions of the selected collection
     pub dimensions: usize,
     pub label_size: usize,
     pub vector_size: usize,
     pub chunk_size: usize,
     pub top_k: usize,
     pub search_method: String,
-    pub similarity_threshold: Number,
+    /// Minimum similarity a result must reach, applied by every search method.
+    pub similarity_threshold: Option<Number>,
+    /// Metric for newly created databases; existing ones keep the metric they were created with.
+    pub metric: Option<Metric>,
+    /// Storage precision for newly created databases, fixed like the metric.
+    pub precision: Option<Precision>,
+    /// Worker threads for parallel search and adds; 0 lets rayon use every core.
+    pub threads: usize,
+    pub hnsw_m: usize,
+    pub hnsw_ef_construction: usize,
+    pub hnsw_ef_search: usize,
+    /// Number of k-means lists to train; `None` picks one from the row count.
+    pub ivf_nlist: Option<usize>,
+    /// Number of lists scanned per IVF query.
+    pub ivf_nprobe: usize,
+    /// Product-quantizer slices per vector; `None` trains IVF without PQ codes.
+    pub ivf_pq_subquantizers: Option<usize>,
+    /// Bits per PQ code, at most 8.
+    pub ivf_pq_bits: usize,
+    /// Recall@top_k the PQ re-ranking depth is calibrated to reach.
+    pub ivf_pq_recall_target: Number,
+    /// Candidates kept by the binary method's Hamming scan, as a multiple of top_k.
+    pub binary_oversample: usize,
+    /// Metadata fields to keep a secondary index for; `None` keeps the stored set
+    pub indexed_fields: Option<Vec<String>>,
+    /// Initial LMDB map size in bytes; the map grows when an add fills it.
+    pub map_size: usize,
+    /// Input lines `add` parses in parallel and stores in one transaction.
+    pub batch_size: usize,
 }
 
 impl State {
-    pub fn new() -> Result<Self> {
+    /// Load the configuration. Dimensions stay unset until
+    /// `VectorDatabase::select_collection` resolves them for `collection`.
+    pub fn new(collection: Option<String>) -> Result<Self> {
         let mut config = Config::default();
         #[allow(deprecated)]
         {
@@ -60,14 +141,12 @@ impl State {
             .or_else(|| env::var("VEKTA_PATH").ok())
             .context("VEKTA_PATH not set in config or environment")?;
 
-        let dimensions = vekta_config
-            .dimensions
-            .or_else(|| {
-                env::var("VEKTA_DIMENSIONS")
-                    .ok()
-                    .and_then(|s| s.parse().ok())
-            })
-            .context("VEKTA_DIMENSIONS not set in config or environment")?;
+        // Only the default collection takes its dimensions from the configuration
+        let configured_dimensions = vekta_config.dimensions.or_else(|| {
+            env::var("VEKTA_DIMENSIONS")
+                .ok()
+                .and_then(|s| s.parse().ok())
+        });
 
         let label_size = vekta_config
             .label_size
@@ -88,42 +167,221 @@ impl State {
             .or_else(|| env::var("VEKTA_SEARCH_METHOD").ok())
             .unwrap_or_else(|| "exact".to_string());
 
-        let similarity_threshold = env::var("VEKTA_SIMILARITY_THRESHOLD")
-            .unwrap_or_else(|_| "0.0".to_string()) // Set to 0.0 for testing
-            .parse()
-            .context("Failed to parse VEKTA_SIMILARITY_THRESHOLD")?;
+        let threads = vekta_config
+            .threads
+            .or_else(|| env::var("VEKTA_THREADS").ok().and_then(|s| s.parse().ok()))
+            .unwrap_or(0);
 
-        if dimensions % 8 != 0 {
-            anyhow::bail!("VEKTA_DIMENSIONS must be a multiple of 8.");
-        }
+        let hnsw_m = vekta_config
+            .hnsw_m
+            .or_else(|| env::var("VEKTA_HNSW_M").ok().and_then(|s| s.parse().ok()))
+            .unwrap_or(hnsw::DEFAULT_M);
+
+        let hnsw_ef_construction = vekta_config
+            .hnsw_ef_construction
+            .or_else(|| {
+                env::var("VEKTA_HNSW_EF_CONSTRUCTION")
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+            })
+            .unwrap_or(hnsw::DEFAULT_EF_CONSTRUCTION);
+
+        let hnsw_ef_search = vekta_config
+            .hnsw_ef_search
+            .or_else(|| {
+                env::var("VEKTA_HNSW_EF_SEARCH")
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+            })
+            .unwrap_or(hnsw::DEFAULT_EF_SEARCH);
+
+        let ivf_nlist = vekta_config.ivf_nlist.or_else(|| {
+            env::var("VEKTA_IVF_NLIST")
+                .ok()
+                .and_then(|s| s.parse().ok())
+        });
+
+        let ivf_nprobe = vekta_config
+            .ivf_nprobe
+            .or_else(|| {
+                env::var("VEKTA_IVF_NPROBE")
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+            })
+            .unwrap_or(ivf::DEFAULT_NPROBE);
+
+        let ivf_pq_subquantizers = vekta_config.ivf_pq_subquantizers.or_else(|| {
+            env::var("VEKTA_IVF_PQ_SUBQUANTIZERS")
+                .ok()
+                .and_then(|s| s.parse().ok())
+        });
+
+        let ivf_pq_bits = vekta_config
+            .ivf_pq_bits
+            .or_else(|| {
+                env::var("VEKTA_IVF_PQ_BITS")
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+            })
+            .unwrap_or(pq::DEFAULT_BITS);
+
+        let ivf_pq_recall_target = vekta_config
+            .ivf_pq_recall_target
+            .or_else(|| {
+                env::var("VEKTA_IVF_PQ_RECALL_TARGET")
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+            })
+            .unwrap_or(pq::DEFAULT_RECALL_TARGET);
 
-        let vector_size = dimensions * size_of::<Number>();
-        let chunk_size = vector_size + label_size;
+        let binary_oversample = vekta_config
+            .binary_oversample
+            .or_else(|| {
+                env::var("VEKTA_BINARY_OVERSAMPLE")
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+            })
+            .unwrap_or(binary::DEFAULT_OVERSAMPLE);
+
+        let indexed_fields = vekta_config.indexed_fields.or_else(|| {
+            env::var("VEKTA_INDEXED_FIELDS")
+                .ok()
+                .map(|s| split_fields(&s))
+        });
+
+        let map_size = vekta_config
+            .map_size
+            .or_else(|| env::var("VEKTA_MAP_SIZE").ok().and_then(|s| s.parse().ok()))
+            .unwrap_or(DEFAULT_MAP_SIZE);
+
+        let batch_size = vekta_config
+            .batch_size
+            .or_else(|| {
+                env::var("VEKTA_BATCH_SIZE")
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+            })
+            .unwrap_or(DEFAULT_BATCH_SIZE);
+
+        let similarity_threshold = match vekta_config.similarity_threshold {
+            Some(threshold) => Some(threshold),
+            None => env::var("VEKTA_SIMILARITY_THRESHOLD")
+                .ok()
+                .map(|s| s.parse())
+                .transpose()
+                .context("Failed to parse VEKTA_SIMILARITY_THRESHOLD")?,
+        };
+
+        let metric = vekta_config
+            .metric
+            .or_else(|| env::var("VEKTA_METRIC").ok())
+            .map(|s| s.parse::<Metric>())
+            .transpose()?;
+
+        let precision = vekta_config
+            .precision
+            .or_else(|| env::var("VEKTA_PRECISION").ok())
+            .map(|s| s.parse::<Precision>())
+            .transpose()?;
 
         Ok(Self {
             path,
-            dimensions,
+            collection: collection.unwrap_or_else(|| DEFAULT_COLLECTION.to_string()),
+            configured_dimensions,
+            dimensions: 0,
             label_size,
-            vector_size,
-            chunk_size,
+            vector_size: 0,
+            chunk_size: 0,
             top_k,
             search_method,
             similarity_threshold,
+            metric,
+            precision,
+            threads,
+            hnsw_m,
+            hnsw_ef_construction,
+            hnsw_ef_search,
+            ivf_nlist,
+            ivf_nprobe,
+            ivf_pq_subquantizers,
+            ivf_pq_bits,
+            ivf_pq_recall_target,
+            binary_oversample,
+            indexed_fields,
+            map_size,
+            batch_size,
         })
     }
 
+    pub fn set_dimensions(&mut self, dimensions: usize) -> Result<()> {
+        if dimensions == 0 || !dimensions.is_multiple_of(8) {
+            anyhow::bail!(
+                "Dimensions must be a positive multiple of 8, got {}.",
+                dimensions
+            );
+        }
+        self.dimensions = dimensions;
+        self.vector_size = dimensions * size_of::<Number>();
+        self.chunk_size = self.vector_size + self.label_size;
+        Ok(())
+    }
+
     pub fn print_config(&self) {
         println!("path={}", self.path);
+        println!("collection={}", self.collection);
         println!("dimensions={}", self.dimensions);
         println!("label_size={}", self.label_size);
         println!("vector_size={}", self.vector_size);
         println!("chunk_size={}", self.chunk_size);
         println!("top_k={}", self.top_k);
         println!("search_method={}", self.search_method);
-        println!("similarity_threshold={}", self.similarity_threshold);
+        match self.similarity_threshold {
+            Some(threshold) => println!("similarity_threshold={}", threshold),
+            None => println!("similarity_threshold=none"),
+        }
+        match self.metric {
+            Some(metric) => println!("metric={}", metric),
+            None => println!("metric=cosine (default)"),
+        }
+        match self.precision {
+            Some(precision) => println!("precision={}", precision),
+            None => println!("precision=f32 (default)"),
+        }
+        println!("threads={}", self.threads);
+        println!("hnsw_m={}", self.hnsw_m);
+        println!("hnsw_ef_construction={}", self.hnsw_ef_construction);
+        println!("hnsw_ef_search={}", self.hnsw_ef_search);
+        match self.ivf_nlist {
+            Some(nlist) => println!("ivf_nlist={}", nlist),
+            None => println!("ivf_nlist=auto"),
+        }
+        println!("ivf_nprobe={}", self.ivf_nprobe);
+        match self.ivf_pq_subquantizers {
+            Some(subquantizers) => println!("ivf_pq_subquantizers={}", subquantizers),
+            None => println!("ivf_pq_subquantizers=none"),
+        }
+        println!("ivf_pq_bits={}", self.ivf_pq_bits);
+        println!("ivf_pq_recall_target={}", self.ivf_pq_recall_target);
+        println!("binary_oversample={}", self.binary_oversample);
+        match &self.indexed_fields {
+            Some(fields) => println!("indexed_fields={}", fields.join(",")),
+            None => println!("indexed_fields=unchanged"),
+        }
+        println!("map_size={}", self.map_size);
+        println!("batch_size={}", self.batch_size);
     }
 }
 
+/// Split a comma-separated field list, ignoring blanks, so an empty string
+/// means no fields.
+fn split_fields(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
 pub fn verbose_print(message: &str) {
     if env::var("VEKTA_VERBOSE").unwrap_or_else(|_| "false".to_string()) == "true" {
         eprintln!("{}", message);
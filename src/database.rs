@@ -1,120 +1,1449 @@
 use anyhow::{Context, Result};
-use bincode;
+use heed::byteorder::BigEndian;
 use heed::types::*;
-use heed::EnvOpenOptions;
+use heed::{DatabaseFlags, EnvOpenOptions, RoTxn, RwTxn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use sha2::{Digest, Sha256};
-use std::fs;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::ops::Bound;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use crate::config::{Number, State};
-use crate::vector_entry::{Metadata, VectorEntry};
-use crate::vector_ops::normalize_vector;
+use crate::ann::RandomProjectionIndex;
+use crate::arena::{VectorArena, ARENA_FILE_NAME};
+use crate::binary::{BinaryCodes, CODES_FILE_NAME};
+use crate::collection::{self, collection_dir, db_name, CollectionSchema, DEFAULT_COLLECTION};
+use crate::config::{verbose_print, Number, State};
+use crate::field_index::{FieldIndex, KeyRange};
+use crate::filter::{Filter, RowSet};
+use crate::hnsw::{GraphStore, GraphStoreMut, HnswHeader, HnswNode, HnswParams};
+use crate::ivf::IvfIndex;
+use crate::migrate;
+use crate::pq::{calibrate_rerank, residual, EncodedRow, PqIndex, PqSettings, ProductQuantizer};
+use crate::schema::Schema;
+use crate::top_k::{ScoredRow, TopK};
+use crate::vector_entry::{empty_metadata, CodeChunk, EntryRecord, VectorEntry};
+use crate::vector_ops::{normalize_vector, Metric, Precision, StoredVector};
 
+type RowKey = U64<BigEndian>;
+type ListKey = U32<BigEndian>;
+
+const ANN_INDEX_NAME: &str = "ann";
+const HNSW_INDEX_NAME: &str = "hnsw";
+const IVF_INDEX_NAME: &str = "ivf";
+const IVF_PQ_INDEX_NAME: &str = "ivf_pq";
+const BINARY_INDEX_NAME: &str = "binary";
+const FIELD_INDEX_NAME: &str = "fields";
+const INT8_RANGES_KEY: &str = "int8_ranges";
+/// A precision change whose new arena is written but maybe not yet in place
+const PENDING_QUANTIZE_KEY: &str = "pending_quantize";
+/// LMDB databases each collection opens in the environment.
+const COLLECTION_DBS: u32 = 12;
+/// Named collections an environment can hold. `vekta migrate` opens every
+/// collection in one process, so the environment has room for all of them.
+pub const MAX_COLLECTIONS: usize = 64;
+/// Every collection's databases, the default collection's included, plus the
+/// collection registry and the environment settings.
+const MAX_DBS: u32 = (MAX_COLLECTIONS as u32 + 1) * COLLECTION_DBS + 2;
+pub const DEFAULT_MAP_SIZE: usize = 64 * 1024 * 1024; // 64MB
+/// Entries `vekta add` parses together and writes in one transaction.
+pub const DEFAULT_BATCH_SIZE: usize = 1000;
+/// Map sizes are rounded up to a multiple of this, which covers every common
+/// OS page size.
+const MAP_SIZE_GRANULE: usize = 64 * 1024;
+pub const SCHEMA_KEY: &str = "schema";
+/// Marked upgraded records in databases that predate the schema record
+pub const RECORD_FORMAT_KEY: &str = "record_format";
+const FORMAT_VERSION_KEY: &str = "format_version";
+
+/// Open the LMDB environment at `path`. Every open in a process must use the
+/// same options, so they are only set here. The map starts at `map_size`, or
+/// at twice the data file if that is larger, so rewriting what is stored
+/// does not fill it.
+fn open_env(path: &Path, map_size: usize) -> Result<heed::Env> {
+    let env = unsafe {
+        EnvOpenOptions::new()
+            .map_size(round_map_size(map_size))
+            .max_dbs(MAX_DBS)
+            .open(path)
+            .with_context(|| format!("Failed to open LMDB environment at '{}'", path.display()))?
+    };
+    let headroom = round_map_size(env.real_disk_size()? as usize * 2);
+    if env.info().map_size < headroom {
+        // No transaction is open while the environment is being opened
+        unsafe { env.resize(headroom)? };
+    }
+    Ok(env)
+}
+
+fn round_map_size(size: usize) -> usize {
+    size.max(1).div_ceil(MAP_SIZE_GRANULE) * MAP_SIZE_GRANULE
+}
+
+/// Whether an error comes from a write that did not fit in the LMDB map.
+fn is_map_full(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<heed::Error>(),
+            Some(heed::Error::Mdb(heed::MdbError::MapFull))
+        )
+    })
+}
+
+/// How much of the LMDB map the environment uses, in bytes.
+pub struct MapUsage {
+    pub map_size: usize,
+    /// Pages holding data, not counting freed pages waiting for reuse
+    pub used: u64,
+    pub file_size: u64,
+}
+
+/// Handles to one collection's databases. Clones share the environment.
+#[derive(Clone)]
 pub struct LmdbWrapper {
     env: heed::Env,
-    db: heed::Database<Str, SerdeBincode<Vec<u8>>>,
+    db: heed::Database<Str, Bytes>,
     label_index: heed::Database<Str, Str>,
+    rows: heed::Database<RowKey, Str>,
+    free_rows: heed::Database<RowKey, Unit>,
+    meta: heed::Database<Str, Str>,
+    indexes: heed::Database<Str, Bytes>,
+    ann_buckets: heed::Database<Bytes, RowKey>,
+    hnsw_nodes: heed::Database<RowKey, Bytes>,
+    /// Rows of `hnsw_nodes` by their level, so the top node is found without a scan
+    hnsw_levels: heed::Database<U8, RowKey>,
+    ivf_lists: heed::Database<ListKey, RowKey>,
+    /// Same lists as `ivf_lists`, but each posting is the row followed by its PQ code
+    ivf_pq_lists: heed::Database<ListKey, Bytes>,
+    /// Secondary index keys from `FieldIndex::keys`, each posted to the rows that hold it
+    field_index: heed::Database<Bytes, RowKey>,
+    /// Registry of named collections, shared by every collection in the environment
+    collections: heed::Database<Str, Str>,
+    /// Settings of the whole environment, such as its format version
+    environment: heed::Database<Str, Str>,
+}
+
+impl LmdbWrapper {
+    /// Open the LMDB environment at `path` and the databases of one collection
+    /// in it, creating them if needed.
+    pub fn new<P: AsRef<Path>>(path: P, collection: &str, map_size: usize) -> Result<Self> {
+        let path = PathBuf::from(path.as_ref());
+
+        fs::create_dir_all(&path).with_context(|| {
+            format!(
+                "Failed to create directory for LMDB at '{}'",
+                path.display()
+            )
+        })?;
+
+        verbose_print(&format!("Attempting to open LMDB at: {}", path.display()));
+
+        let env = open_env(&path, map_size)?;
+
+        let mut wtxn = env
+            .write_txn()
+            .with_context(|| "Failed to create write transaction for LMDB")?;
+        let db: heed::Database<Str, Bytes> = env
+            .create_database(&mut wtxn, Some(&db_name(collection, "main")))
+            .with_context(|| "Failed to create main LMDB database")?;
+        let label_index: heed::Database<Str, Str> = env
+            .create_database(&mut wtxn, Some(&db_name(collection, "label_index")))
+            .with_context(|| "Failed to create label index LMDB database")?;
+        let rows: heed::Database<RowKey, Str> = env
+            .create_database(&mut wtxn, Some(&db_name(collection, "rows")))
+            .with_context(|| "Failed to create row index LMDB database")?;
+        let free_rows: heed::Database<RowKey, Unit> = env
+            .create_database(&mut wtxn, Some(&db_name(collection, "free_rows")))
+            .with_context(|| "Failed to create free row LMDB database")?;
+        let meta: heed::Database<Str, Str> = env
+            .create_database(&mut wtxn, Some(&db_name(collection, "meta")))
+            .with_context(|| "Failed to create meta LMDB database")?;
+        let indexes: heed::Database<Str, Bytes> = env
+            .create_database(&mut wtxn, Some(&db_name(collection, "indexes")))
+            .with_context(|| "Failed to create index LMDB database")?;
+        let ann_buckets = env
+            .database_options()
+            .types::<Bytes, RowKey>()
+            .name(&db_name(collection, "ann_buckets"))
+            .flags(DatabaseFlags::DUP_SORT | DatabaseFlags::DUP_FIXED)
+            .create(&mut wtxn)
+            .with_context(|| "Failed to create ANN bucket LMDB database")?;
+        let hnsw_nodes: heed::Database<RowKey, Bytes> = env
+            .create_database(&mut wtxn, Some(&db_name(collection, "hnsw_nodes")))
+            .with_context(|| "Failed to create HNSW node LMDB database")?;
+        let hnsw_levels = env
+            .database_options()
+            .types::<U8, RowKey>()
+            .name(&db_name(collection, "hnsw_levels"))
+            .flags(DatabaseFlags::DUP_SORT | DatabaseFlags::DUP_FIXED)
+            .create(&mut wtxn)
+            .with_context(|| "Failed to create HNSW level LMDB database")?;
+        // Graphs built before the level index existed get it on first open
+        if hnsw_levels.is_empty(&wtxn)? {
+            index_levels(&hnsw_nodes, &hnsw_levels, &mut wtxn)
+                .with_context(|| "Failed to index the levels of HNSW nodes")?;
+        }
+        let ivf_lists = env
+            .database_options()
+            .types::<ListKey, RowKey>()
+            .name(&db_name(collection, "ivf_lists"))
+            .flags(DatabaseFlags::DUP_SORT | DatabaseFlags::DUP_FIXED)
+            .create(&mut wtxn)
+            .with_context(|| "Failed to create IVF posting list LMDB database")?;
+        let ivf_pq_lists = env
+            .database_options()
+            .types::<ListKey, Bytes>()
+            .name(&db_name(collection, "ivf_pq_lists"))
+            .flags(DatabaseFlags::DUP_SORT | DatabaseFlags::DUP_FIXED)
+            .create(&mut wtxn)
+            .with_context(|| "Failed to create IVF-PQ posting list LMDB database")?;
+        let field_index = env
+            .database_options()
+            .types::<Bytes, RowKey>()
+            .name(&db_name(collection, "field_index"))
+            .flags(DatabaseFlags::DUP_SORT | DatabaseFlags::DUP_FIXED)
+            .create(&mut wtxn)
+            .with_context(|| "Failed to create metadata field index LMDB database")?;
+        let collections: heed::Database<Str, Str> = env
+            .create_database(&mut wtxn, Some("collections"))
+            .with_context(|| "Failed to create collection registry LMDB database")?;
+        let environment: heed::Database<Str, Str> = env
+            .create_database(&mut wtxn, Some("environment"))
+            .with_context(|| "Failed to create environment settings LMDB database")?;
+        wtxn.commit()
+            .with_context(|| "Failed to commit initial LMDB transaction")?;
+
+        Ok(Self {
+            env,
+            db,
+            label_index,
+            rows,
+            free_rows,
+            meta,
+            indexes,
+            ann_buckets,
+            hnsw_nodes,
+            hnsw_levels,
+            ivf_lists,
+            ivf_pq_lists,
+            field_index,
+            collections,
+            environment,
+        })
+    }
+
+    /// Write a compacted copy of the LMDB environment at `path` to `target`,
+    /// without creating any database in it.
+    pub fn copy_environment(path: &Path, target: &Path, map_size: usize) -> Result<()> {
+        let env = open_env(path, map_size)?;
+        env.copy_to_file(target.join("data.mdb"), heed::CompactionOption::Enabled)
+            .with_context(|| {
+                format!(
+                    "Failed to copy the LMDB environment at '{}'",
+                    path.display()
+                )
+            })?;
+        Ok(())
+    }
+
+    /// Double the map size. LMDB only allows this while the process has no
+    /// transaction open.
+    pub fn grow_map(&self) -> Result<usize> {
+        let size = self.env.info().map_size * 2;
+        unsafe { self.env.resize(size)? };
+        verbose_print(&format!("Grew the LMDB map to {} bytes", size));
+        Ok(size)
+    }
+
+    pub fn map_usage(&self) -> Result<MapUsage> {
+        Ok(MapUsage {
+            map_size: self.env.info().map_size,
+            used: self.env.non_free_pages_size()?,
+            file_size: self.env.real_disk_size()?,
+        })
+    }
+
+    /// The format version stamped in the environment, if any.
+    pub fn format_version(&self) -> Result<Option<u32>> {
+        let rtxn = self.env.read_txn()?;
+        self.environment
+            .get(&rtxn, FORMAT_VERSION_KEY)?
+            .map(str::parse)
+            .transpose()
+            .context("Failed to parse the stored format version")
+    }
+
+    pub fn put_format_version(&self, wtxn: &mut RwTxn, version: u32) -> Result<()> {
+        self.environment
+            .put(wtxn, FORMAT_VERSION_KEY, &version.to_string())?;
+        Ok(())
+    }
+
+    pub fn get_collection(&self, name: &str) -> Result<Option<CollectionSchema>> {
+        let rtxn = self.env.read_txn()?;
+        self.collections
+            .get(&rtxn, name)?
+            .map(serde_json::from_str)
+            .transpose()
+            .with_context(|| format!("Failed to parse the schema of collection '{}'", name))
+    }
+
+    pub fn put_collection(&self, name: &str, schema: &CollectionSchema) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        self.collections
+            .put(&mut wtxn, name, &serde_json::to_string(schema)?)?;
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    /// Names of the registered collections, in order.
+    pub fn collection_names(&self) -> Result<Vec<String>> {
+        let rtxn = self.env.read_txn()?;
+        let names = self
+            .collections
+            .remap_data_type::<DecodeIgnore>()
+            .iter(&rtxn)?
+            .map(|result| result.map(|(name, _)| name.to_string()))
+            .collect::<std::result::Result<Vec<_>, heed::Error>>()?;
+        Ok(names)
+    }
+
+    /// Empty every database of this wrapper's collection and unregister it,
+    /// in one write transaction. LMDB keeps the emptied databases' names.
+    pub fn drop_collection(&self, name: &str) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        self.db.clear(&mut wtxn)?;
+        self.label_index.clear(&mut wtxn)?;
+        self.rows.clear(&mut wtxn)?;
+        self.free_rows.clear(&mut wtxn)?;
+        self.meta.clear(&mut wtxn)?;
+        self.indexes.clear(&mut wtxn)?;
+        self.ann_buckets.clear(&mut wtxn)?;
+        self.hnsw_nodes.clear(&mut wtxn)?;
+        self.hnsw_levels.clear(&mut wtxn)?;
+        self.ivf_lists.clear(&mut wtxn)?;
+        self.ivf_pq_lists.clear(&mut wtxn)?;
+        self.field_index.clear(&mut wtxn)?;
+        self.collections.delete(&mut wtxn, name)?;
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    /// Reuse the lowest freed rows, then append past the highest live or
+    /// reused row.
+    pub fn allocate_rows(&self, wtxn: &mut RwTxn, count: usize) -> Result<Vec<usize>> {
+        let mut rows = Vec::with_capacity(count);
+        while rows.len() < count {
+            let Some((row, ())) = self.free_rows.first(wtxn)? else {
+                break;
+            };
+            self.free_rows.delete(wtxn, &row)?;
+            rows.push(row as usize);
+        }
+
+        let next = self
+            .rows
+            .last(wtxn)?
+            .map(|(row, _)| row as usize)
+            .into_iter()
+            .chain(rows.iter().copied())
+            .max()
+            .map_or(0, |row| row + 1);
+        let missing = count - rows.len();
+        rows.extend(next..next + missing);
+        Ok(rows)
+    }
+
+    /// Map a row to the record stored under `key`.
+    pub fn put_row(&self, wtxn: &mut RwTxn, row: usize, key: &str) -> Result<()> {
+        self.rows.put(wtxn, &(row as u64), key)?;
+        Ok(())
+    }
+
+    fn release_row(&self, wtxn: &mut RwTxn, row: usize) -> Result<()> {
+        self.rows.delete(wtxn, &(row as u64))?;
+        self.free_rows.put(wtxn, &(row as u64), &())?;
+        Ok(())
+    }
+
+    pub fn write_txn(&self) -> Result<RwTxn<'_>> {
+        Ok(self.env.write_txn()?)
+    }
+
+    /// Run `f` in a write transaction and commit it. When the writes do not
+    /// fit in the LMDB map, the transaction is aborted, the map doubled and
+    /// `f` run again, so anything `f` changes outside the transaction must be
+    /// safe to redo.
+    pub fn with_write_txn<T>(&self, mut f: impl FnMut(&mut RwTxn) -> Result<T>) -> Result<T> {
+        loop {
+            let attempt = self.write_txn().and_then(|mut wtxn| {
+                let value = f(&mut wtxn)?;
+                wtxn.commit()?;
+                Ok(value)
+            });
+            match attempt {
+                Err(error) if is_map_full(&error) => {
+                    self.grow_map()?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Store a record for a row obtained from `allocate_rows`.
+    pub fn put_record(
+        &self,
+        wtxn: &mut RwTxn,
+        key: &str,
+        label: &str,
+        row: usize,
+        value: &[u8],
+    ) -> Result<()> {
+        if self.db.get(wtxn, key)?.is_some() {
+            anyhow::bail!("An entry with unique_id '{}' already exists", key);
+        }
+
+        self.db.put(wtxn, key, value)?;
+        self.label_index.put(wtxn, label, key)?;
+        self.rows.put(wtxn, &(row as u64), key)?;
+        Ok(())
+    }
+
+    /// Remove a record, its label mapping and its row.
+    pub fn remove(&self, wtxn: &mut RwTxn, key: &str, label: &str, row: usize) -> Result<bool> {
+        let deleted = self.db.delete(wtxn, key)?;
+        if self.label_index.get(wtxn, label)? == Some(key) {
+            self.label_index.delete(wtxn, label)?;
+        }
+        self.release_row(wtxn, row)?;
+        Ok(deleted)
+    }
+
+    pub fn put_ann_buckets(&self, wtxn: &mut RwTxn, keys: &[[u8; 9]], row: usize) -> Result<()> {
+        for key in keys {
+            self.ann_buckets.put(wtxn, key, &(row as u64))?;
+        }
+        Ok(())
+    }
+
+    pub fn delete_ann_buckets(&self, wtxn: &mut RwTxn, keys: &[[u8; 9]], row: usize) -> Result<()> {
+        for key in keys {
+            self.ann_buckets
+                .delete_one_duplicate(wtxn, key, &(row as u64))?;
+        }
+        Ok(())
+    }
+
+    pub fn clear_ann_buckets(&self, wtxn: &mut RwTxn) -> Result<()> {
+        self.ann_buckets.clear(wtxn)?;
+        Ok(())
+    }
+
+    /// All rows stored in any of the given buckets.
+    pub fn ann_bucket_rows(&self, keys: &[[u8; 9]]) -> Result<HashSet<usize>> {
+        let rtxn = self.env.read_txn()?;
+        let mut rows = HashSet::new();
+        for key in keys {
+            if let Some(bucket) = self.ann_buckets.get_duplicates(&rtxn, key)? {
+                for result in bucket {
+                    let (_, row) = result?;
+                    rows.insert(row as usize);
+                }
+            }
+        }
+        Ok(rows)
+    }
+
+    pub fn put_ivf_posting(&self, wtxn: &mut RwTxn, list: u32, row: usize) -> Result<()> {
+        self.ivf_lists.put(wtxn, &list, &(row as u64))?;
+        Ok(())
+    }
+
+    pub fn delete_ivf_posting(&self, wtxn: &mut RwTxn, list: u32, row: usize) -> Result<()> {
+        self.ivf_lists
+            .delete_one_duplicate(wtxn, &list, &(row as u64))?;
+        Ok(())
+    }
+
+    pub fn clear_ivf_lists(&self, wtxn: &mut RwTxn) -> Result<()> {
+        self.ivf_lists.clear(wtxn)?;
+        Ok(())
+    }
+
+    /// All rows posted to any of the given lists.
+    pub fn ivf_list_rows(&self, lists: &[u32]) -> Result<Vec<usize>> {
+        let rtxn = self.env.read_txn()?;
+        let mut rows = Vec::new();
+        for list in lists {
+            if let Some(postings) = self.ivf_lists.get_duplicates(&rtxn, list)? {
+                for result in postings {
+                    let (_, row) = result?;
+                    rows.push(row as usize);
+                }
+            }
+        }
+        Ok(rows)
+    }
+
+    pub fn put_ivf_pq_posting(
+        &self,
+        wtxn: &mut RwTxn,
+        list: u32,
+        row: usize,
+        code: &[u8],
+    ) -> Result<()> {
+        self.ivf_pq_lists.put(wtxn, &list, &pq_posting(row, code))?;
+        Ok(())
+    }
+
+    pub fn delete_ivf_pq_posting(
+        &self,
+        wtxn: &mut RwTxn,
+        list: u32,
+        row: usize,
+        code: &[u8],
+    ) -> Result<()> {
+        self.ivf_pq_lists
+            .delete_one_duplicate(wtxn, &list, &pq_posting(row, code))?;
+        Ok(())
+    }
+
+    pub fn clear_ivf_pq_lists(&self, wtxn: &mut RwTxn) -> Result<()> {
+        self.ivf_pq_lists.clear(wtxn)?;
+        Ok(())
+    }
+
+    /// Call `f` with the row and PQ code of every posting in a list.
+    pub fn for_each_ivf_pq_posting(
+        &self,
+        list: u32,
+        mut f: impl FnMut(usize, &[u8]),
+    ) -> Result<()> {
+        let rtxn = self.env.read_txn()?;
+        if let Some(postings) = self.ivf_pq_lists.get_duplicates(&rtxn, &list)? {
+            for result in postings {
+                let (_, posting) = result?;
+                let (row, code) = posting.split_at(8);
+                f(u64::from_be_bytes(row.try_into()?) as usize, code);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn put_field_keys(&self, wtxn: &mut RwTxn, keys: &[Vec<u8>], row: usize) -> Result<()> {
+        for key in keys {
+            self.field_index.put(wtxn, key, &(row as u64))?;
+        }
+        Ok(())
+    }
+
+    pub fn delete_field_keys(&self, wtxn: &mut RwTxn, keys: &[Vec<u8>], row: usize) -> Result<()> {
+        for key in keys {
+            self.field_index
+                .delete_one_duplicate(wtxn, key, &(row as u64))?;
+        }
+        Ok(())
+    }
+
+    pub fn clear_field_index(&self, wtxn: &mut RwTxn) -> Result<()> {
+        self.field_index.clear(wtxn)?;
+        Ok(())
+    }
+
+    /// All rows posted under any key in the range.
+    pub fn field_index_rows(&self, range: &KeyRange) -> Result<RowSet> {
+        let rtxn = self.env.read_txn()?;
+        let bounds: (Bound<&[u8]>, Bound<&[u8]>) = (
+            range.start.as_ref().map(Vec::as_slice),
+            range.end.as_ref().map(Vec::as_slice),
+        );
+        let mut rows = RowSet::default();
+        for result in self.field_index.range(&rtxn, &bounds)? {
+            let (_, row) = result?;
+            rows.insert(row as usize);
+        }
+        Ok(rows)
+    }
+
+    /// The HNSW graph as seen by a write transaction.
+    pub fn graph_writer<'a, 't>(&self, wtxn: &'a mut RwTxn<'t>) -> GraphWriter<'a, 't> {
+        GraphWriter {
+            nodes: self.hnsw_nodes,
+            levels: self.hnsw_levels,
+            wtxn,
+        }
+    }
+
+    /// A read-only snapshot of the HNSW graph.
+    pub fn graph_reader(&self) -> Result<GraphReader<'_>> {
+        Ok(GraphReader {
+            nodes: self.hnsw_nodes,
+            levels: self.hnsw_levels,
+            rtxn: self.env.read_txn()?,
+        })
+    }
+
+    pub fn clear_hnsw_nodes(&self, wtxn: &mut RwTxn) -> Result<()> {
+        self.hnsw_nodes.clear(wtxn)?;
+        self.hnsw_levels.clear(wtxn)?;
+        Ok(())
+    }
+
+    pub fn get_index(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self.indexes.get(&rtxn, name)?.map(|v| v.to_vec()))
+    }
+
+    pub fn put_index(&self, wtxn: &mut RwTxn, name: &str, value: &[u8]) -> Result<()> {
+        self.indexes.put(wtxn, name, value)?;
+        Ok(())
+    }
+
+    pub fn delete_index(&self, wtxn: &mut RwTxn, name: &str) -> Result<()> {
+        self.indexes.delete(wtxn, name)?;
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self.db.get(&rtxn, key)?.map(|v| v.to_vec()))
+    }
+
+    pub fn get_by_row(&self, row: usize) -> Result<Option<Vec<u8>>> {
+        let rtxn = self.env.read_txn()?;
+        match self.rows.get(&rtxn, &(row as u64))? {
+            Some(key) => Ok(self.db.get(&rtxn, key)?.map(|v| v.to_vec())),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_meta(&self, key: &str) -> Result<Option<String>> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self.meta.get(&rtxn, key)?.map(str::to_string))
+    }
+
+    pub fn put_meta(&self, key: &str, value: &str) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        self.put_meta_in(&mut wtxn, key, value)?;
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    pub fn put_meta_in(&self, wtxn: &mut RwTxn, key: &str, value: &str) -> Result<()> {
+        self.meta.put(wtxn, key, value)?;
+        Ok(())
+    }
+
+    pub fn delete_meta_in(&self, wtxn: &mut RwTxn, key: &str) -> Result<()> {
+        self.meta.delete(wtxn, key)?;
+        Ok(())
+    }
+
+    pub fn key_for_label(&self, label: &str) -> Result<Option<String>> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self.label_index.get(&rtxn, label)?.map(str::to_string))
+    }
+
+    pub fn keys(&self) -> Result<Vec<String>> {
+        let rtxn = self.env.read_txn()?;
+        let keys = self
+            .db
+            .remap_data_type::<DecodeIgnore>()
+            .iter(&rtxn)?
+            .map(|result| result.map(|(k, _)| k.to_string()))
+            .collect::<std::result::Result<Vec<_>, heed::Error>>()?;
+
+        Ok(keys)
+    }
+
+    /// Call `f` with every stored record, in key order, within one read
+    /// transaction.
+    pub fn for_each_record(&self, mut f: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        let rtxn = self.env.read_txn()?;
+        for result in self.db.iter(&rtxn)? {
+            let (_, value) = result?;
+            f(value)?;
+        }
+        Ok(())
+    }
+
+    /// Call `f` with the record stored at each of `rows`, within one read
+    /// transaction. Rows that hold no record are skipped.
+    pub fn for_each_record_at(
+        &self,
+        rows: impl Iterator<Item = usize>,
+        mut f: impl FnMut(&[u8]) -> Result<()>,
+    ) -> Result<()> {
+        let rtxn = self.env.read_txn()?;
+        for row in rows {
+            if let Some(key) = self.rows.get(&rtxn, &(row as u64))? {
+                if let Some(value) = self.db.get(&rtxn, key)? {
+                    f(value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace every stored record with `f` applied to its key and value,
+    /// within `wtxn`. Returns the number of records rewritten.
+    pub fn rewrite_records(
+        &self,
+        wtxn: &mut RwTxn,
+        mut f: impl FnMut(&str, &[u8]) -> Result<Vec<u8>>,
+    ) -> Result<usize> {
+        let mut rewritten = Vec::new();
+        for result in self.db.iter(wtxn)? {
+            let (key, value) = result?;
+            rewritten.push((key.to_string(), f(key, value)?));
+        }
+        for (key, value) in &rewritten {
+            self.db.put(wtxn, key, value)?;
+        }
+        Ok(rewritten.len())
+    }
+
+    /// Ids of all rows that currently hold a stored vector, in ascending order.
+    pub fn live_rows(&self) -> Result<Vec<usize>> {
+        let rtxn = self.env.read_txn()?;
+        let rows = self
+            .rows
+            .remap_data_type::<DecodeIgnore>()
+            .iter(&rtxn)?
+            .map(|result| result.map(|(row, _)| row as usize))
+            .collect::<std::result::Result<Vec<_>, heed::Error>>()?;
+
+        Ok(rows)
+    }
+
+    pub fn count(&self) -> Result<usize> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self.db.len(&rtxn)?.try_into().unwrap())
+    }
+}
+
+/// An IVF-PQ posting: the row in big-endian order, then its code, so postings
+/// in a list sort by row and all have the same size.
+fn pq_posting(row: usize, code: &[u8]) -> Vec<u8> {
+    let mut posting = (row as u64).to_be_bytes().to_vec();
+    posting.extend_from_slice(code);
+    posting
+}
+
+fn load_node(
+    nodes: &heed::Database<RowKey, Bytes>,
+    txn: &RoTxn,
+    row: usize,
+) -> Result<Option<HnswNode>> {
+    match nodes.get(txn, &(row as u64))? {
+        Some(value) => Ok(Some(bincode::deserialize(value)?)),
+        None => Ok(None),
+    }
+}
+
+/// A row on the highest level.
+fn top_node(levels: &heed::Database<U8, RowKey>, txn: &RoTxn) -> Result<Option<(usize, usize)>> {
+    Ok(levels
+        .last(txn)?
+        .map(|(level, row)| (row as usize, level as usize)))
+}
+
+fn node_level(node: &HnswNode) -> u8 {
+    node.neighbors.len().saturating_sub(1) as u8
+}
+
+fn index_levels(
+    nodes: &heed::Database<RowKey, Bytes>,
+    levels: &heed::Database<U8, RowKey>,
+    wtxn: &mut RwTxn,
+) -> Result<()> {
+    let mut indexed = Vec::new();
+    for result in nodes.iter(wtxn)? {
+        let (row, value) = result?;
+        let node: HnswNode = bincode::deserialize(value)?;
+        indexed.push((node_level(&node), row));
+    }
+    for (level, row) in indexed {
+        levels.put(wtxn, &level, &row)?;
+    }
+    Ok(())
+}
+
+pub struct GraphWriter<'a, 't> {
+    nodes: heed::Database<RowKey, Bytes>,
+    levels: heed::Database<U8, RowKey>,
+    wtxn: &'a mut RwTxn<'t>,
+}
+
+impl GraphStore for GraphWriter<'_, '_> {
+    fn load(&self, row: usize) -> Result<Option<HnswNode>> {
+        load_node(&self.nodes, self.wtxn, row)
+    }
+
+    fn contains(&self, row: usize) -> Result<bool> {
+        Ok(self.nodes.get(self.wtxn, &(row as u64))?.is_some())
+    }
+
+    fn top_node(&self) -> Result<Option<(usize, usize)>> {
+        top_node(&self.levels, self.wtxn)
+    }
+
+    fn node_count(&self) -> Result<usize> {
+        Ok(self.levels.len(self.wtxn)? as usize)
+    }
+}
+
+impl GraphStoreMut for GraphWriter<'_, '_> {
+    fn save(&mut self, row: usize, node: &HnswNode) -> Result<()> {
+        // A node keeps its level, so only its first save indexes it
+        if self.nodes.get(self.wtxn, &(row as u64))?.is_none() {
+            self.levels
+                .put(self.wtxn, &node_level(node), &(row as u64))?;
+        }
+        self.nodes
+            .put(self.wtxn, &(row as u64), &bincode::serialize(node)?)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, row: usize) -> Result<()> {
+        if let Some(node) = load_node(&self.nodes, self.wtxn, row)? {
+            self.levels
+                .delete_one_duplicate(self.wtxn, &node_level(&node), &(row as u64))?;
+            self.nodes.delete(self.wtxn, &(row as u64))?;
+        }
+        Ok(())
+    }
+}
+
+pub struct GraphReader<'e> {
+    nodes: heed::Database<RowKey, Bytes>,
+    levels: heed::Database<U8, RowKey>,
+    rtxn: RoTxn<'e>,
+}
+
+impl GraphStore for GraphReader<'_> {
+    fn load(&self, row: usize) -> Result<Option<HnswNode>> {
+        load_node(&self.nodes, &self.rtxn, row)
+    }
+
+    fn contains(&self, row: usize) -> Result<bool> {
+        Ok(self.nodes.get(&self.rtxn, &(row as u64))?.is_some())
+    }
+
+    fn top_node(&self) -> Result<Option<(usize, usize)>> {
+        top_node(&self.levels, &self.rtxn)
+    }
+
+    fn node_count(&self) -> Result<usize> {
+        Ok(self.levels.len(&self.rtxn)? as usize)
+    }
+}
+
+/// What `add_batch` does when the label is already stored with different content.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnConflict {
+    /// Keep the stored entry and drop the new one
+    Skip,
+    /// Overwrite the stored entry and its label mapping
+    Replace,
+    /// Store the new entry under `label_1`, `label_2`, ...
+    Rename,
+    /// Fail the add
+    Error,
+}
+
+pub enum AddOutcome {
+    Added(String),
+    Replaced(String),
+    Unchanged(String),
+    Skipped(String),
+}
+
+/// An entry checked and normalized for storage, with the hash its content is
+/// compared by and the record stored under its label before the batch.
+struct PreparedEntry {
+    entry: VectorEntry,
+    content_hash: String,
+    stored: Option<(EntryRecord, String)>,
+}
+
+/// The schema and int8 ranges `quantize` switches to, kept under
+/// `PENDING_QUANTIZE_KEY` while the new arena is swapped in.
+#[derive(Serialize, Deserialize)]
+struct PendingQuantize {
+    schema: Schema,
+    ranges: Vec<Number>,
+}
+
+/// One record for `store` to write, and the record it replaces.
+struct PendingWrite {
+    entry: VectorEntry,
+    content_hash: String,
+    replacing: Option<EntryRecord>,
+}
+
+/// The live rows of a database together with the arena they index into, for
+/// scanning vectors without touching LMDB.
+pub struct RowVectors<'a> {
+    pub rows: Vec<usize>,
+    arena: &'a VectorArena,
 }
 
-impl LmdbWrapper {
-    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let path = PathBuf::from(path.as_ref());
+impl<'a> RowVectors<'a> {
+    pub fn get(&self, row: usize) -> Option<StoredVector<'a>> {
+        self.arena.row(row).ok()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, StoredVector<'a>)> + '_ {
+        self.rows
+            .iter()
+            .filter_map(|&row| self.get(row).map(|vector| (row, vector)))
+    }
+
+    /// Every live vector converted back to full precision.
+    pub fn decoded(&self) -> Vec<(usize, Vec<Number>)> {
+        self.iter()
+            .map(|(row, vector)| (row, vector.to_vec()))
+            .collect()
+    }
+}
+
+pub struct VectorDatabase {
+    path: PathBuf,
+    lmdb: LmdbWrapper,
+    arena: VectorArena,
+    metric: Metric,
+    ann_index: Option<RandomProjectionIndex>,
+    hnsw: Option<HnswHeader>,
+    ivf_index: Option<IvfIndex>,
+    ivf_pq: Option<PqIndex>,
+    binary_codes: Option<BinaryCodes>,
+    field_index: Option<FieldIndex>,
+    schema: Schema,
+    dimensions: usize,
+    vector_size: usize,
+    label_size: usize,
+}
+
+impl VectorDatabase {
+    pub fn open(state: &State) -> Result<Self> {
+        verbose_print(&format!(
+            "Opening collection '{}' of database at path: {}",
+            state.collection, state.path
+        ));
+        let lmdb = Self::open_registered(state, &state.collection)?;
+        let dir = collection_dir(Path::new(&state.path), &state.collection);
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create directory '{}'", dir.display()))?;
+        migrate::check_format(&lmdb, &state.path)?;
+        Self::finish_quantize(&lmdb, &dir)?;
+        let schema = Self::resolve_schema(&lmdb, state)?;
+        let (metric, precision) = (schema.metric, schema.precision);
+        let arena = VectorArena::open(
+            dir.join(ARENA_FILE_NAME),
+            state.dimensions,
+            precision,
+            Self::resolve_int8_ranges(&lmdb, precision, state.dimensions)?,
+        )?;
+        let ann_index = lmdb
+            .get_index(ANN_INDEX_NAME)?
+            .map(|value| bincode::deserialize(&value))
+            .transpose()
+            .context("Failed to load the persisted ANN index")?;
+        let hnsw = lmdb
+            .get_index(HNSW_INDEX_NAME)?
+            .map(|value| bincode::deserialize(&value))
+            .transpose()
+            .context("Failed to load the persisted HNSW index")?;
+        let ivf_index = lmdb
+            .get_index(IVF_INDEX_NAME)?
+            .map(|value| bincode::deserialize(&value))
+            .transpose()
+            .context("Failed to load the persisted IVF index")?;
+        let ivf_pq = lmdb
+            .get_index(IVF_PQ_INDEX_NAME)?
+            .map(|value| bincode::deserialize(&value))
+            .transpose()
+            .context("Failed to load the persisted IVF-PQ codec")?;
+        let binary_codes = match lmdb.get_index(BINARY_INDEX_NAME)? {
+            Some(value) => {
+                let dimensions: usize = bincode::deserialize(&value)
+                    .context("Failed to load the persisted binary index")?;
+                if dimensions != state.dimensions {
+                    anyhow::bail!(
+                        "Binary codes were built for {} dimensions, not {}; run `vekta reindex --method binary`",
+                        dimensions,
+                        state.dimensions
+                    );
+                }
+                Some(BinaryCodes::open(dir.join(CODES_FILE_NAME), dimensions)?)
+            }
+            None => None,
+        };
+        let field_index = lmdb
+            .get_index(FIELD_INDEX_NAME)?
+            .map(|value| bincode::deserialize(&value))
+            .transpose()
+            .context("Failed to load the persisted metadata field index")?;
+
+        let mut db = Self {
+            path: dir,
+            lmdb,
+            arena,
+            metric,
+            ann_index,
+            hnsw,
+            ivf_index,
+            ivf_pq,
+            binary_codes,
+            field_index,
+            schema,
+            dimensions: state.dimensions,
+            vector_size: state.vector_size,
+            label_size: state.label_size,
+        };
+
+        // The configured fields win over the stored ones; unset keeps what is stored
+        if let Some(fields) = &state.indexed_fields {
+            if db.indexed_fields() != fields.as_slice() {
+                let count = db.rebuild_field_index(fields.clone())?;
+                verbose_print(&format!(
+                    "Rebuilt metadata field index over {} entries",
+                    count
+                ));
+            }
+        }
+        Ok(db)
+    }
+
+    /// Resolve the selected collection's dimensions into `state`: from the
+    /// registry for a named collection, from the configuration for the
+    /// default one.
+    pub fn select_collection(state: &mut State) -> Result<()> {
+        if collection::is_default(&state.collection) {
+            let dimensions = state
+                .configured_dimensions
+                .context("VEKTA_DIMENSIONS not set in config or environment")?;
+            return state.set_dimensions(dimensions);
+        }
+        collection::validate_name(&state.collection)?;
+        let lmdb = LmdbWrapper::new(&state.path, DEFAULT_COLLECTION, state.map_size)?;
+        let schema = lmdb.get_collection(&state.collection)?.with_context(|| {
+            format!(
+                "Collection '{}' does not exist; create it with `vekta collection create {}`",
+                state.collection, state.collection
+            )
+        })?;
+        if let Some(configured) = state
+            .configured_dimensions
+            .filter(|&d| d != schema.dimensions)
+        {
+            verbose_print(&format!(
+                "Collection '{}' has {} dimensions; ignoring configured {}",
+                state.collection, schema.dimensions, configured
+            ));
+        }
+        state.set_dimensions(schema.dimensions)
+    }
+
+    /// Register a new named collection with the dimensions, metric and
+    /// precision in `state`, and open it.
+    pub fn create_collection(state: &State) -> Result<Self> {
+        collection::validate_name(&state.collection)?;
+        if collection::is_default(&state.collection) {
+            anyhow::bail!("The default collection always exists");
+        }
+        let registry = LmdbWrapper::new(&state.path, DEFAULT_COLLECTION, state.map_size)?;
+        migrate::check_format(&registry, &state.path)?;
+        if registry.get_collection(&state.collection)?.is_some() {
+            anyhow::bail!("Collection '{}' already exists", state.collection);
+        }
+        if registry.collection_names()?.len() >= MAX_COLLECTIONS {
+            anyhow::bail!(
+                "The database already holds the maximum of {} named collections",
+                MAX_COLLECTIONS
+            );
+        }
+        registry.put_collection(
+            &state.collection,
+            &CollectionSchema {
+                dimensions: state.dimensions,
+            },
+        )?;
+        Self::open(state)
+    }
+
+    /// The default collection followed by every named one.
+    pub fn collection_names(state: &State) -> Result<Vec<String>> {
+        let lmdb = LmdbWrapper::new(&state.path, DEFAULT_COLLECTION, state.map_size)?;
+        let mut names = vec![DEFAULT_COLLECTION.to_string()];
+        names.extend(lmdb.collection_names()?);
+        Ok(names)
+    }
+
+    /// Open the databases of collection `name`. A named collection is looked up
+    /// in the registry first, so a mistyped name does not create its databases.
+    fn open_registered(state: &State, name: &str) -> Result<LmdbWrapper> {
+        let open = |collection| {
+            LmdbWrapper::new(&state.path, collection, state.map_size)
+                .with_context(|| format!("Failed to create LMDB at '{}'", state.path))
+        };
+        if !collection::is_default(name)
+            && open(DEFAULT_COLLECTION)?.get_collection(name)?.is_none()
+        {
+            anyhow::bail!("Collection '{}' does not exist", name);
+        }
+        open(name)
+    }
+
+    /// Delete a named collection's entries, indexes and files.
+    pub fn drop_collection(state: &State, name: &str) -> Result<()> {
+        collection::validate_name(name)?;
+        if collection::is_default(name) {
+            anyhow::bail!("The default collection cannot be dropped");
+        }
+        let lmdb = Self::open_registered(state, name)?;
+        migrate::check_format(&lmdb, &state.path)?;
+        lmdb.drop_collection(name)?;
+        let dir = collection_dir(Path::new(&state.path), name);
+        if dir.exists() {
+            fs::remove_dir_all(&dir)
+                .with_context(|| format!("Failed to remove '{}'", dir.display()))?;
+        }
+        Ok(())
+    }
+
+    /// The collection's stored schema, checked against the configuration. A
+    /// collection without one is new, or predates the schema record; its
+    /// schema is written from the configuration and any older per-setting
+    /// meta keys, which it replaces.
+    fn resolve_schema(lmdb: &LmdbWrapper, state: &State) -> Result<Schema> {
+        if let Some(stored) = lmdb.get_meta(SCHEMA_KEY)? {
+            let schema: Schema =
+                serde_json::from_str(&stored).context("Failed to parse the stored schema")?;
+            schema.validate(state)?;
+            return Ok(schema);
+        }
+
+        let metric = Self::legacy_setting(lmdb, "metric", state.metric, Metric::Cosine)?;
+        let precision = Self::legacy_setting(lmdb, "precision", state.precision, Precision::F32)?;
+        let schema = Schema::new(state, metric, precision);
+        schema.validate(state)?;
+
+        let mut wtxn = lmdb.write_txn()?;
+        lmdb.put_meta_in(&mut wtxn, SCHEMA_KEY, &serde_json::to_string(&schema)?)?;
+        for key in ["metric", "precision", RECORD_FORMAT_KEY] {
+            lmdb.delete_meta_in(&mut wtxn, key)?;
+        }
+        wtxn.commit()?;
+        Ok(schema)
+    }
+
+    /// A storage setting of a collection without a schema record: the stored
+    /// meta key if there is one, else the configured value for a new
+    /// collection, or `legacy` for one that already holds entries from
+    /// before the setting existed.
+    fn legacy_setting<T>(
+        lmdb: &LmdbWrapper,
+        key: &str,
+        configured: Option<T>,
+        legacy: T,
+    ) -> Result<T>
+    where
+        T: FromStr<Err = anyhow::Error> + Copy,
+    {
+        if let Some(stored) = lmdb.get_meta(key)? {
+            return stored.parse();
+        }
+        Ok(if lmdb.count()? == 0 {
+            configured.unwrap_or(legacy)
+        } else {
+            legacy
+        })
+    }
+
+    /// The stored schema of the selected collection, without checking it
+    /// against the configuration, or `None` if it has not been written yet.
+    pub fn stored_schema(state: &State) -> Result<Option<Schema>> {
+        if !Path::new(&state.path).join("data.mdb").exists() {
+            return Ok(None);
+        }
+        let lmdb = Self::open_registered(state, &state.collection)?;
+        lmdb.get_meta(SCHEMA_KEY)?
+            .map(|stored| serde_json::from_str(&stored))
+            .transpose()
+            .context("Failed to parse the stored schema")
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    pub fn map_usage(&self) -> Result<MapUsage> {
+        self.lmdb.map_usage()
+    }
+
+    /// Per-dimension ranges for int8-dim storage. A new database starts with
+    /// `[-1, 1]` in every dimension, which fits normalized vectors; `vekta
+    /// quantize` fits them to the stored data.
+    fn resolve_int8_ranges(
+        lmdb: &LmdbWrapper,
+        precision: Precision,
+        dimensions: usize,
+    ) -> Result<Vec<Number>> {
+        if precision != Precision::Int8Dim {
+            return Ok(Vec::new());
+        }
+        match lmdb.get_meta(INT8_RANGES_KEY)? {
+            Some(ranges) => {
+                serde_json::from_str(&ranges).context("Failed to parse stored int8 ranges")
+            }
+            None => {
+                let ranges = vec![1.0; dimensions];
+                lmdb.put_meta(INT8_RANGES_KEY, &serde_json::to_string(&ranges)?)?;
+                Ok(ranges)
+            }
+        }
+    }
+
+    pub fn metric(&self) -> Metric {
+        self.metric
+    }
+
+    pub fn precision(&self) -> Precision {
+        self.arena.precision()
+    }
+
+    /// Re-encode every stored vector in another precision, fitting the
+    /// per-dimension ranges first for int8-dim. The new arena is written next
+    /// to the old one and flushed, the new schema is recorded as pending, and
+    /// only then is the arena renamed over the old one and the schema put in
+    /// place, so `finish_quantize` can complete or undo a switch that was
+    /// interrupted. ANN buckets and IVF lists were computed from the old
+    /// values, so callers rebuild the indexes afterwards.
+    pub fn quantize(&mut self, precision: Precision) -> Result<usize> {
+        let decoded = self.vectors()?.decoded();
+        let ranges = match precision {
+            Precision::Int8Dim if decoded.is_empty() => vec![1.0; self.dimensions],
+            Precision::Int8Dim => (0..self.dimensions)
+                .map(|d| {
+                    decoded
+                        .iter()
+                        .fold(0.0 as Number, |max, (_, vector)| max.max(vector[d].abs()))
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        let arena_path = self.path.join(ARENA_FILE_NAME);
+        let tmp_path = arena_path.with_extension("bin.tmp");
+        if tmp_path.exists() {
+            fs::remove_file(&tmp_path)?;
+        }
+        let mut arena = VectorArena::open(&tmp_path, self.dimensions, precision, ranges.clone())?;
+        for (row, vector) in &decoded {
+            arena.write(*row, vector)?;
+        }
+        arena.sync()?;
+
+        let pending = PendingQuantize {
+            schema: Schema {
+                precision,
+                ..self.schema.clone()
+            },
+            ranges,
+        };
+        self.lmdb.with_write_txn(|wtxn| {
+            self.lmdb.put_meta_in(
+                wtxn,
+                PENDING_QUANTIZE_KEY,
+                &serde_json::to_string(&pending)?,
+            )
+        })?;
 
-        fs::create_dir_all(&path).with_context(|| {
+        fs::rename(&tmp_path, &arena_path).with_context(|| {
             format!(
-                "Failed to create directory for LMDB at '{}'",
-                path.display()
+                "Failed to replace vector arena at '{}'",
+                arena_path.display()
             )
         })?;
+        File::open(&self.path)
+            .and_then(|dir| dir.sync_all())
+            .with_context(|| format!("Failed to flush directory '{}'", self.path.display()))?;
 
-        println!("Attempting to open LMDB at: {}", path.display());
-
-        let env = unsafe {
-            EnvOpenOptions::new()
-                .map_size(10 * 1024 * 1024 * 1024) // 10GB
-                .max_dbs(2)
-                .open(&path)
-                .with_context(|| {
-                    format!("Failed to open LMDB environment at '{}'", path.display())
-                })?
-        };
-
-        let mut wtxn = env
-            .write_txn()
-            .with_context(|| "Failed to create write transaction for LMDB")?;
-        let db: heed::Database<Str, SerdeBincode<Vec<u8>>> = env
-            .create_database(&mut wtxn, Some("main"))
-            .with_context(|| "Failed to create main LMDB database")?;
-        let label_index: heed::Database<Str, Str> = env
-            .create_database(&mut wtxn, Some("label_index"))
-            .with_context(|| "Failed to create label index LMDB database")?;
-        wtxn.commit()
-            .with_context(|| "Failed to commit initial LMDB transaction")?;
-
-        Ok(Self {
-            env,
-            db,
-            label_index,
-        })
+        Self::apply_quantize(&self.lmdb, &pending)?;
+        self.schema = pending.schema;
+        self.arena = arena;
+        Ok(decoded.len())
     }
 
-    pub fn add(&self, key: &str, value: &[u8], label: &str) -> Result<()> {
-        let mut wtxn = self.env.write_txn()?;
-        self.db.put(&mut wtxn, key, &value.to_vec())?;
-        self.label_index.put(&mut wtxn, label, key)?;
-        wtxn.commit()?;
+    /// Complete or undo a `quantize` that stopped after recording its pending
+    /// schema. If the new arena is still next to the old one, it was never
+    /// renamed into place, so the old arena and schema still match and the
+    /// new arena is dropped; otherwise the pending schema is put in place.
+    fn finish_quantize(lmdb: &LmdbWrapper, dir: &Path) -> Result<()> {
+        let Some(pending) = lmdb.get_meta(PENDING_QUANTIZE_KEY)? else {
+            return Ok(());
+        };
+        let pending: PendingQuantize = serde_json::from_str(&pending)
+            .context("Failed to parse the pending precision change")?;
+        let tmp_path = dir.join(ARENA_FILE_NAME).with_extension("bin.tmp");
+        if tmp_path.exists() {
+            verbose_print("Undoing an interrupted precision change");
+            fs::remove_file(&tmp_path)?;
+            lmdb.with_write_txn(|wtxn| lmdb.delete_meta_in(wtxn, PENDING_QUANTIZE_KEY))?;
+        } else {
+            verbose_print(&format!(
+                "Completing an interrupted change to {} precision",
+                pending.schema.precision
+            ));
+            Self::apply_quantize(lmdb, &pending)?;
+        }
         Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
-        let rtxn = self.env.read_txn()?;
-        Ok(self.db.get(&rtxn, key)?.map(|v| v.to_vec()))
+    /// Store the schema and int8 ranges of a precision change and clear it
+    /// from pending, in one write transaction.
+    fn apply_quantize(lmdb: &LmdbWrapper, pending: &PendingQuantize) -> Result<()> {
+        lmdb.with_write_txn(|wtxn| {
+            lmdb.put_meta_in(wtxn, SCHEMA_KEY, &serde_json::to_string(&pending.schema)?)?;
+            if pending.schema.precision == Precision::Int8Dim {
+                lmdb.put_meta_in(
+                    wtxn,
+                    INT8_RANGES_KEY,
+                    &serde_json::to_string(&pending.ranges)?,
+                )?;
+            }
+            lmdb.delete_meta_in(wtxn, PENDING_QUANTIZE_KEY)
+        })
     }
 
-    pub fn label_exists(&self, label: &str) -> Result<bool> {
-        let rtxn = self.env.read_txn()?;
-        Ok(self.label_index.get(&rtxn, label)?.is_some())
-    }
+    /// Add a batch of entries in one write transaction. Entries are checked,
+    /// normalized and hashed in parallel on the current rayon pool, then
+    /// resolved in input order against the stored entries and the ones before
+    /// them in the batch, so the outcomes are those of adding them one by one.
+    /// A label repeated within the batch is folded into its earlier write
+    /// instead of being stored twice. If any entry fails, none of the batch is
+    /// stored.
+    pub fn add_batch(
+        &mut self,
+        entries: &[VectorEntry],
+        on_conflict: OnConflict,
+    ) -> Result<Vec<AddOutcome>> {
+        let prepared = entries
+            .par_iter()
+            .map(|entry| {
+                self.prepare_entry(entry)
+                    .with_context(|| format!("Failed to add entry with label: {}", entry.label))
+            })
+            .collect::<Result<Vec<_>>>()?;
 
-    pub fn iter(&self) -> Result<Vec<(String, Vec<u8>)>> {
-        let rtxn = self.env.read_txn()?;
-        let iter_result: Vec<(String, Vec<u8>)> = self
-            .db
-            .iter(&rtxn)?
-            .map(|result| result.map(|(k, v)| (k.to_string(), v.to_vec())))
-            .collect::<std::result::Result<Vec<_>, heed::Error>>()?;
+        let mut writes: Vec<PendingWrite> = Vec::new();
+        // Labels written earlier in this batch, with the index of their write
+        let mut batch_labels: HashMap<String, usize> = HashMap::new();
+        let mut outcomes = Vec::with_capacity(prepared.len());
 
-        Ok(iter_result)
-    }
+        'entries: for prepared in prepared {
+            let PreparedEntry {
+                mut entry,
+                content_hash,
+                stored,
+            } = prepared;
+            let label = entry.label.clone();
 
-    pub fn count(&self) -> Result<usize> {
-        let rtxn = self.env.read_txn()?;
-        Ok(self.db.len(&rtxn)?.try_into().unwrap())
-    }
-}
+            let unchanged = match batch_labels.get(&label) {
+                Some(&i) => Some(writes[i].content_hash == content_hash),
+                None => stored.as_ref().map(|(_, hash)| *hash == content_hash),
+            };
+            match unchanged {
+                None => {
+                    batch_labels.insert(label.clone(), writes.len());
+                    writes.push(PendingWrite {
+                        entry,
+                        content_hash,
+                        replacing: None,
+                    });
+                    outcomes.push(AddOutcome::Added(label));
+                    continue;
+                }
+                // Content is the same, no need to add
+                Some(true) => {
+                    outcomes.push(AddOutcome::Unchanged(label));
+                    continue;
+                }
+                Some(false) => {}
+            }
 
-pub struct VectorDatabase {
-    lmdb: LmdbWrapper,
-    vector_size: usize,
-    label_size: usize,
-}
+            match on_conflict {
+                OnConflict::Skip => outcomes.push(AddOutcome::Skipped(label)),
+                OnConflict::Error => {
+                    anyhow::bail!("Label '{}' already exists with different content", label)
+                }
+                OnConflict::Replace => {
+                    match batch_labels.get(&label) {
+                        // The earlier write never reaches LMDB, so it is simply swapped out
+                        Some(&i) => {
+                            writes[i].entry = entry;
+                            writes[i].content_hash = content_hash;
+                        }
+                        None => {
+                            batch_labels.insert(label.clone(), writes.len());
+                            writes.push(PendingWrite {
+                                entry,
+                                content_hash,
+                                replacing: stored.map(|(record, _)| record),
+                            });
+                        }
+                    }
+                    outcomes.push(AddOutcome::Replaced(label));
+                }
+                OnConflict::Rename => {
+                    let mut counter = 0;
+                    let final_label = loop {
+                        counter += 1;
+                        let candidate = format!("{}_{}", label, counter);
+                        let candidate_hash = match batch_labels.get(&candidate) {
+                            Some(&i) => Some(writes[i].content_hash.clone()),
+                            None => self
+                                .get_entry_by_label(&candidate)?
+                                .map(|existing| generate_content_hash(&existing)),
+                        };
+                        match candidate_hash {
+                            Some(hash) if hash == content_hash => {
+                                outcomes.push(AddOutcome::Unchanged(candidate));
+                                continue 'entries;
+                            }
+                            Some(_) => continue,
+                            None => break candidate,
+                        }
+                    };
 
-impl VectorDatabase {
-    pub fn open(state: &State) -> Result<Self> {
-        println!("Opening database at path: {}", state.path);
-        let lmdb = LmdbWrapper::new(&state.path)
-            .with_context(|| format!("Failed to create LMDB at '{}'", state.path))?;
+                    // The renamed entry is a separate record, so it needs its own key
+                    entry.label = final_label.clone();
+                    entry.unique_id =
+                        generate_unique_id(&final_label, &entry.vector, &entry.metadata);
+                    batch_labels.insert(final_label.clone(), writes.len());
+                    writes.push(PendingWrite {
+                        entry,
+                        content_hash,
+                        replacing: None,
+                    });
+                    outcomes.push(AddOutcome::Added(final_label));
+                }
+            }
+        }
 
-        Ok(Self {
-            lmdb,
-            vector_size: state.vector_size,
-            label_size: state.label_size,
-        })
+        self.store(&writes)?;
+        Ok(outcomes)
     }
 
-    pub fn add_entry(&mut self, entry: &VectorEntry) -> Result<String> {
+    /// Check an entry against the database's sizes, normalize it if the metric
+    /// calls for it and round it to the storage precision, and look up what is
+    /// stored under its label.
+    fn prepare_entry(&self, entry: &VectorEntry) -> Result<PreparedEntry> {
         if entry.vector.len() * std::mem::size_of::<Number>() != self.vector_size {
             anyhow::bail!(
                 "Vector size mismatch: expected {} bytes, got {} bytes",
@@ -130,98 +1459,694 @@ impl VectorDatabase {
             );
         }
 
-        let content_hash = generate_content_hash(entry);
-        let mut final_label = entry.label.clone();
-        let mut counter = 0;
+        // Create a copy of the entry, normalized if the metric calls for it; the label may be changed later
+        let mut normalized_entry = entry.clone();
+        if self.metric.normalizes() {
+            normalize_vector(&mut normalized_entry.vector);
+        }
+        self.arena.check_range(&normalized_entry.vector)?;
+        // Compare against what the vector reads back as in the storage precision
+        normalized_entry.vector = self.arena.round_trip(&normalized_entry.vector);
+        let content_hash = generate_content_hash(&normalized_entry);
+
+        let stored = match self.get_record_by_label(&entry.label)? {
+            Some(record) => {
+                let hash = generate_content_hash(&self.load_vector(record.clone())?);
+                Some((record, hash))
+            }
+            None => None,
+        };
 
-        loop {
-            if let Some(existing_entry) = self.get_entry_by_label(&final_label)? {
-                let existing_hash = generate_content_hash(&existing_entry);
-
-                if existing_hash == content_hash {
-                    // Content is the same, no need to add
-                    return Ok(final_label);
-                } else {
-                    // Same label, different content. Modify the label
-                    counter += 1;
-                    final_label = format!("{}_{}", entry.label, counter);
-                    continue;
+        Ok(PreparedEntry {
+            entry: normalized_entry,
+            content_hash,
+            stored,
+        })
+    }
+
+    /// Write the entries' vectors to the arena and their records to LMDB,
+    /// replacing the records they name, and keep the ANN buckets, HNSW graph,
+    /// IVF posting lists and metadata field index in step, all in one write
+    /// transaction.
+    fn store(&mut self, writes: &[PendingWrite]) -> Result<()> {
+        if writes.is_empty() {
+            return Ok(());
+        }
+        let lmdb = self.lmdb.clone();
+        self.hnsw = lmdb.with_write_txn(|wtxn| self.write_batch(wtxn, writes))?;
+        Ok(())
+    }
+
+    /// The writes of `store` within `wtxn`, returning the updated HNSW header.
+    /// Nothing outside the transaction changes, except arena rows that no
+    /// record points to yet, so a failed attempt can simply be repeated.
+    fn write_batch(
+        &mut self,
+        wtxn: &mut RwTxn,
+        writes: &[PendingWrite],
+    ) -> Result<Option<HnswHeader>> {
+        let old_vectors = writes
+            .iter()
+            .map(|write| match &write.replacing {
+                Some(old) => self.arena.decode(old.row as usize).map(Some),
+                None => Ok(None),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // The header is only updated in memory once the transaction commits
+        let mut hnsw = self.hnsw.clone();
+
+        // Every new row is allocated before any old one is released, so the
+        // replaced vectors stay intact until the transaction commits
+        let rows = self.lmdb.allocate_rows(wtxn, writes.len())?;
+        for (write, &row) in writes.iter().zip(&rows) {
+            self.arena.write(row, &write.entry.vector)?;
+            if let Some(codes) = &mut self.binary_codes {
+                codes.write(row, &write.entry.vector)?;
+            }
+        }
+        // The commit is durable, so the rows it points at have to be as well
+        self.arena.sync()?;
+        if let Some(codes) = &self.binary_codes {
+            codes.sync()?;
+        }
+
+        for ((write, old_vector), &row) in writes.iter().zip(&old_vectors).zip(&rows) {
+            let entry = &write.entry;
+            if let (Some(old), Some(old_vector)) = (&write.replacing, old_vector) {
+                if let Some(index) = &self.ann_index {
+                    let keys = index.bucket_keys(old_vector);
+                    self.lmdb
+                        .delete_ann_buckets(wtxn, &keys, old.row as usize)?;
                 }
+                if let Some(index) = &self.ivf_index {
+                    self.delete_ivf_postings(wtxn, index, old_vector, old.row as usize)?;
+                }
+                if let Some(header) = &mut hnsw {
+                    header.remove(
+                        &mut self.lmdb.graph_writer(wtxn),
+                        &self.arena,
+                        self.metric,
+                        old.row as usize,
+                    )?;
+                }
+                if let Some(index) = &self.field_index {
+                    self.lmdb.delete_field_keys(
+                        wtxn,
+                        &index.keys(&old.metadata),
+                        old.row as usize,
+                    )?;
+                }
+                self.lmdb
+                    .remove(wtxn, &old.unique_id, &old.label, old.row as usize)?;
             }
 
-            // Label doesn't exist, we can add the entry
-            break;
+            let value = EntryRecord::from_entry(entry, row).encode()?;
+            self.lmdb
+                .put_record(wtxn, &entry.unique_id, &entry.label, row, &value)?;
+            if let Some(index) = &self.ann_index {
+                self.lmdb
+                    .put_ann_buckets(wtxn, &index.bucket_keys(&entry.vector), row)?;
+            }
+            if let Some(index) = &self.ivf_index {
+                self.put_ivf_postings(wtxn, index, &entry.vector, row)?;
+            }
+            if let Some(index) = &self.field_index {
+                self.lmdb
+                    .put_field_keys(wtxn, &index.keys(&entry.metadata), row)?;
+            }
+            if let Some(header) = &mut hnsw {
+                let mut graph = self.lmdb.graph_writer(wtxn);
+                header.insert(&mut graph, &self.arena, self.metric, row)?;
+            }
+        }
+        if let Some(header) = &hnsw {
+            self.lmdb
+                .put_index(wtxn, HNSW_INDEX_NAME, &bincode::serialize(header)?)?;
         }
+        Ok(hnsw)
+    }
 
-        // Create a copy of the entry with a normalized vector and possibly modified label
-        let mut normalized_entry = entry.clone();
-        normalize_vector(&mut normalized_entry.vector);
-        normalized_entry.label = final_label.clone();
+    /// Remove an entry by unique_id or, failing that, by label.
+    /// Returns the unique_id of the removed entry, or `None` if nothing matched.
+    pub fn delete_entry(&mut self, key: &str) -> Result<Option<String>> {
+        let record = match self.get_record(key)? {
+            Some(record) => record,
+            None => match self.get_record_by_label(key)? {
+                Some(record) => record,
+                None => return Ok(None),
+            },
+        };
+        let row = record.row as usize;
+
+        let hnsw = self.lmdb.with_write_txn(|wtxn| {
+            let mut hnsw = self.hnsw.clone();
+            if let Some(index) = &self.ann_index {
+                let keys = index.bucket_keys(&self.arena.decode(row)?);
+                self.lmdb.delete_ann_buckets(wtxn, &keys, row)?;
+            }
+            if let Some(index) = &self.ivf_index {
+                self.delete_ivf_postings(wtxn, index, &self.arena.decode(row)?, row)?;
+            }
+            if let Some(index) = &self.field_index {
+                self.lmdb
+                    .delete_field_keys(wtxn, &index.keys(&record.metadata), row)?;
+            }
+            if let Some(header) = &mut hnsw {
+                header.remove(
+                    &mut self.lmdb.graph_writer(wtxn),
+                    &self.arena,
+                    self.metric,
+                    row,
+                )?;
+                self.lmdb
+                    .put_index(wtxn, HNSW_INDEX_NAME, &bincode::serialize(header)?)?;
+            }
+            self.lmdb
+                .remove(wtxn, &record.unique_id, &record.label, row)?;
+            Ok(hnsw)
+        })?;
+        self.hnsw = hnsw;
 
-        let value = bincode::serialize(&normalized_entry)?;
-        self.lmdb
-            .add(&normalized_entry.unique_id, &value, &final_label)?;
-        Ok(final_label)
+        Ok(Some(record.unique_id))
     }
 
-    #[allow(dead_code)]
-    pub fn get_entry(&self, unique_id: &str) -> Result<Option<VectorEntry>> {
-        if let Some(value) = self.lmdb.get(unique_id)? {
-            Ok(Some(bincode::deserialize(&value)?))
-        } else {
-            Ok(None)
+    pub fn ann_index(&self) -> Option<&RandomProjectionIndex> {
+        self.ann_index.as_ref()
+    }
+
+    /// Build a fresh ANN index over every live vector, sized for the current
+    /// row count, and persist it in place of any existing one.
+    pub fn rebuild_ann_index(&mut self) -> Result<usize> {
+        let vectors = self.vectors()?;
+        let index = RandomProjectionIndex::new(self.dimensions, vectors.rows.len());
+
+        self.lmdb.with_write_txn(|wtxn| {
+            self.lmdb.clear_ann_buckets(wtxn)?;
+            for (row, vector) in vectors.iter() {
+                self.lmdb
+                    .put_ann_buckets(wtxn, &index.bucket_keys(&vector.to_vec()), row)?;
+            }
+            self.lmdb
+                .put_index(wtxn, ANN_INDEX_NAME, &bincode::serialize(&index)?)
+        })?;
+
+        let count = vectors.rows.len();
+        self.ann_index = Some(index);
+        Ok(count)
+    }
+
+    /// Rows sharing a bucket with the query in the persisted ANN index.
+    pub fn ann_candidates(&self, query: &[Number]) -> Result<HashSet<usize>> {
+        let index = self
+            .ann_index
+            .as_ref()
+            .context("ANN index not initialized")?;
+        self.lmdb.ann_bucket_rows(&index.probe_keys(query))
+    }
+
+    pub fn hnsw_index(&self) -> Option<&HnswHeader> {
+        self.hnsw.as_ref()
+    }
+
+    /// Build a fresh HNSW graph over every live vector and persist it in place
+    /// of any existing one.
+    pub fn rebuild_hnsw_index(&mut self, params: HnswParams) -> Result<usize> {
+        let rows = self.lmdb.live_rows()?;
+
+        let header = self.lmdb.with_write_txn(|wtxn| {
+            let mut header = HnswHeader::new(params);
+            self.lmdb.clear_hnsw_nodes(wtxn)?;
+            let mut graph = self.lmdb.graph_writer(wtxn);
+            for &row in &rows {
+                header.insert(&mut graph, &self.arena, self.metric, row)?;
+            }
+            self.lmdb
+                .put_index(wtxn, HNSW_INDEX_NAME, &bincode::serialize(&header)?)?;
+            Ok(header)
+        })?;
+
+        self.hnsw = Some(header);
+        Ok(rows.len())
+    }
+
+    /// Walk the persisted HNSW graph for the rows closest to the query.
+    /// Only rows in `filter`, if given, are returned; the walk itself still
+    /// passes through the others.
+    pub fn hnsw_search(
+        &self,
+        query: &[Number],
+        k: usize,
+        ef_search: usize,
+        filter: Option<&RowSet>,
+    ) -> Result<Vec<ScoredRow>> {
+        let header = self.hnsw.as_ref().context("HNSW index not initialized")?;
+        let graph = self.lmdb.graph_reader()?;
+        header.search(
+            &graph,
+            &self.arena,
+            self.metric,
+            query,
+            k,
+            ef_search,
+            filter,
+        )
+    }
+
+    pub fn ivf_index(&self) -> Option<&IvfIndex> {
+        self.ivf_index.as_ref()
+    }
+
+    pub fn ivf_pq(&self) -> Option<&PqIndex> {
+        self.ivf_pq.as_ref()
+    }
+
+    /// Post a vector to its IVF list, with its PQ code if the index has a codec.
+    fn put_ivf_postings(
+        &self,
+        wtxn: &mut RwTxn,
+        index: &IvfIndex,
+        vector: &[Number],
+        row: usize,
+    ) -> Result<()> {
+        let list = index.assign(vector);
+        self.lmdb.put_ivf_posting(wtxn, list, row)?;
+        if let Some(pq) = &self.ivf_pq {
+            let code = pq.quantizer.encode(&residual(vector, index.centroid(list)));
+            self.lmdb.put_ivf_pq_posting(wtxn, list, row, &code)?;
         }
+        Ok(())
     }
 
-    pub fn get_entry_by_index(&self, index: usize) -> Result<Option<VectorEntry>> {
-        let entries = self.lmdb.iter()?;
-        entries
-            .get(index)
-            .map(|(_, value)| bincode::deserialize(value))
-            .transpose()
-            .map_err(|e| e.into())
+    /// Undo `put_ivf_postings`; the vector must be the one that was posted.
+    fn delete_ivf_postings(
+        &self,
+        wtxn: &mut RwTxn,
+        index: &IvfIndex,
+        vector: &[Number],
+        row: usize,
+    ) -> Result<()> {
+        let list = index.assign(vector);
+        self.lmdb.delete_ivf_posting(wtxn, list, row)?;
+        if let Some(pq) = &self.ivf_pq {
+            let code = pq.quantizer.encode(&residual(vector, index.centroid(list)));
+            self.lmdb.delete_ivf_pq_posting(wtxn, list, row, &code)?;
+        }
+        Ok(())
     }
 
-    pub fn list_entries(&self) -> Result<Vec<String>> {
-        self.lmdb
-            .iter()?
+    /// Train k-means centroids over every live vector, post each vector to its
+    /// nearest list, and persist both in place of any existing IVF index.
+    /// Without an explicit `nlist`, the square root of the row count is used.
+    /// With PQ settings, a product quantizer is also trained on the residuals
+    /// and its re-ranking depth calibrated; without them any codec is dropped.
+    pub fn train_ivf_index(
+        &mut self,
+        nlist: Option<usize>,
+        pq_settings: Option<PqSettings>,
+    ) -> Result<usize> {
+        let decoded = self.vectors()?.decoded();
+        let data: Vec<&[Number]> = decoded
+            .iter()
+            .map(|(_, vector)| vector.as_slice())
+            .collect();
+        let nlist = nlist.unwrap_or_else(|| (data.len() as f64).sqrt().ceil() as usize);
+        let index = IvfIndex::train(&data, nlist, self.metric.normalizes())?;
+        let assigned: Vec<(usize, &[Number], u32)> = decoded
+            .iter()
+            .map(|(row, vector)| (*row, vector.as_slice(), index.assign(vector)))
+            .collect();
+
+        let mut encoded = Vec::new();
+        let pq = match pq_settings {
+            Some(settings) => {
+                let residuals: Vec<Vec<Number>> = assigned
+                    .iter()
+                    .map(|&(_, vector, list)| residual(vector, index.centroid(list)))
+                    .collect();
+                let quantizer =
+                    ProductQuantizer::train(&residuals, settings.subquantizers, settings.bits)?;
+                encoded = assigned
+                    .iter()
+                    .zip(&residuals)
+                    .map(|(&(row, vector, list), residual)| EncodedRow {
+                        row,
+                        vector,
+                        list,
+                        code: quantizer.encode(residual),
+                    })
+                    .collect();
+                let (rerank_factor, calibrated_recall) =
+                    calibrate_rerank(&index, &quantizer, self.metric, &encoded, &settings);
+                Some(PqIndex {
+                    quantizer,
+                    rerank_factor,
+                    calibrated_recall,
+                })
+            }
+            None => None,
+        };
+
+        self.lmdb.with_write_txn(|wtxn| {
+            self.lmdb.clear_ivf_lists(wtxn)?;
+            self.lmdb.clear_ivf_pq_lists(wtxn)?;
+            for &(row, _, list) in &assigned {
+                self.lmdb.put_ivf_posting(wtxn, list, row)?;
+            }
+            for entry in &encoded {
+                self.lmdb
+                    .put_ivf_pq_posting(wtxn, entry.list, entry.row, &entry.code)?;
+            }
+            self.lmdb
+                .put_index(wtxn, IVF_INDEX_NAME, &bincode::serialize(&index)?)?;
+            match &pq {
+                Some(pq) => self
+                    .lmdb
+                    .put_index(wtxn, IVF_PQ_INDEX_NAME, &bincode::serialize(pq)?),
+                None => self.lmdb.delete_index(wtxn, IVF_PQ_INDEX_NAME),
+            }
+        })?;
+
+        let count = assigned.len();
+        self.ivf_index = Some(index);
+        self.ivf_pq = pq;
+        Ok(count)
+    }
+
+    pub fn binary_codes(&self) -> Option<&BinaryCodes> {
+        self.binary_codes.as_ref()
+    }
+
+    /// Write the sign-bit code of every live vector to a fresh codes file and
+    /// mark the binary index as present, so new entries get codes too.
+    pub fn rebuild_binary_index(&mut self) -> Result<usize> {
+        let vectors = self.vectors()?;
+        let mut codes = BinaryCodes::open(self.path.join(CODES_FILE_NAME), self.dimensions)?;
+        codes.clear()?;
+        for (row, vector) in vectors.iter() {
+            codes.write(row, &vector.to_vec())?;
+        }
+        codes.sync()?;
+
+        self.lmdb.with_write_txn(|wtxn| {
+            self.lmdb.put_index(
+                wtxn,
+                BINARY_INDEX_NAME,
+                &bincode::serialize(&self.dimensions)?,
+            )
+        })?;
+
+        let count = vectors.rows.len();
+        self.binary_codes = Some(codes);
+        Ok(count)
+    }
+
+    pub fn field_index(&self) -> Option<&FieldIndex> {
+        self.field_index.as_ref()
+    }
+
+    pub fn indexed_fields(&self) -> &[String] {
+        self.field_index.as_ref().map_or(&[], FieldIndex::fields)
+    }
+
+    /// Index the given metadata fields of every stored entry, replacing any
+    /// existing field index. An empty list drops the index.
+    pub fn rebuild_field_index(&mut self, fields: Vec<String>) -> Result<usize> {
+        if fields.is_empty() {
+            self.lmdb.with_write_txn(|wtxn| {
+                self.lmdb.clear_field_index(wtxn)?;
+                self.lmdb.delete_index(wtxn, FIELD_INDEX_NAME)
+            })?;
+            self.field_index = None;
+            return Ok(0);
+        }
+
+        let index = FieldIndex::new(fields)?;
+        let mut postings = Vec::new();
+        self.lmdb.for_each_record(|value| {
+            let record = EntryRecord::decode(value)?;
+            postings.push((index.keys(&record.metadata), record.row as usize));
+            Ok(())
+        })?;
+
+        self.lmdb.with_write_txn(|wtxn| {
+            self.lmdb.clear_field_index(wtxn)?;
+            for (keys, row) in &postings {
+                self.lmdb.put_field_keys(wtxn, keys, *row)?;
+            }
+            self.lmdb
+                .put_index(wtxn, FIELD_INDEX_NAME, &bincode::serialize(&index)?)
+        })?;
+
+        self.field_index = Some(index);
+        Ok(postings.len())
+    }
+
+    /// The `depth` rows among `rows`, which must be live, whose binary codes
+    /// are closest to the query's.
+    pub fn binary_candidates(
+        &self,
+        query: &[Number],
+        rows: &[usize],
+        depth: usize,
+    ) -> Result<Vec<usize>> {
+        let codes = self
+            .binary_codes
+            .as_ref()
+            .context("Binary index not initialized")?;
+        Ok(codes
+            .nearest(query, rows, depth)?
+            .into_sorted_vec()
             .into_iter()
-            .map(|(key, _)| Ok(key))
-            .collect()
+            .map(|(row, _)| row)
+            .collect())
     }
 
-    pub fn count(&self) -> Result<usize> {
-        self.lmdb.count()
+    /// Rows in the `nprobe` posting lists closest to the query, and in
+    /// `filter` if given.
+    pub fn ivf_candidates(
+        &self,
+        query: &[Number],
+        nprobe: usize,
+        filter: Option<&RowSet>,
+    ) -> Result<Vec<usize>> {
+        let index = self
+            .ivf_index
+            .as_ref()
+            .context("IVF index not trained; run `vekta train`")?;
+        let mut rows = self.lmdb.ivf_list_rows(&index.probe_lists(query, nprobe))?;
+        if let Some(filter) = filter {
+            rows.retain(|&row| filter.contains(row));
+        }
+        Ok(rows)
     }
 
-    pub fn label_exists(&self, label: &str) -> Result<bool> {
-        self.lmdb.label_exists(label)
+    /// The `depth` rows in the `nprobe` closest lists that score best against
+    /// the query by their PQ codes, to be re-ranked at full precision. Rows
+    /// outside `filter`, if given, are skipped before scoring.
+    pub fn ivf_pq_candidates(
+        &self,
+        query: &[Number],
+        nprobe: usize,
+        depth: usize,
+        filter: Option<&RowSet>,
+    ) -> Result<Vec<usize>> {
+        let index = self
+            .ivf_index
+            .as_ref()
+            .context("IVF index not trained; run `vekta train`")?;
+        let pq = self.ivf_pq.as_ref().context("IVF index has no PQ codec")?;
+
+        let mut best = TopK::new(depth, None);
+        for list in index.probe_lists(query, nprobe) {
+            let table = pq
+                .quantizer
+                .score_table(self.metric, query, index.centroid(list));
+            self.lmdb.for_each_ivf_pq_posting(list, |row, code| {
+                if filter.is_none_or(|filter| filter.contains(row)) {
+                    best.push(row, table.score(code));
+                }
+            })?;
+        }
+        Ok(best
+            .into_sorted_vec()
+            .into_iter()
+            .map(|(row, _)| row)
+            .collect())
     }
 
-    pub fn get_entry_by_label(&self, label: &str) -> Result<Option<VectorEntry>> {
-        let rtxn = self.lmdb.env.read_txn()?;
-        if let Some(unique_id) = self.lmdb.label_index.get(&rtxn, label)? {
-            self.get_entry(&unique_id)
+    /// The rows whose metadata matches each filter. Filters the field index
+    /// can answer are narrowed by index lookups, and only their candidates
+    /// are checked against the records when the lookup was inexact; the rest
+    /// share one pass over the stored records.
+    pub fn matching_rows(&self, filters: &[&Filter]) -> Result<Vec<RowSet>> {
+        let mut matches: Vec<RowSet> = filters.iter().map(|_| RowSet::default()).collect();
+        let mut scanned = Vec::new();
+        for (i, filter) in filters.iter().enumerate() {
+            let plan = match &self.field_index {
+                Some(index) => {
+                    index.plan(filter, &mut |range| self.lmdb.field_index_rows(range))?
+                }
+                None => None,
+            };
+            match plan {
+                Some(plan) if plan.exact => {
+                    verbose_print("Filter answered by the metadata field index");
+                    matches[i] = plan.rows;
+                }
+                Some(plan) => {
+                    verbose_print(&format!(
+                        "Filter narrowed to {} candidates by the metadata field index",
+                        plan.rows.len()
+                    ));
+                    let rows = &mut matches[i];
+                    self.lmdb.for_each_record_at(plan.rows.iter(), |value| {
+                        let record = EntryRecord::decode(value)?;
+                        if filter.matches(&record.metadata) {
+                            rows.insert(record.row as usize);
+                        }
+                        Ok(())
+                    })?;
+                }
+                None => scanned.push(i),
+            }
+        }
+        if scanned.is_empty() {
+            return Ok(matches);
+        }
+
+        self.lmdb.for_each_record(|value| {
+            let record = EntryRecord::decode(value)?;
+            for &i in &scanned {
+                if filters[i].matches(&record.metadata) {
+                    matches[i].insert(record.row as usize);
+                }
+            }
+            Ok(())
+        })?;
+        Ok(matches)
+    }
+
+    pub fn get_record(&self, unique_id: &str) -> Result<Option<EntryRecord>> {
+        if let Some(value) = self.lmdb.get(unique_id)? {
+            Ok(Some(EntryRecord::decode(&value)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn get_record_by_row(&self, row: usize) -> Result<Option<EntryRecord>> {
+        if let Some(value) = self.lmdb.get_by_row(row)? {
+            Ok(Some(EntryRecord::decode(&value)?))
         } else {
             Ok(None)
         }
     }
+
+    pub fn get_record_by_label(&self, label: &str) -> Result<Option<EntryRecord>> {
+        match self.lmdb.key_for_label(label)? {
+            Some(unique_id) => self.get_record(&unique_id),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_entry(&self, unique_id: &str) -> Result<Option<VectorEntry>> {
+        self.get_record(unique_id)?
+            .map(|record| self.load_vector(record))
+            .transpose()
+    }
+
+    pub fn get_entry_by_label(&self, label: &str) -> Result<Option<VectorEntry>> {
+        self.get_record_by_label(label)?
+            .map(|record| self.load_vector(record))
+            .transpose()
+    }
+
+    /// Look an entry up by unique_id or, failing that, by label. The vector
+    /// is the stored one, so it is normalized if the metric calls for it and
+    /// rounded to the storage precision.
+    pub fn find_entry(&self, key: &str) -> Result<Option<VectorEntry>> {
+        match self.get_entry(key)? {
+            Some(entry) => Ok(Some(entry)),
+            None => self.get_entry_by_label(key),
+        }
+    }
+
+    fn load_vector(&self, record: EntryRecord) -> Result<VectorEntry> {
+        let vector = self.arena.decode(record.row as usize)?;
+        Ok(record.into_entry(vector))
+    }
+
+    /// One row's vector, read straight from the memory-mapped arena. Unlike
+    /// `vectors`, this does not list the live rows, so scoring index
+    /// candidates costs nothing per stored vector.
+    pub fn stored_vector(&self, row: usize) -> Option<StoredVector<'_>> {
+        self.arena.row(row).ok()
+    }
+
+    /// Ids of all rows that currently hold a stored vector, in ascending order.
+    pub fn live_rows(&self) -> Result<Vec<usize>> {
+        self.lmdb.live_rows()
+    }
+
+    /// All live vectors, read straight from the memory-mapped arena.
+    pub fn vectors(&self) -> Result<RowVectors<'_>> {
+        Ok(RowVectors {
+            rows: self.lmdb.live_rows()?,
+            arena: &self.arena,
+        })
+    }
+
+    pub fn list_entries(&self) -> Result<Vec<String>> {
+        self.lmdb.keys()
+    }
+
+    pub fn count(&self) -> Result<usize> {
+        self.lmdb.count()
+    }
 }
 
 pub fn parse_input_line(line: &str, state: &State) -> Result<VectorEntry> {
-    let mut json_value: Value = serde_json::from_str(line)
+    let json_value: Value = serde_json::from_str(line)
         .with_context(|| format!("Failed to parse JSON from input line: {}", line))?;
 
-    println!("JSON parsed successfully");
+    verbose_print("JSON parsed successfully");
+
+    parse_input_value(json_value, state)
+}
+
+/// Parse a search query: an input line that may also carry a `filter`.
+pub fn parse_query_line(line: &str, state: &State) -> Result<(VectorEntry, Option<Filter>)> {
+    let mut json_value: Value = serde_json::from_str(line)
+        .with_context(|| format!("Failed to parse JSON from input line: {}", line))?;
+    let filter = match json_value
+        .as_object_mut()
+        .and_then(|obj| obj.remove("filter"))
+    {
+        Some(filter) => Some(Filter::from_value(filter)?),
+        None => None,
+    };
+    Ok((parse_input_value(json_value, state)?, filter))
+}
 
+fn parse_input_value(mut json_value: Value, state: &State) -> Result<VectorEntry> {
     ensure_utf8(&mut json_value);
 
+    // Null metadata is stored as `{}`, like missing metadata, so the entry
+    // and its unique_id agree
+    if json_value.get("metadata").is_some_and(Value::is_null) {
+        json_value["metadata"] = empty_metadata();
+    }
+
     if json_value.get("unique_id").is_none() {
         let label = json_value["label"].as_str().unwrap_or("");
         let vector = json_value["vector"]
             .as_array()
             .ok_or_else(|| anyhow::anyhow!("Vector field is missing or not an array"))?;
-        let metadata = json_value["metadata"].clone();
+        let metadata = json_value
+            .get("metadata")
+            .cloned()
+            .unwrap_or_else(empty_metadata);
 
         let mut vector_numbers: Vec<Number> = vector
             .iter()
@@ -237,9 +2162,6 @@ pub fn parse_input_line(line: &str, state: &State) -> Result<VectorEntry> {
         // Normalize the vector
         normalize_vector(&mut vector_numbers);
 
-        let metadata: Metadata =
-            serde_json::from_value(metadata).with_context(|| "Failed to parse metadata")?;
-
         let unique_id = generate_unique_id(label, &vector_numbers, &metadata);
         json_value["unique_id"] = Value::String(unique_id);
     }
@@ -251,7 +2173,11 @@ pub fn parse_input_line(line: &str, state: &State) -> Result<VectorEntry> {
         )
     })?;
 
-    println!("Deserialized into VectorEntry successfully");
+    verbose_print("Deserialized into VectorEntry successfully");
+
+    if !entry.metadata.is_object() {
+        anyhow::bail!("Metadata must be a JSON object");
+    }
 
     if entry.vector.len() != state.dimensions {
         anyhow::bail!(
@@ -264,12 +2190,26 @@ pub fn parse_input_line(line: &str, state: &State) -> Result<VectorEntry> {
     Ok(entry)
 }
 
+/// Parse a key line for commands that address existing entries. Accepts a bare
+/// label or unique_id, a JSON string, or a JSON object with `unique_id` or `label`.
+pub fn parse_key_line(line: &str) -> Result<String> {
+    let line = line.trim();
+    match serde_json::from_str::<Value>(line) {
+        Ok(Value::String(key)) => Ok(key),
+        Ok(Value::Object(obj)) => obj
+            .get("unique_id")
+            .or_else(|| obj.get("label"))
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| anyhow::anyhow!("JSON input has no 'unique_id' or 'label' field")),
+        _ => Ok(line.to_string()),
+    }
+}
+
 pub fn ensure_utf8(value: &mut Value) {
     match value {
-        Value::String(s) => {
-            if let Err(_) = String::from_utf8(s.clone().into_bytes()) {
-                *s = String::from_utf8_lossy(s.as_bytes()).into_owned();
-            }
+        Value::String(s) if String::from_utf8(s.clone().into_bytes()).is_err() => {
+            *s = String::from_utf8_lossy(s.as_bytes()).into_owned();
         }
         Value::Array(arr) => {
             for item in arr {
@@ -285,21 +2225,33 @@ pub fn ensure_utf8(value: &mut Value) {
     }
 }
 
-pub fn generate_unique_id(label: &str, vector: &[Number], metadata: &Metadata) -> String {
+pub fn generate_unique_id(label: &str, vector: &[Number], metadata: &Value) -> String {
     let mut hasher = Sha256::new();
     hasher.update(label.as_bytes());
     for &num in vector {
         hasher.update(num.to_le_bytes());
     }
-    hasher.update(serde_json::to_string(metadata).unwrap().as_bytes());
+    hasher.update(canonical_metadata(metadata).as_bytes());
     format!("{:x}", hasher.finalize())
 }
 
+/// The metadata text that ids and content hashes are computed from. JSON
+/// objects serialize with sorted keys; code-chunk metadata keeps the field
+/// order of its typed view, so entries keep the ids they had before metadata
+/// became free-form.
+fn canonical_metadata(metadata: &Value) -> String {
+    match CodeChunk::from_metadata(metadata) {
+        Some(chunk) => serde_json::to_string(&chunk),
+        None => serde_json::to_string(metadata),
+    }
+    .unwrap()
+}
+
 fn generate_content_hash(entry: &VectorEntry) -> String {
     let mut hasher = Sha256::new();
     for &num in &entry.vector {
         hasher.update(num.to_le_bytes());
     }
-    hasher.update(serde_json::to_string(&entry.metadata).unwrap().as_bytes());
+    hasher.update(canonical_metadata(&entry.metadata).as_bytes());
     format!("{:x}", hasher.finalize())
 }
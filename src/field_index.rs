@@ -0,0 +1,488 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::ops::Bound;
+
+use crate::filter::{Filter, RowSet};
+use crate::vector_entry::metadata_field;
+
+/// LMDB's default limit on key length.
+const MAX_KEY_SIZE: usize = 511;
+/// Longest field path that can be indexed, leaving room for the value.
+const MAX_FIELD_SIZE: usize = 255;
+
+const TAG_BOOL: u8 = 1;
+const TAG_NUMBER: u8 = 2;
+const TAG_STRING: u8 = 3;
+
+/// Secondary index over scalar metadata values. Every indexed field of an
+/// entry contributes one key, `field \0 tag value`, posted to the entry's
+/// row. Numbers are encoded so that byte order matches numeric order, which
+/// lets range filters scan a contiguous run of keys. Strings longer than the
+/// key limit are truncated, so lookups on them only narrow the candidates.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct FieldIndex {
+    fields: Vec<String>,
+}
+
+/// A contiguous run of index keys.
+pub struct KeyRange {
+    pub start: Bound<Vec<u8>>,
+    pub end: Bound<Vec<u8>>,
+}
+
+/// Rows an index lookup found for a filter. When `exact` is false the rows
+/// are a superset of the matches and each must still be checked against the
+/// filter.
+pub struct Plan {
+    pub rows: RowSet,
+    pub exact: bool,
+}
+
+impl FieldIndex {
+    pub fn new(fields: Vec<String>) -> Result<Self> {
+        for field in &fields {
+            if field.is_empty() || field.contains('\0') {
+                anyhow::bail!("Invalid indexed field '{}'", field);
+            }
+            if field.len() > MAX_FIELD_SIZE {
+                anyhow::bail!(
+                    "Indexed field '{}' is longer than {} bytes",
+                    field,
+                    MAX_FIELD_SIZE
+                );
+            }
+        }
+        Ok(Self { fields })
+    }
+
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    /// The index keys for an entry's metadata. Fields that are missing or
+    /// hold an array, an object or null are not indexed.
+    pub fn keys(&self, metadata: &Value) -> Vec<Vec<u8>> {
+        self.fields
+            .iter()
+            .filter_map(|field| {
+                metadata_field(metadata, field).and_then(|value| encode_key(field, value))
+            })
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    /// Narrow a filter to candidate rows with index lookups, or `None` if some
+    /// part of it touches a field that is not indexed and the records have to
+    /// be scanned instead. `lookup` returns the rows posted under a key range.
+    pub fn plan(
+        &self,
+        filter: &Filter,
+        lookup: &mut impl FnMut(&KeyRange) -> Result<RowSet>,
+    ) -> Result<Option<Plan>> {
+        let plan = match filter {
+            Filter::And(filters) => {
+                // Any indexed conjunct narrows the result; the rest are checked later
+                let mut narrowed: Option<Plan> = None;
+                let mut exact = true;
+                for filter in filters {
+                    match self.plan(filter, lookup)? {
+                        Some(plan) => {
+                            exact &= plan.exact;
+                            narrowed = Some(match narrowed {
+                                Some(mut narrowed) => {
+                                    narrowed.rows.intersect_with(&plan.rows);
+                                    narrowed
+                                }
+                                None => plan,
+                            });
+                        }
+                        None => exact = false,
+                    }
+                }
+                narrowed.map(|rows| Plan {
+                    rows: rows.rows,
+                    exact,
+                })
+            }
+            Filter::Or(filters) => {
+                let mut rows = RowSet::default();
+                let mut exact = true;
+                for filter in filters {
+                    match self.plan(filter, lookup)? {
+                        Some(plan) => {
+                            exact &= plan.exact;
+                            rows.union_with(&plan.rows);
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                Some(Plan { rows, exact })
+            }
+            Filter::Not(_) => None,
+            Filter::Eq { field, value } if self.is_indexed(field) => {
+                match encode_key(field, value) {
+                    Some((key, exact)) => Some(Plan {
+                        rows: lookup(&exact_range(key))?,
+                        exact,
+                    }),
+                    None => None,
+                }
+            }
+            Filter::Prefix { field, value } if self.is_indexed(field) => {
+                let (key, exact) = string_key(field, value);
+                Some(Plan {
+                    rows: lookup(&prefix_range(key))?,
+                    exact,
+                })
+            }
+            Filter::Glob { field, pattern } if self.is_indexed(field) => {
+                let literal = pattern
+                    .find(['*', '?'])
+                    .map_or(pattern.as_str(), |end| &pattern[..end]);
+                let (key, _) = string_key(field, literal);
+                Some(Plan {
+                    rows: lookup(&prefix_range(key))?,
+                    exact: false,
+                })
+            }
+            Filter::Range {
+                field,
+                gt,
+                gte,
+                lt,
+                lte,
+            } if self.is_indexed(field) => {
+                let start = match (*gt, *gte) {
+                    (Some(gt), Some(gte)) if gt >= gte => Bound::Excluded(number_key(field, gt)),
+                    (_, Some(gte)) => Bound::Included(number_key(field, gte)),
+                    (Some(gt), None) => Bound::Excluded(number_key(field, gt)),
+                    (None, None) => Bound::Included(tag_prefix(field, TAG_NUMBER)),
+                };
+                let end = match (*lt, *lte) {
+                    (Some(lt), Some(lte)) if lt <= lte => Bound::Excluded(number_key(field, lt)),
+                    (_, Some(lte)) => Bound::Included(number_key(field, lte)),
+                    (Some(lt), None) => Bound::Excluded(number_key(field, lt)),
+                    (None, None) => Bound::Excluded(tag_prefix(field, TAG_NUMBER + 1)),
+                };
+                Some(Plan {
+                    rows: lookup(&KeyRange { start, end })?,
+                    exact: true,
+                })
+            }
+            _ => None,
+        };
+        Ok(plan)
+    }
+
+    fn is_indexed(&self, field: &str) -> bool {
+        self.fields.iter().any(|f| f == field)
+    }
+}
+
+fn tag_prefix(field: &str, tag: u8) -> Vec<u8> {
+    let mut key = Vec::with_capacity(field.len() + 10);
+    key.extend_from_slice(field.as_bytes());
+    key.push(0);
+    key.push(tag);
+    key
+}
+
+/// The key for a scalar value, and whether it holds the whole value.
+fn encode_key(field: &str, value: &Value) -> Option<(Vec<u8>, bool)> {
+    match value {
+        Value::Bool(b) => {
+            let mut key = tag_prefix(field, TAG_BOOL);
+            key.push(*b as u8);
+            Some((key, true))
+        }
+        Value::Number(n) => n.as_f64().map(|n| (number_key(field, n), true)),
+        Value::String(s) => Some(string_key(field, s)),
+        _ => None,
+    }
+}
+
+fn number_key(field: &str, n: f64) -> Vec<u8> {
+    // -0.0 and 0.0 compare equal, so they share a key
+    let n = if n == 0.0 { 0.0 } else { n };
+    let bits = n.to_bits();
+    let ordered = if bits >> 63 == 1 {
+        !bits
+    } else {
+        bits | 1 << 63
+    };
+    let mut key = tag_prefix(field, TAG_NUMBER);
+    key.extend_from_slice(&ordered.to_be_bytes());
+    key
+}
+
+fn string_key(field: &str, s: &str) -> (Vec<u8>, bool) {
+    let mut key = tag_prefix(field, TAG_STRING);
+    let room = MAX_KEY_SIZE - key.len();
+    let exact = s.len() <= room;
+    key.extend_from_slice(&s.as_bytes()[..s.len().min(room)]);
+    (key, exact)
+}
+
+fn exact_range(key: Vec<u8>) -> KeyRange {
+    KeyRange {
+        start: Bound::Included(key.clone()),
+        end: Bound::Included(key),
+    }
+}
+
+/// Every key starting with `prefix`.
+fn prefix_range(prefix: Vec<u8>) -> KeyRange {
+    // The first key past the prefix: drop trailing 0xff bytes and bump the last
+    let mut end = prefix.clone();
+    while end.last() == Some(&0xff) {
+        end.pop();
+    }
+    let end = match end.last_mut() {
+        Some(last) => {
+            *last += 1;
+            Bound::Excluded(end)
+        }
+        None => Bound::Unbounded,
+    };
+    KeyRange {
+        start: Bound::Included(prefix),
+        end,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    /// An in-memory stand-in for the LMDB index: key -> rows.
+    struct Postings {
+        index: FieldIndex,
+        rows: Vec<Value>,
+        keys: BTreeMap<Vec<u8>, Vec<usize>>,
+    }
+
+    impl Postings {
+        fn new(fields: &[&str], rows: Vec<Value>) -> Self {
+            let index = FieldIndex::new(fields.iter().map(|f| f.to_string()).collect()).unwrap();
+            let mut keys: BTreeMap<Vec<u8>, Vec<usize>> = BTreeMap::new();
+            for (row, metadata) in rows.iter().enumerate() {
+                for key in index.keys(metadata) {
+                    keys.entry(key).or_default().push(row);
+                }
+            }
+            Self { index, rows, keys }
+        }
+
+        fn plan(&self, filter: Value) -> Option<(Vec<usize>, bool)> {
+            let filter = Filter::from_value(filter).unwrap();
+            let mut lookup = |range: &KeyRange| {
+                let mut rows = RowSet::default();
+                for posted in self
+                    .keys
+                    .range((range.start.clone(), range.end.clone()))
+                    .flat_map(|(_, rows)| rows)
+                {
+                    rows.insert(*posted);
+                }
+                Ok(rows)
+            };
+            self.index
+                .plan(&filter, &mut lookup)
+                .unwrap()
+                .map(|plan| (plan.rows.iter().collect(), plan.exact))
+        }
+
+        fn matching(&self, filter: Value) -> Vec<usize> {
+            let filter = Filter::from_value(filter).unwrap();
+            (0..self.rows.len())
+                .filter(|&row| filter.matches(&self.rows[row]))
+                .collect()
+        }
+    }
+
+    fn numbers() -> Postings {
+        let rows = [-1e9, -2.5, -1.0, -0.5, -0.0, 0.0, 0.25, 1.0, 1.5, 1e9]
+            .into_iter()
+            .map(|x| json!({ "x": x }))
+            .chain([json!({"x": "1"}), json!({"x": true}), json!({})])
+            .collect();
+        Postings::new(&["x"], rows)
+    }
+
+    #[test]
+    fn number_keys_sort_in_numeric_order() {
+        let values = [
+            f64::NEG_INFINITY,
+            -1e300,
+            -2.5,
+            -1.0,
+            -0.5,
+            -f64::MIN_POSITIVE,
+            0.0,
+            f64::MIN_POSITIVE,
+            0.25,
+            1.0,
+            1.5,
+            1e300,
+            f64::INFINITY,
+        ];
+        for pair in values.windows(2) {
+            assert!(
+                number_key("x", pair[0]) < number_key("x", pair[1]),
+                "{} sorts before {}",
+                pair[0],
+                pair[1]
+            );
+        }
+        assert_eq!(number_key("x", -0.0), number_key("x", 0.0));
+        // Numbers sort between booleans and strings of the same field
+        assert!(tag_prefix("x", TAG_BOOL) < number_key("x", f64::NEG_INFINITY));
+        assert!(number_key("x", f64::INFINITY) < tag_prefix("x", TAG_STRING));
+    }
+
+    #[test]
+    fn ranges_find_exactly_the_matching_numbers() {
+        let postings = numbers();
+        for range in [
+            json!({"gt": -1.0, "lt": 1.0}),
+            json!({"gte": -1.0, "lte": 1.0}),
+            json!({"gte": 0}),
+            json!({"gt": 0}),
+            json!({"lte": -0.0}),
+            json!({"lt": 0}),
+            json!({"gt": -3, "gte": -1, "lt": 1.5, "lte": 2}),
+            json!({"gt": 0.1, "lt": 0.2}),
+            json!({"gte": 1.5, "lte": 1.5}),
+            json!({}),
+        ] {
+            let mut filter = json!({"field": "x"});
+            filter
+                .as_object_mut()
+                .unwrap()
+                .extend(range.as_object().unwrap().clone());
+            let filter = json!({ "range": filter });
+            assert_eq!(
+                postings.plan(filter.clone()),
+                Some((postings.matching(filter.clone()), true)),
+                "{}",
+                filter
+            );
+        }
+    }
+
+    #[test]
+    fn eq_and_prefix_lookups() {
+        let postings = Postings::new(
+            &["path", "ok"],
+            vec![
+                json!({"path": "src/main.rs", "ok": true}),
+                json!({"path": "src/lib.rs", "ok": false}),
+                json!({"path": "srcs", "ok": 1}),
+                json!({"path": "docs/index.md"}),
+            ],
+        );
+        assert_eq!(
+            postings.plan(json!({"eq": {"field": "ok", "value": true}})),
+            Some((vec![0], true))
+        );
+        assert_eq!(
+            postings.plan(json!({"eq": {"field": "ok", "value": 1.0}})),
+            Some((vec![2], true))
+        );
+        assert_eq!(
+            postings.plan(json!({"prefix": {"field": "path", "value": "src/"}})),
+            Some((vec![0, 1], true))
+        );
+        assert_eq!(
+            postings.plan(json!({"prefix": {"field": "path", "value": ""}})),
+            Some((vec![0, 1, 2, 3], true))
+        );
+        // Globs only narrow to their literal prefix
+        assert_eq!(
+            postings.plan(json!({"glob": {"field": "path", "pattern": "src/*.rs"}})),
+            Some((vec![0, 1], false))
+        );
+    }
+
+    #[test]
+    fn long_strings_only_narrow() {
+        let long = "a".repeat(600);
+        let postings = Postings::new(
+            &["s"],
+            vec![json!({ "s": long }), json!({ "s": format!("{}b", long) })],
+        );
+        assert_eq!(
+            postings.plan(json!({"eq": {"field": "s", "value": long}})),
+            Some((vec![0, 1], false))
+        );
+    }
+
+    #[test]
+    fn prefix_ranges_bump_past_trailing_ff() {
+        let range = prefix_range(vec![1, 0xff, 0xff]);
+        assert_eq!(range.start, Bound::Included(vec![1, 0xff, 0xff]));
+        assert_eq!(range.end, Bound::Excluded(vec![2]));
+        assert_eq!(prefix_range(vec![0xff]).end, Bound::Unbounded);
+        assert_eq!(prefix_range(vec![]).end, Bound::Unbounded);
+    }
+
+    #[test]
+    fn and_with_an_unindexed_field_is_inexact() {
+        let postings = numbers();
+        let filter = json!({"and": [
+            {"range": {"field": "x", "gte": 0}},
+            {"eq": {"field": "y", "value": 1}},
+        ]});
+        // Narrowed by the indexed conjunct only, so the rows must be rechecked
+        assert_eq!(
+            postings.plan(filter.clone()),
+            Some((vec![4, 5, 6, 7, 8, 9], false))
+        );
+        assert!(postings.matching(filter).is_empty());
+
+        let filter = json!({"and": [
+            {"range": {"field": "x", "gte": 0}},
+            {"range": {"field": "x", "lt": 1}},
+        ]});
+        assert_eq!(
+            postings.plan(filter.clone()),
+            Some((postings.matching(filter), true))
+        );
+
+        assert_eq!(
+            postings.plan(json!({"and": [{"eq": {"field": "y", "value": 1}}]})),
+            None
+        );
+        assert_eq!(
+            postings.plan(json!({"and": [
+                {"range": {"field": "x", "gte": 0}},
+                {"not": {"eq": {"field": "x", "value": 1}}},
+            ]})),
+            Some((vec![4, 5, 6, 7, 8, 9], false))
+        );
+    }
+
+    #[test]
+    fn or_needs_every_branch_indexed() {
+        let postings = numbers();
+        let filter = json!({"or": [
+            {"range": {"field": "x", "lt": -1}},
+            {"eq": {"field": "x", "value": "1"}},
+        ]});
+        assert_eq!(
+            postings.plan(filter.clone()),
+            Some((postings.matching(filter), true))
+        );
+        assert_eq!(
+            postings.plan(json!({"or": [
+                {"range": {"field": "x", "lt": -1}},
+                {"eq": {"field": "y", "value": 1}},
+            ]})),
+            None
+        );
+    }
+}
@@ -0,0 +1,368 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::vector_entry::metadata_field;
+
+/// A predicate over an entry's metadata, carried by a query as its `filter`
+/// field. Fields are dotted paths into the metadata object, and an entry
+/// without the field, or with a value of another type, does not match. Each
+/// variant is written as a one-key JSON object, for example
+/// `{"and": [{"prefix": {"field": "file_path", "value": "src/"}},
+/// {"range": {"field": "start_line", "lt": 100}}]}`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    /// Text, number or boolean equality
+    Eq {
+        field: String,
+        value: Value,
+    },
+    /// Text starting with `value`
+    Prefix {
+        field: String,
+        value: String,
+    },
+    /// Text matching a glob: `*` and `?` stay within one path segment, `**`
+    /// also crosses `/`
+    Glob {
+        field: String,
+        pattern: String,
+    },
+    /// Number within the given bounds; missing bounds are open
+    Range {
+        field: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        gt: Option<f64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        gte: Option<f64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        lt: Option<f64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        lte: Option<f64>,
+    },
+}
+
+impl Filter {
+    /// Parse a filter and check that each operand has a type its predicate
+    /// can compare, so a malformed query fails instead of matching nothing.
+    pub fn from_value(value: Value) -> Result<Self> {
+        let filter: Filter =
+            serde_json::from_value(value).map_err(|e| anyhow::anyhow!("Invalid filter: {}", e))?;
+        filter.validate()?;
+        Ok(filter)
+    }
+
+    fn validate(&self) -> Result<()> {
+        match self {
+            Filter::And(filters) | Filter::Or(filters) => {
+                filters.iter().try_for_each(Filter::validate)
+            }
+            Filter::Not(filter) => filter.validate(),
+            Filter::Eq { field, value } => match value {
+                Value::String(_) | Value::Number(_) | Value::Bool(_) => Ok(()),
+                _ => anyhow::bail!(
+                    "Filter on '{}' must compare a string, a number or a boolean",
+                    field
+                ),
+            },
+            Filter::Prefix { .. } | Filter::Glob { .. } | Filter::Range { .. } => Ok(()),
+        }
+    }
+
+    pub fn matches(&self, metadata: &Value) -> bool {
+        match self {
+            Filter::And(filters) => filters.iter().all(|f| f.matches(metadata)),
+            Filter::Or(filters) => filters.iter().any(|f| f.matches(metadata)),
+            Filter::Not(filter) => !filter.matches(metadata),
+            Filter::Eq { field, value } => match (metadata_field(metadata, field), value) {
+                (Some(Value::Number(number)), Value::Number(expected)) => {
+                    number.as_f64() == expected.as_f64()
+                }
+                (Some(found), expected) => found == expected,
+                (None, _) => false,
+            },
+            Filter::Prefix { field, value } => {
+                matches!(metadata_field(metadata, field), Some(Value::String(text)) if text.starts_with(value.as_str()))
+            }
+            Filter::Glob { field, pattern } => {
+                matches!(metadata_field(metadata, field), Some(Value::String(text)) if glob_match(pattern.as_bytes(), text.as_bytes()))
+            }
+            Filter::Range {
+                field,
+                gt,
+                gte,
+                lt,
+                lte,
+            } => match metadata_field(metadata, field).and_then(Value::as_f64) {
+                Some(n) => {
+                    gt.is_none_or(|b| n > b)
+                        && gte.is_none_or(|b| n >= b)
+                        && lt.is_none_or(|b| n < b)
+                        && lte.is_none_or(|b| n <= b)
+                }
+                None => false,
+            },
+        }
+    }
+}
+
+/// Glob match over bytes. `*` and `?` never match `/`; `**` matches anything,
+/// and `**/` also matches nothing, so `src/**/*.rs` covers `src/main.rs`.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', b'/', rest @ ..] => {
+            glob_match(rest, text)
+                || (0..text.len())
+                    .filter(|&i| text[i] == b'/')
+                    .any(|i| glob_match(rest, &text[i + 1..]))
+        }
+        [b'*', b'*', rest @ ..] => (0..=text.len()).any(|i| glob_match(rest, &text[i..])),
+        [b'*', rest @ ..] => {
+            let segment = text.iter().position(|&c| c == b'/').unwrap_or(text.len());
+            (0..=segment).any(|i| glob_match(rest, &text[i..]))
+        }
+        [b'?', rest @ ..] => matches!(text, [c, tail @ ..] if *c != b'/' && glob_match(rest, tail)),
+        [c, rest @ ..] => matches!(text, [t, tail @ ..] if t == c && glob_match(rest, tail)),
+    }
+}
+
+/// A set of arena rows, one bit per row.
+#[derive(Default)]
+pub struct RowSet {
+    bits: Vec<u64>,
+    len: usize,
+}
+
+impl RowSet {
+    pub fn insert(&mut self, row: usize) {
+        let (word, bit) = (row / 64, row % 64);
+        if word >= self.bits.len() {
+            self.bits.resize(word + 1, 0);
+        }
+        if self.bits[word] & (1 << bit) == 0 {
+            self.bits[word] |= 1 << bit;
+            self.len += 1;
+        }
+    }
+
+    pub fn contains(&self, row: usize) -> bool {
+        self.bits
+            .get(row / 64)
+            .is_some_and(|word| word & (1 << (row % 64)) != 0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.bits.iter().enumerate().flat_map(|(word, &bits)| {
+            (0..64)
+                .filter(move |bit| bits & (1 << bit) != 0)
+                .map(move |bit| word * 64 + bit)
+        })
+    }
+
+    pub fn union_with(&mut self, other: &RowSet) {
+        if other.bits.len() > self.bits.len() {
+            self.bits.resize(other.bits.len(), 0);
+        }
+        for (word, other) in self.bits.iter_mut().zip(&other.bits) {
+            *word |= other;
+        }
+        self.recount();
+    }
+
+    pub fn intersect_with(&mut self, other: &RowSet) {
+        self.bits.truncate(other.bits.len());
+        for (word, other) in self.bits.iter_mut().zip(&other.bits) {
+            *word &= other;
+        }
+        self.recount();
+    }
+
+    fn recount(&mut self) {
+        self.len = self
+            .bits
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn filter(value: Value) -> Filter {
+        Filter::from_value(value).unwrap()
+    }
+
+    fn row_set(rows: &[usize]) -> RowSet {
+        let mut set = RowSet::default();
+        for &row in rows {
+            set.insert(row);
+        }
+        set
+    }
+
+    #[test]
+    fn glob_stars_stay_within_a_segment() {
+        for (pattern, text, expected) in [
+            ("*", "", true),
+            ("*", "main.rs", true),
+            ("*", "src/main.rs", false),
+            ("*.rs", "main.rs", true),
+            ("*.rs", "main.rsx", false),
+            ("src/*.rs", "src/main.rs", true),
+            ("src/*.rs", "src/bin/main.rs", false),
+            ("src/*", "src/", true),
+            ("file?.rs", "file1.rs", true),
+            ("file?.rs", "file12.rs", false),
+            ("file?.rs", "file.rs", false),
+            ("a?b", "a/b", false),
+            ("", "", true),
+            ("", "a", false),
+            ("main.rs", "main.rs", true),
+            ("main.rs", "main.r", false),
+        ] {
+            assert_eq!(
+                glob_match(pattern.as_bytes(), text.as_bytes()),
+                expected,
+                "{} against {}",
+                pattern,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn glob_double_star_crosses_segments() {
+        for (pattern, text, expected) in [
+            ("src/**/*.rs", "src/main.rs", true),
+            ("src/**/*.rs", "src/bin/main.rs", true),
+            ("src/**/*.rs", "src/a/b/c/lib.rs", true),
+            ("src/**/*.rs", "src/main.py", false),
+            ("src/**/*.rs", "srcx/main.rs", false),
+            ("src/**/*.rs", "lib/src/main.rs", false),
+            ("**/*.rs", "main.rs", true),
+            ("**/*.rs", "a/b/main.rs", true),
+            ("**/*.rs", "a/b/main.rs/x", false),
+            ("src/**", "src/a/b", true),
+            ("src/**", "src/", true),
+            ("**", "", true),
+            ("a**b", "a/x/b", true),
+            ("docs/**/index.md", "docs/index.md", true),
+            ("docs/**/index.md", "docs/api/v1/index.md", true),
+            ("docs/**/index.md", "docs/api/xindex.md", false),
+        ] {
+            assert_eq!(
+                glob_match(pattern.as_bytes(), text.as_bytes()),
+                expected,
+                "{} against {}",
+                pattern,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn ranges_handle_negative_zero_and_fractional_bounds() {
+        let between = filter(json!({"range": {"field": "x", "gt": -1.5, "lte": 0}}));
+        for (x, expected) in [
+            (json!(-2), false),
+            (json!(-1.5), false),
+            (json!(-1.25), true),
+            (json!(-0.0), true),
+            (json!(0), true),
+            (json!(0.001), false),
+        ] {
+            assert_eq!(between.matches(&json!({ "x": x })), expected, "x = {}", x);
+        }
+
+        let open = filter(json!({"range": {"field": "x", "gte": 0.5}}));
+        assert!(open.matches(&json!({"x": 0.5})));
+        assert!(open.matches(&json!({"x": 1e300})));
+        assert!(!open.matches(&json!({"x": 0.4999})));
+        assert!(!open.matches(&json!({"x": "1"})));
+        assert!(!open.matches(&json!({"y": 1})));
+    }
+
+    #[test]
+    fn filters_serialize_as_written() {
+        for value in [
+            json!({"range": {"field": "x", "gt": -1.5}}),
+            json!({"range": {"field": "x", "gte": 0.0, "lt": 10.0}}),
+            json!({"range": {"field": "x"}}),
+            json!({"and": [
+                {"not": {"eq": {"field": "a", "value": "b"}}},
+                {"glob": {"field": "path", "pattern": "src/**/*.rs"}},
+                {"range": {"field": "line", "lte": 100.0}},
+            ]}),
+        ] {
+            assert_eq!(serde_json::to_value(filter(value.clone())).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn eq_compares_numbers_by_value_and_paths_into_arrays() {
+        let metadata = json!({"n": 1, "tags": ["a", "b"], "author": {"name": "ana"}, "ok": true});
+        assert!(filter(json!({"eq": {"field": "n", "value": 1.0}})).matches(&metadata));
+        assert!(!filter(json!({"eq": {"field": "n", "value": "1"}})).matches(&metadata));
+        assert!(filter(json!({"eq": {"field": "tags.1", "value": "b"}})).matches(&metadata));
+        assert!(!filter(json!({"eq": {"field": "tags.2", "value": "b"}})).matches(&metadata));
+        assert!(filter(json!({"eq": {"field": "author.name", "value": "ana"}})).matches(&metadata));
+        assert!(filter(json!({"eq": {"field": "ok", "value": true}})).matches(&metadata));
+        assert!(Filter::from_value(json!({"eq": {"field": "n", "value": [1]}})).is_err());
+    }
+
+    #[test]
+    fn combinators_and_missing_fields() {
+        let metadata = json!({"path": "src/main.rs", "line": 10});
+        let both = filter(json!({"and": [
+            {"prefix": {"field": "path", "value": "src/"}},
+            {"range": {"field": "line", "lt": 20}},
+        ]}));
+        assert!(both.matches(&metadata));
+        let either = filter(json!({"or": [
+            {"eq": {"field": "missing", "value": 1}},
+            {"glob": {"field": "path", "pattern": "**/*.rs"}},
+        ]}));
+        assert!(either.matches(&metadata));
+        // A missing field fails its predicate, so negating it matches
+        assert!(
+            filter(json!({"not": {"eq": {"field": "missing", "value": 1}}})).matches(&metadata)
+        );
+        assert!(filter(json!({"and": []})).matches(&metadata));
+        assert!(!filter(json!({"or": []})).matches(&metadata));
+    }
+
+    #[test]
+    fn row_sets_count_and_combine_across_words() {
+        let mut set = row_set(&[0, 63, 64, 200, 63]);
+        assert_eq!(set.len(), 4);
+        assert!(set.contains(63) && set.contains(200));
+        assert!(!set.contains(1) && !set.contains(10_000));
+        assert_eq!(set.iter().collect::<Vec<_>>(), [0, 63, 64, 200]);
+
+        set.union_with(&row_set(&[1, 500]));
+        assert_eq!(set.len(), 6);
+        assert!(set.contains(500));
+
+        // The other set is shorter, so the rows past its last word go
+        set.intersect_with(&row_set(&[0, 64, 65]));
+        assert_eq!(set.iter().collect::<Vec<_>>(), [0, 64]);
+        assert_eq!(set.len(), 2);
+
+        set.intersect_with(&RowSet::default());
+        assert_eq!(set.len(), 0);
+        assert_eq!(set.iter().count(), 0);
+    }
+}
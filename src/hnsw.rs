@@ -0,0 +1,415 @@
+use anyhow::Result;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+
+use crate::arena::VectorArena;
+use crate::config::{Number, State};
+use crate::filter::RowSet;
+use crate::top_k::ScoredRow;
+use crate::vector_ops::Metric;
+
+const SEED: u64 = 42;
+const MAX_LEVEL: usize = 16;
+
+pub const DEFAULT_M: usize = 16;
+pub const DEFAULT_EF_CONSTRUCTION: usize = 200;
+pub const DEFAULT_EF_SEARCH: usize = 64;
+/// How many times more nodes a filtered search may visit than it would need
+/// if the filter's rows were spread evenly through the graph.
+const FILTERED_VISIT_SLACK: usize = 4;
+
+/// Build-time parameters, fixed when the graph is created.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct HnswParams {
+    /// Links per node on the upper layers; layer 0 allows twice as many
+    pub m: usize,
+    /// Beam width while inserting
+    pub ef_construction: usize,
+}
+
+impl HnswParams {
+    pub fn from_state(state: &State) -> Self {
+        HnswParams {
+            m: state.hnsw_m,
+            ef_construction: state.hnsw_ef_construction,
+        }
+    }
+}
+
+/// Persisted alongside the nodes; tracks where every search starts.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HnswHeader {
+    pub params: HnswParams,
+    pub entry_point: Option<usize>,
+    pub max_level: usize,
+}
+
+/// Adjacency lists of one row, from layer 0 up to the node's level.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HnswNode {
+    pub neighbors: Vec<Vec<usize>>,
+}
+
+impl HnswNode {
+    fn level(&self) -> usize {
+        self.neighbors.len() - 1
+    }
+}
+
+/// Where the graph's nodes live. Rows without a node (deleted, or added while
+/// no graph existed) are skipped during traversal.
+pub trait GraphStore {
+    fn load(&self, row: usize) -> Result<Option<HnswNode>>;
+    fn contains(&self, row: usize) -> Result<bool>;
+    /// The node with the highest level, used to find a new entry point.
+    fn top_node(&self) -> Result<Option<(usize, usize)>>;
+    fn node_count(&self) -> Result<usize>;
+}
+
+pub trait GraphStoreMut: GraphStore {
+    fn save(&mut self, row: usize, node: &HnswNode) -> Result<()>;
+    fn remove(&mut self, row: usize) -> Result<()>;
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Scored(Number, usize);
+
+impl Eq for Scored {}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0
+            .total_cmp(&other.0)
+            .then_with(|| other.1.cmp(&self.1))
+    }
+}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Scores rows of the arena against one fixed vector.
+struct Scorer<'a> {
+    arena: &'a VectorArena,
+    metric: Metric,
+    query: &'a [Number],
+}
+
+impl Scorer<'_> {
+    fn score(&self, row: usize) -> Result<Number> {
+        let vector = self.arena.row(row)?;
+        Ok(self
+            .metric
+            .score_stored(self.query, &vector)
+            .unwrap_or(Number::NEG_INFINITY))
+    }
+}
+
+impl HnswHeader {
+    pub fn new(params: HnswParams) -> Self {
+        HnswHeader {
+            params,
+            entry_point: None,
+            max_level: 0,
+        }
+    }
+
+    fn max_links(&self, layer: usize) -> usize {
+        if layer == 0 {
+            self.params.m * 2
+        } else {
+            self.params.m
+        }
+    }
+
+    fn random_level(&self, row: usize) -> usize {
+        let mut rng = StdRng::seed_from_u64(SEED ^ row as u64);
+        let ml = 1.0 / (self.params.m.max(2) as f64).ln();
+        let uniform: f64 = rng.gen_range(f64::EPSILON..1.0);
+        ((-uniform.ln() * ml).floor() as usize).min(MAX_LEVEL)
+    }
+
+    /// Link a row that is already written to the arena into the graph.
+    pub fn insert<S: GraphStoreMut>(
+        &mut self,
+        store: &mut S,
+        arena: &VectorArena,
+        metric: Metric,
+        row: usize,
+    ) -> Result<()> {
+        let level = self.random_level(row);
+        let mut node = HnswNode {
+            neighbors: vec![Vec::new(); level + 1],
+        };
+
+        // A stale entry point is replaced by the highest remaining node; only an
+        // empty graph makes the new row its sole node
+        if let Some(entry_point) = self.entry_point {
+            if !store.contains(entry_point)? {
+                self.entry_point = None;
+                self.max_level = 0;
+                if let Some((top, top_level)) = store.top_node()? {
+                    self.entry_point = Some(top);
+                    self.max_level = top_level;
+                }
+            }
+        }
+        let entry_point = match self.entry_point {
+            Some(entry_point) => entry_point,
+            None => {
+                store.save(row, &node)?;
+                self.entry_point = Some(row);
+                self.max_level = level;
+                return Ok(());
+            }
+        };
+
+        let query = arena.decode(row)?;
+        let scorer = Scorer {
+            arena,
+            metric,
+            query: &query,
+        };
+
+        let mut entry = vec![(entry_point, scorer.score(entry_point)?)];
+        for layer in (level + 1..=self.max_level).rev() {
+            entry = search_layer(store, &scorer, &entry, 1, layer, None, None)?;
+        }
+
+        for layer in (0..=level.min(self.max_level)).rev() {
+            let found = search_layer(
+                store,
+                &scorer,
+                &entry,
+                self.params.ef_construction,
+                layer,
+                None,
+                None,
+            )?;
+            let max_links = self.max_links(layer);
+            node.neighbors[layer] = found
+                .iter()
+                .filter(|&&(neighbor, _)| neighbor != row)
+                .take(max_links)
+                .map(|&(neighbor, _)| neighbor)
+                .collect();
+
+            for &neighbor in &node.neighbors[layer] {
+                if let Some(mut other) = store.load(neighbor)? {
+                    if other.level() < layer {
+                        continue;
+                    }
+                    other.neighbors[layer].push(row);
+                    if other.neighbors[layer].len() > max_links {
+                        shrink_links(
+                            &mut other.neighbors[layer],
+                            neighbor,
+                            arena,
+                            metric,
+                            max_links,
+                        )?;
+                    }
+                    store.save(neighbor, &other)?;
+                }
+            }
+            entry = found;
+        }
+
+        store.save(row, &node)?;
+        if level > self.max_level {
+            self.entry_point = Some(row);
+            self.max_level = level;
+        }
+        Ok(())
+    }
+
+    /// Unlink a row from the graph, choosing a new entry point if needed. The
+    /// row's vector must still be in the arena. Each former neighbour is offered
+    /// the row's other neighbours in its place, so the graph stays connected.
+    pub fn remove<S: GraphStoreMut>(
+        &mut self,
+        store: &mut S,
+        arena: &VectorArena,
+        metric: Metric,
+        row: usize,
+    ) -> Result<()> {
+        let node = match store.load(row)? {
+            Some(node) => node,
+            None => return Ok(()),
+        };
+
+        for (layer, neighbors) in node.neighbors.iter().enumerate() {
+            let max_links = self.max_links(layer);
+            for &neighbor in neighbors {
+                if let Some(mut other) = store.load(neighbor)? {
+                    if let Some(links) = other.neighbors.get_mut(layer) {
+                        links.retain(|&linked| linked != row);
+                        for &replacement in neighbors {
+                            if replacement != neighbor && !links.contains(&replacement) {
+                                links.push(replacement);
+                            }
+                        }
+                        if links.len() > max_links {
+                            shrink_links(links, neighbor, arena, metric, max_links)?;
+                        }
+                        store.save(neighbor, &other)?;
+                    }
+                }
+            }
+        }
+        store.remove(row)?;
+
+        if self.entry_point == Some(row) {
+            match store.top_node()? {
+                Some((top, level)) => {
+                    self.entry_point = Some(top);
+                    self.max_level = level;
+                }
+                None => {
+                    self.entry_point = None;
+                    self.max_level = 0;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Approximate nearest rows to `query`, best first. With a filter, only
+    /// rows in it are collected on the bottom layer, but the search still
+    /// walks through the rest, so a selective filter widens the search. The
+    /// walk stops once it has visited `FILTERED_VISIT_SLACK` times the nodes
+    /// an evenly spread filter would need, so it may return fewer than `k`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn search<S: GraphStore>(
+        &self,
+        store: &S,
+        arena: &VectorArena,
+        metric: Metric,
+        query: &[Number],
+        k: usize,
+        ef_search: usize,
+        filter: Option<&RowSet>,
+    ) -> Result<Vec<ScoredRow>> {
+        let entry_point = match self.entry_point {
+            Some(entry_point) => entry_point,
+            None => return Ok(Vec::new()),
+        };
+
+        let scorer = Scorer {
+            arena,
+            metric,
+            query,
+        };
+        let mut entry = vec![(entry_point, scorer.score(entry_point)?)];
+        for layer in (1..=self.max_level).rev() {
+            entry = search_layer(store, &scorer, &entry, 1, layer, None, None)?;
+        }
+
+        let ef = ef_search.max(k);
+        let max_visits = match filter {
+            Some(filter) => Some(
+                (ef.saturating_mul(store.node_count()?) / filter.len().max(1))
+                    .saturating_mul(FILTERED_VISIT_SLACK)
+                    .max(ef),
+            ),
+            None => None,
+        };
+        let mut found = search_layer(store, &scorer, &entry, ef, 0, filter, max_visits)?;
+        found.truncate(k);
+        Ok(found)
+    }
+}
+
+/// Beam search over one layer, returning up to `ef` rows best first. Rows
+/// outside `filter`, if given, are explored but never returned. The search
+/// ends early once `max_visits` nodes, if given, have been seen.
+fn search_layer<S: GraphStore>(
+    store: &S,
+    scorer: &Scorer,
+    entry: &[ScoredRow],
+    ef: usize,
+    layer: usize,
+    filter: Option<&RowSet>,
+    max_visits: Option<usize>,
+) -> Result<Vec<ScoredRow>> {
+    let accepted = |row: usize| filter.is_none_or(|filter| filter.contains(row));
+    let mut visited: HashSet<usize> = entry.iter().map(|&(row, _)| row).collect();
+    let mut candidates: BinaryHeap<Scored> = entry.iter().map(|&(r, s)| Scored(s, r)).collect();
+    let mut results: BinaryHeap<Reverse<Scored>> = entry
+        .iter()
+        .filter(|&&(r, _)| accepted(r))
+        .map(|&(r, s)| Reverse(Scored(s, r)))
+        .collect();
+
+    while let Some(Scored(score, row)) = candidates.pop() {
+        if max_visits.is_some_and(|max_visits| visited.len() >= max_visits) {
+            break;
+        }
+        if let Some(Reverse(Scored(worst, _))) = results.peek() {
+            if score < *worst && results.len() >= ef {
+                break;
+            }
+        }
+
+        let node = match store.load(row)? {
+            Some(node) => node,
+            None => continue,
+        };
+        let links = match node.neighbors.get(layer) {
+            Some(links) => links,
+            None => continue,
+        };
+
+        for &neighbor in links {
+            // Links to removed rows can linger on nodes the row did not link back to
+            if !visited.insert(neighbor) || !store.contains(neighbor)? {
+                continue;
+            }
+            let neighbor_score = scorer.score(neighbor)?;
+            let worst = results.peek().map(|Reverse(Scored(s, _))| *s);
+            if results.len() < ef || worst.is_some_and(|worst| neighbor_score > worst) {
+                candidates.push(Scored(neighbor_score, neighbor));
+                if accepted(neighbor) {
+                    results.push(Reverse(Scored(neighbor_score, neighbor)));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(results
+        .into_sorted_vec()
+        .into_iter()
+        .map(|Reverse(Scored(score, row))| (row, score))
+        .collect())
+}
+
+/// Keep only the `max_links` neighbours closest to `row`.
+fn shrink_links(
+    links: &mut Vec<usize>,
+    row: usize,
+    arena: &VectorArena,
+    metric: Metric,
+    max_links: usize,
+) -> Result<()> {
+    let vector = arena.decode(row)?;
+    let scorer = Scorer {
+        arena,
+        metric,
+        query: &vector,
+    };
+    let mut scored = links
+        .iter()
+        .map(|&link| Ok(Scored(scorer.score(link)?, link)))
+        .collect::<Result<Vec<_>>>()?;
+    scored.sort_by(|a, b| b.cmp(a));
+    scored.truncate(max_links);
+    *links = scored.into_iter().map(|Scored(_, link)| link).collect();
+    Ok(())
+}
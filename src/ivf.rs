@@ -0,0 +1,126 @@
+use anyhow::Result;
+use rand::prelude::*;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Number;
+use crate::vector_ops::{compute_l2_distance_simd, normalize_vector};
+
+const SEED: u64 = 42;
+const KMEANS_ITERATIONS: usize = 25;
+
+pub const DEFAULT_NPROBE: usize = 8;
+
+/// Inverted-file index. Only the k-means centroids are held here; the posting
+/// lists live in LMDB as `list id -> row` pairs, like the ANN buckets.
+///
+/// Vectors are assigned to, and queries probe, the centroids at the smallest
+/// Euclidean distance. That matches the `l2` metric, and on normalized
+/// vectors it is also the order of cosine similarity. For `dot` and `l1` on
+/// vectors that are not normalized it is only an approximation: the lists
+/// nearest the query in L2 need not hold the best-scoring rows, so recall at
+/// a given `nprobe` is lower than for `cosine` or `l2`.
+#[derive(Serialize, Deserialize)]
+pub struct IvfIndex {
+    centroids: Vec<Vec<Number>>,
+}
+
+impl IvfIndex {
+    /// Run k-means over the given vectors. `nlist` is capped at the number of
+    /// vectors; with `spherical` set the centroids are kept normalized.
+    pub fn train(data: &[&[Number]], nlist: usize, spherical: bool) -> Result<Self> {
+        if data.is_empty() {
+            anyhow::bail!("No stored vectors to train the IVF index on");
+        }
+        Ok(IvfIndex {
+            centroids: kmeans(data, nlist, spherical),
+        })
+    }
+
+    pub fn nlist(&self) -> usize {
+        self.centroids.len()
+    }
+
+    pub fn centroid(&self, list: u32) -> &[Number] {
+        &self.centroids[list as usize]
+    }
+
+    /// The posting list a vector belongs to.
+    pub fn assign(&self, vector: &[Number]) -> u32 {
+        nearest(&self.centroids, vector) as u32
+    }
+
+    /// The `nprobe` lists whose centroids are closest to the query.
+    pub fn probe_lists(&self, query: &[Number], nprobe: usize) -> Vec<u32> {
+        let mut lists: Vec<(Number, u32)> = self
+            .centroids
+            .iter()
+            .enumerate()
+            .map(|(list, centroid)| (distance(centroid, query), list as u32))
+            .collect();
+        lists.sort_by(|a, b| a.0.total_cmp(&b.0));
+        lists.truncate(nprobe.max(1));
+        lists.into_iter().map(|(_, list)| list).collect()
+    }
+}
+
+/// Lloyd's k-means seeded from a random sample of the data. `k` is capped at
+/// the number of points; `data` must not be empty.
+pub fn kmeans(data: &[&[Number]], k: usize, spherical: bool) -> Vec<Vec<Number>> {
+    let k = k.clamp(1, data.len());
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let mut centroids: Vec<Vec<Number>> = data
+        .choose_multiple(&mut rng, k)
+        .map(|vector| vector.to_vec())
+        .collect();
+
+    let mut assignments = vec![usize::MAX; data.len()];
+    for _ in 0..KMEANS_ITERATIONS {
+        let next: Vec<usize> = data
+            .par_iter()
+            .map(|vector| nearest(&centroids, vector))
+            .collect();
+        let changed = next.iter().zip(&assignments).any(|(a, b)| a != b);
+        assignments = next;
+        if !changed {
+            break;
+        }
+
+        let dimensions = centroids[0].len();
+        let mut sums = vec![vec![0.0 as Number; dimensions]; k];
+        let mut counts = vec![0usize; k];
+        for (vector, &list) in data.iter().zip(&assignments) {
+            counts[list] += 1;
+            for (sum, &value) in sums[list].iter_mut().zip(vector.iter()) {
+                *sum += value;
+            }
+        }
+
+        for (list, (sum, count)) in sums.into_iter().zip(counts).enumerate() {
+            if count == 0 {
+                // Reseed an empty cluster from a random point
+                centroids[list] = data[rng.gen_range(0..data.len())].to_vec();
+                continue;
+            }
+            let mut centroid: Vec<Number> = sum.into_iter().map(|v| v / count as Number).collect();
+            if spherical {
+                normalize_vector(&mut centroid);
+            }
+            centroids[list] = centroid;
+        }
+    }
+    centroids
+}
+
+fn distance(a: &[Number], b: &[Number]) -> Number {
+    compute_l2_distance_simd(a, b).unwrap_or(Number::INFINITY)
+}
+
+fn nearest(centroids: &[Vec<Number>], vector: &[Number]) -> usize {
+    centroids
+        .iter()
+        .enumerate()
+        .map(|(list, centroid)| (distance(centroid, vector), list))
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+        .map_or(0, |(_, list)| list)
+}
@@ -1,75 +1,310 @@
 mod ann;
+mod arena;
+mod binary;
+mod collection;
 mod config;
 mod database;
+mod field_index;
+mod filter;
+mod hnsw;
+mod ivf;
+mod migrate;
+mod pq;
+mod schema;
 mod search;
+mod simd;
+mod top_k;
 mod vector_entry;
 mod vector_ops;
 
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
-use std::collections::HashSet;
-use std::io::{self, BufRead};
+use clap::{Parser, Subcommand, ValueEnum};
+use rayon::prelude::*;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
 use crate::config::State;
-use crate::database::{parse_input_line, VectorDatabase};
-use crate::search::SearchEngine;
-use crate::vector_ops::normalize_vector;
+use crate::database::{
+    parse_input_line, parse_key_line, parse_query_line, AddOutcome, OnConflict, VectorDatabase,
+};
+use crate::filter::Filter;
+use crate::hnsw::HnswParams;
+use crate::pq::PqSettings;
+use crate::schema::FORMAT_VERSION;
+use crate::search::{Query, SearchEngine};
+use crate::vector_entry::VectorEntry;
+use crate::vector_ops::{Metric, Precision};
 
 #[derive(Parser)]
 #[command(name = "vekta")]
 #[command(version = "0.1")]
 #[command(about = "A simple vector database", long_about = None)]
 struct Cli {
+    /// Collection to work on; defaults to the database's default collection
+    #[arg(long, global = true)]
+    collection: Option<String>,
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    Add,
+    Add {
+        /// How to handle a label that is already stored with different content
+        #[arg(long, value_enum, default_value_t = OnConflict::Skip)]
+        on_conflict: OnConflict,
+        /// Lines parsed together and stored in one transaction; defaults to the configured batch_size
+        #[arg(long)]
+        batch_size: Option<usize>,
+    },
+    Delete,
+    /// Print the stored entries for the labels or unique_ids read from stdin
+    Get {
+        /// Include each entry's stored vector
+        #[arg(long)]
+        with_vector: bool,
+    },
     List,
     Search,
+    /// Rebuild persisted indexes from the stored vectors
+    Reindex {
+        /// Index to rebuild; defaults to every persisted index, or the ANN index if there is none
+        #[arg(long, value_enum)]
+        method: Option<IndexKind>,
+    },
+    /// Re-encode the stored vectors in another storage precision
+    Quantize {
+        #[arg(long)]
+        precision: Precision,
+    },
+    /// Train the IVF index's k-means centroids and rebuild its posting lists
+    Train {
+        /// Number of lists; defaults to the configured ivf_nlist, or the square root of the vector count
+        #[arg(long)]
+        nlist: Option<usize>,
+    },
     Config,
+    /// Report the entry count and how much of the LMDB map is in use
+    Stats,
+    /// Report the CPU's SIMD features and which similarity kernels are in use
+    CpuInfo,
+    /// Create, list, drop and inspect named collections
+    Collection {
+        #[command(subcommand)]
+        action: CollectionAction,
+    },
+    /// Rewrite every collection of the database in the current on-disk format
+    Migrate {
+        /// Write the migrated database to this new directory and leave the old one as it is
+        #[arg(long)]
+        to: Option<PathBuf>,
+    },
 }
 
-fn add_command(state: &State) -> Result<()> {
+#[derive(Subcommand)]
+enum CollectionAction {
+    /// Create a named collection with its own dimensions, metric and precision
+    Create {
+        name: String,
+        /// Defaults to the configured dimensions
+        #[arg(long)]
+        dimensions: Option<usize>,
+        /// Defaults to the configured metric
+        #[arg(long)]
+        metric: Option<Metric>,
+        /// Defaults to the configured precision
+        #[arg(long)]
+        precision: Option<Precision>,
+    },
+    /// List the collections in the database
+    List,
+    /// Delete a named collection with its entries and indexes
+    Drop { name: String },
+    /// Show a collection's settings, entry count and indexes
+    Info {
+        /// Defaults to the collection given with --collection
+        name: Option<String>,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum IndexKind {
+    /// Random-projection LSH used by the ann and hybrid methods
+    Ann,
+    /// HNSW graph used by the hnsw method
+    Hnsw,
+    /// Inverted file used by the ivf method; rebuilding retrains it
+    Ivf,
+    /// Sign-bit codes used by the binary method
+    Binary,
+    /// Secondary index over the metadata fields in `indexed_fields`, used by filters
+    Fields,
+}
+
+fn add_command(state: &State, on_conflict: OnConflict, batch_size: Option<usize>) -> Result<()> {
+    let batch_size = batch_size.unwrap_or(state.batch_size);
+    if batch_size == 0 {
+        anyhow::bail!("Batch size must be at least 1");
+    }
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines().enumerate();
+    let mut db = VectorDatabase::open(state)?;
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(state.threads)
+        .build()
+        .context("Failed to build add thread pool")?;
+
+    let start = Instant::now();
+    let (mut processed, mut added, mut replaced, mut unchanged, mut skipped) = (0, 0, 0, 0, 0);
+    loop {
+        let batch = lines
+            .by_ref()
+            .take(batch_size)
+            .map(|(i, line_result)| {
+                let line = line_result.context("Failed to read input line")?;
+                config::verbose_print(&format!("Processing line {}: {}", i, line));
+                Ok(line)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if batch.is_empty() {
+            break;
+        }
+
+        let outcomes = pool.install(|| {
+            let entries = batch
+                .par_iter()
+                .map(|line| {
+                    parse_input_line(line, state)
+                        .with_context(|| format!("Failed to parse input line: {}", line))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            db.add_batch(&entries, on_conflict)
+        })?;
+        processed += batch.len();
+
+        for outcome in outcomes {
+            match outcome {
+                AddOutcome::Added(label) => {
+                    added += 1;
+                    config::verbose_print(&format!("Added vector with label '{}'", label))
+                }
+                AddOutcome::Replaced(label) => {
+                    replaced += 1;
+                    config::verbose_print(&format!("Replaced vector with label '{}'", label))
+                }
+                AddOutcome::Unchanged(label) => {
+                    unchanged += 1;
+                    config::verbose_print(&format!("Vector with label '{}' is unchanged", label))
+                }
+                AddOutcome::Skipped(label) => {
+                    skipped += 1;
+                    eprintln!(
+                        "Warning: Label '{}' already exists in the database. Skipping.",
+                        label
+                    )
+                }
+            }
+        }
+    }
+
+    let seconds = start.elapsed().as_secs_f64();
+    println!(
+        "Processed {} lines in {:.2}s ({:.0} lines/s): {} added, {} replaced, {} unchanged, {} skipped",
+        processed,
+        seconds,
+        processed as f64 / seconds.max(f64::EPSILON),
+        added,
+        replaced,
+        unchanged,
+        skipped
+    );
+    Ok(())
+}
+
+fn delete_command(state: &State) -> Result<()> {
     let stdin = io::stdin();
     let reader = stdin.lock();
     let mut db = VectorDatabase::open(state)?;
-    let mut added_labels = HashSet::new();
+    let mut deleted = 0;
+    let mut missing = Vec::new();
 
-    for (i, line_result) in reader.lines().enumerate() {
+    for line_result in reader.lines() {
         let line = line_result.context("Failed to read input line")?;
-        println!("Processing line {}: {}", i, line);
-        let mut entry = parse_input_line(&line, state)
-            .with_context(|| format!("Failed to parse input line: {}", line))?;
-
-        if db.label_exists(&entry.label)? {
-            eprintln!(
-                "Warning: Label '{}' already exists in the database. Skipping.",
-                entry.label
-            );
+        if line.trim().is_empty() {
             continue;
         }
+        let key = parse_key_line(&line)
+            .with_context(|| format!("Failed to parse input line: {}", line))?;
 
-        if added_labels.contains(&entry.label) {
-            eprintln!(
-                "Warning: Duplicate label '{}' found in current input. Skipping.",
-                entry.label
-            );
-            continue;
+        match db
+            .delete_entry(&key)
+            .with_context(|| format!("Failed to delete entry: {}", key))?
+        {
+            Some(unique_id) => {
+                deleted += 1;
+                config::verbose_print(&format!("Deleted entry '{}'", unique_id));
+            }
+            None => {
+                eprintln!("Warning: '{}' not found in the database.", key);
+                missing.push(key);
+            }
         }
+    }
+
+    println!("Deleted {} entries, {} missing", deleted, missing.len());
+    for key in &missing {
+        println!("missing: {}", key);
+    }
+
+    Ok(())
+}
 
-        normalize_vector(&mut entry.vector);
+/// Print the stored entry for every label or unique_id on stdin, one JSON
+/// object per line, and fail if any of them is not stored.
+fn get_command(state: &State, with_vector: bool) -> Result<()> {
+    let stdin = io::stdin();
+    let reader = stdin.lock();
+    let db = VectorDatabase::open(state)?;
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    let mut missing = 0;
 
-        let result = db
-            .add_entry(&entry) // Pass a reference to entry
-            .with_context(|| format!("Failed to add entry with label: {}", entry.label))?;
+    for line_result in reader.lines() {
+        let line = line_result.context("Failed to read input line")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let key = parse_key_line(&line)
+            .with_context(|| format!("Failed to parse input line: {}", line))?;
 
-        added_labels.insert(entry.label.clone());
-        config::verbose_print(&format!("Added vector with label '{}'", result));
+        match db
+            .find_entry(&key)
+            .with_context(|| format!("Failed to read entry: {}", key))?
+        {
+            Some(entry) => {
+                let mut output = serde_json::json!({
+                    "label": entry.label,
+                    "unique_id": entry.unique_id,
+                    "metadata": entry.metadata,
+                });
+                if with_vector {
+                    output["vector"] = serde_json::json!(entry.vector);
+                }
+                writeln!(out, "{}", serde_json::to_string(&output)?)?;
+            }
+            None => {
+                eprintln!("Warning: '{}' not found in the database.", key);
+                missing += 1;
+            }
+        }
     }
+    out.flush()?;
 
+    if missing > 0 {
+        anyhow::bail!("{} of the requested entries were not found", missing);
+    }
     Ok(())
 }
 
@@ -85,72 +320,319 @@ fn list_command(state: &State) -> Result<()> {
     Ok(())
 }
 
+/// Answer every query on stdin, one JSON object per line, in input order.
+/// Queries are read and evaluated in batches of `search::BATCH_SIZE`.
 fn search_command(state: &State) -> Result<()> {
-    let mut input = String::new();
-    std::io::stdin()
-        .read_line(&mut input)
-        .context("Failed to read input")?;
-
-    if input.trim().is_empty() {
-        return Err(anyhow::anyhow!(
-            "Error: No input provided for the search query."
-        ));
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut queries = read_query_batch(&mut lines, state)?;
+    if queries.is_empty() {
+        anyhow::bail!("No input provided for the search query.");
     }
 
-    let query_entry =
-        parse_input_line(&input, state).context("Failed to parse input as a valid query")?;
-    let query_vector = &query_entry.vector;
-
     let db = VectorDatabase::open(state)?;
-    println!("Debug: Database opened, record count: {}", db.count()?);
-
+    config::verbose_print(&format!("Database opened, record count: {}", db.count()?));
     let search_engine = SearchEngine::new(db, state)?;
-    let (results, timings) = search_engine.search(query_vector, state)?;
-
-    let output = serde_json::json!({
-        "query": {
-            "label": query_entry.label,
-            "unique_id": query_entry.unique_id,
-            "vector": &query_vector[..5.min(query_vector.len())],
-            "metadata": query_entry.metadata,
-        },
-        "database_record_count": search_engine.db.count()?,
-        "results": results.iter().map(|result| {
-            serde_json::json!({
-                "label": result.label,
-                "unique_id": result.unique_id,
-                "similarity": result.similarity,
-                "metadata": result.metadata,
+    let record_count = search_engine.db.count()?;
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    while !queries.is_empty() {
+        let batch: Vec<Query> = queries
+            .iter()
+            .map(|(entry, filter)| Query {
+                vector: entry.vector.clone(),
+                filter: filter.clone(),
             })
-        }).collect::<Vec<_>>(),
-        "actual_results_count": results.len(),
-        "requested_results_count": state.top_k,
-        "timings": {
-            "search_duration_ms": timings.search_duration.as_millis(),
-            "sort_duration_ms": timings.sort_duration.as_millis(),
-            "total_duration_ms": timings.total_duration.as_millis(),
+            .collect();
+        let outcomes = search_engine.search_batch(&batch, state)?;
+        for ((query_entry, filter), (results, timings)) in queries.iter().zip(outcomes) {
+            let query_vector = &query_entry.vector;
+            let output = serde_json::json!({
+                "query": {
+                    "label": query_entry.label,
+                    "unique_id": query_entry.unique_id,
+                    "vector": &query_vector[..5.min(query_vector.len())],
+                    "metadata": query_entry.metadata,
+                    "filter": filter,
+                },
+                "database_record_count": record_count,
+                "results": results.iter().map(|result| {
+                    serde_json::json!({
+                        "label": result.label,
+                        "unique_id": result.unique_id,
+                        "similarity": result.similarity,
+                        "metadata": result.metadata,
+                    })
+                }).collect::<Vec<_>>(),
+                "actual_results_count": results.len(),
+                "requested_results_count": state.top_k,
+                "similarity_threshold": state.similarity_threshold,
+                "metric": search_engine.db.metric(),
+                "timings": {
+                    "search_duration_ms": timings.search_duration.as_millis(),
+                    "sort_duration_ms": timings.sort_duration.as_millis(),
+                    "total_duration_ms": timings.total_duration.as_millis(),
+                }
+            });
+            writeln!(out, "{}", serde_json::to_string(&output)?)?;
         }
-    });
+        out.flush()?;
+        queries = read_query_batch(&mut lines, state)?;
+    }
 
-    println!("{}", serde_json::to_string_pretty(&output)?);
+    Ok(())
+}
 
+/// Parse up to `search::BATCH_SIZE` queries, skipping blank lines.
+fn read_query_batch(
+    lines: &mut impl Iterator<Item = io::Result<String>>,
+    state: &State,
+) -> Result<Vec<(VectorEntry, Option<Filter>)>> {
+    let mut queries = Vec::with_capacity(search::BATCH_SIZE);
+    for line_result in lines {
+        let line = line_result.context("Failed to read input line")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let query = parse_query_line(&line, state)
+            .with_context(|| format!("Failed to parse query: {}", line))?;
+        queries.push(query);
+        if queries.len() == search::BATCH_SIZE {
+            break;
+        }
+    }
+    Ok(queries)
+}
+
+fn reindex_command(state: &State, method: Option<IndexKind>) -> Result<()> {
+    let mut db = VectorDatabase::open(state)?;
+
+    let kinds = match method {
+        Some(kind) => vec![kind],
+        None => {
+            let kinds = persisted_indexes(&db);
+            if kinds.is_empty() {
+                vec![IndexKind::Ann]
+            } else {
+                kinds
+            }
+        }
+    };
+    rebuild_indexes(state, &mut db, &kinds)
+}
+
+fn persisted_indexes(db: &VectorDatabase) -> Vec<IndexKind> {
+    let mut kinds = Vec::new();
+    if db.ann_index().is_some() {
+        kinds.push(IndexKind::Ann);
+    }
+    if db.hnsw_index().is_some() {
+        kinds.push(IndexKind::Hnsw);
+    }
+    if db.ivf_index().is_some() {
+        kinds.push(IndexKind::Ivf);
+    }
+    if db.binary_codes().is_some() {
+        kinds.push(IndexKind::Binary);
+    }
+    if db.field_index().is_some() {
+        kinds.push(IndexKind::Fields);
+    }
+    kinds
+}
+
+fn rebuild_indexes(state: &State, db: &mut VectorDatabase, kinds: &[IndexKind]) -> Result<()> {
+    for kind in kinds {
+        match kind {
+            IndexKind::Ann => {
+                let count = db.rebuild_ann_index()?;
+                println!("Rebuilt ANN index over {} vectors", count);
+            }
+            IndexKind::Hnsw => {
+                let count = db.rebuild_hnsw_index(HnswParams::from_state(state))?;
+                println!("Rebuilt HNSW index over {} vectors", count);
+            }
+            IndexKind::Ivf => train_ivf_index(state, db, state.ivf_nlist)?,
+            IndexKind::Binary => {
+                let count = db.rebuild_binary_index()?;
+                println!("Rebuilt binary index over {} vectors", count);
+            }
+            IndexKind::Fields => {
+                let fields = match &state.indexed_fields {
+                    Some(fields) => fields.clone(),
+                    None => db.indexed_fields().to_vec(),
+                };
+                if fields.is_empty() {
+                    anyhow::bail!("No metadata fields to index; set VEKTA_INDEXED_FIELDS");
+                }
+                let count = db.rebuild_field_index(fields)?;
+                println!("Rebuilt metadata field index over {} entries", count);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn quantize_command(state: &State, precision: Precision) -> Result<()> {
+    let mut db = VectorDatabase::open(state)?;
+    let from = db.precision();
+    let count = db.quantize(precision)?;
+    println!(
+        "Re-encoded {} vectors from {} to {}",
+        count, from, precision
+    );
+
+    // Bucket and list assignments depend on the stored values
+    // The field index only depends on metadata, so it stays as it is
+    let kinds: Vec<IndexKind> = persisted_indexes(&db)
+        .into_iter()
+        .filter(|kind| !matches!(kind, IndexKind::Fields))
+        .collect();
+    rebuild_indexes(state, &mut db, &kinds)
+}
+
+fn train_command(state: &State, nlist: Option<usize>) -> Result<()> {
+    let mut db = VectorDatabase::open(state)?;
+    train_ivf_index(state, &mut db, nlist.or(state.ivf_nlist))
+}
+
+fn train_ivf_index(state: &State, db: &mut VectorDatabase, nlist: Option<usize>) -> Result<()> {
+    let count = db.train_ivf_index(nlist, PqSettings::from_state(state))?;
+    let nlist = db.ivf_index().map_or(0, |index| index.nlist());
+    println!(
+        "Trained IVF index with {} lists over {} vectors",
+        nlist, count
+    );
+    if let Some(pq) = db.ivf_pq() {
+        println!(
+            "PQ codes re-rank {}x top_k candidates (recall@{} {:.3} on a sample, target {})",
+            pq.rerank_factor, state.top_k, pq.calibrated_recall, state.ivf_pq_recall_target
+        );
+        if pq.calibrated_recall < state.ivf_pq_recall_target {
+            eprintln!(
+                "Warning: recall target not reached; raise ivf_nprobe or ivf_pq_subquantizers."
+            );
+        }
+    }
+    Ok(())
+}
+
+fn collection_command(state: &State, action: &CollectionAction) -> Result<()> {
+    match action {
+        CollectionAction::Create {
+            name,
+            dimensions,
+            metric,
+            precision,
+        } => {
+            let mut state = state.clone();
+            state.collection = name.clone();
+            let dimensions = dimensions
+                .or(state.configured_dimensions)
+                .context("Pass --dimensions or set VEKTA_DIMENSIONS")?;
+            state.set_dimensions(dimensions)?;
+            state.metric = metric.or(state.metric);
+            state.precision = precision.or(state.precision);
+            let db = VectorDatabase::create_collection(&state)?;
+            println!(
+                "Created collection '{}' with {} dimensions, {} metric and {} precision",
+                name,
+                dimensions,
+                db.metric(),
+                db.precision()
+            );
+        }
+        CollectionAction::List => {
+            for name in VectorDatabase::collection_names(state)? {
+                println!("{}", name);
+            }
+        }
+        CollectionAction::Drop { name } => {
+            VectorDatabase::drop_collection(state, name)?;
+            println!("Dropped collection '{}'", name);
+        }
+        CollectionAction::Info { name } => {
+            let mut state = state.clone();
+            if let Some(name) = name {
+                state.collection = name.clone();
+            }
+            VectorDatabase::select_collection(&mut state)?;
+            let db = VectorDatabase::open(&state)?;
+            let indexes: Vec<String> = persisted_indexes(&db)
+                .iter()
+                .filter_map(|kind| kind.to_possible_value())
+                .map(|value| value.get_name().to_string())
+                .collect();
+            println!("collection={}", state.collection);
+            db.schema().print();
+            println!("entries={}", db.count()?);
+            println!("indexes={}", indexes.join(","));
+            println!("indexed_fields={}", db.indexed_fields().join(","));
+        }
+    }
     Ok(())
 }
 
 fn config_command(state: &State) -> Result<()> {
     state.print_config();
+    match VectorDatabase::stored_schema(state)? {
+        Some(schema) => schema.print(),
+        None => println!("schema=none (written when the collection is first opened)"),
+    }
+    Ok(())
+}
+
+fn stats_command(state: &State) -> Result<()> {
+    let db = VectorDatabase::open(state)?;
+    let usage = db.map_usage()?;
+    println!("collection={}", state.collection);
+    println!("entries={}", db.count()?);
+    println!("map_size={}", usage.map_size);
+    println!("map_used={}", usage.used);
+    println!("file_size={}", usage.file_size);
+    Ok(())
+}
+
+fn migrate_command(state: &State, to: Option<&Path>) -> Result<()> {
+    let migration = migrate::migrate(Path::new(&state.path), to, state.map_size)?;
+    match migration.from {
+        Some(FORMAT_VERSION) => println!(
+            "Database at '{}' is already in format {}",
+            migration.path.display(),
+            FORMAT_VERSION
+        ),
+        Some(from) => println!(
+            "Migrated {} records in {} collections at '{}' from format {} to {}",
+            migration.records,
+            migration.collections,
+            migration.path.display(),
+            from,
+            FORMAT_VERSION
+        ),
+        None => println!(
+            "Database at '{}' is empty; marked it as format {}",
+            migration.path.display(),
+            FORMAT_VERSION
+        ),
+    }
     Ok(())
 }
 
 fn main() -> Result<()> {
     let args = Cli::parse();
-    let state = State::new()?;
+    // Needs no database configuration
+    if let Commands::CpuInfo = args.command {
+        simd::print_cpu_info();
+        return Ok(());
+    }
+    let mut state = State::new(args.collection.clone())?;
 
     let result = match &args.command {
-        Commands::Add => add_command(&state),
-        Commands::List => list_command(&state),
-        Commands::Search => search_command(&state),
-        Commands::Config => config_command(&state),
+        Commands::Collection { action } => collection_command(&state, action),
+        Commands::Migrate { to } => migrate_command(&state, to.as_deref()),
+        command => VectorDatabase::select_collection(&mut state)
+            .and_then(|()| run_command(command, &state)),
     };
 
     if let Err(e) = result {
@@ -160,3 +642,25 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+fn run_command(command: &Commands, state: &State) -> Result<()> {
+    match command {
+        Commands::Add {
+            on_conflict,
+            batch_size,
+        } => add_command(state, *on_conflict, *batch_size),
+        Commands::Delete => delete_command(state),
+        Commands::Get { with_vector } => get_command(state, *with_vector),
+        Commands::List => list_command(state),
+        Commands::Search => search_command(state),
+        Commands::Reindex { method } => reindex_command(state, *method),
+        Commands::Quantize { precision } => quantize_command(state, *precision),
+        Commands::Train { nlist } => train_command(state, *nlist),
+        Commands::Config => config_command(state),
+        Commands::Stats => stats_command(state),
+        Commands::CpuInfo => unreachable!("handled before the configuration is loaded"),
+        Commands::Collection { .. } | Commands::Migrate { .. } => {
+            unreachable!("handled without selecting a collection")
+        }
+    }
+}
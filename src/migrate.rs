@@ -0,0 +1,526 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::arena::{VectorArena, ARENA_FILE_NAME};
+use crate::collection::{collection_dir, DEFAULT_COLLECTION};
+use crate::config::Number;
+use crate::database::{LmdbWrapper, MAX_COLLECTIONS, RECORD_FORMAT_KEY, SCHEMA_KEY};
+use crate::schema::{Schema, FORMAT_VERSION};
+use crate::vector_entry::{CodeChunk, EntryRecord};
+use crate::vector_ops::Precision;
+
+/// A format 0 record: the whole entry, stored as a bincode byte vector
+/// holding the bincode entry.
+#[derive(Deserialize)]
+struct InlineEntry {
+    label: String,
+    unique_id: String,
+    vector: Vec<Number>,
+    metadata: CodeChunk,
+}
+
+/// A format 1 record, from when every entry carried the code-chunk fields.
+#[derive(Deserialize)]
+struct TypedEntryRecord {
+    label: String,
+    unique_id: String,
+    row: u64,
+    metadata: CodeChunk,
+}
+
+/// What `migrate` found and rewrote.
+pub struct Migration {
+    pub path: PathBuf,
+    /// Format the database was in, or `None` if it stored nothing yet
+    pub from: Option<u32>,
+    pub collections: usize,
+    pub records: usize,
+}
+
+/// The on-disk format of the environment `lmdb` belongs to. Environments
+/// written before the format was stamped are recognized by what they store.
+/// `None` means nothing is stored yet, so any format can take it over.
+pub fn stored_format(lmdb: &LmdbWrapper) -> Result<Option<u32>> {
+    if let Some(version) = lmdb.format_version()? {
+        return Ok(Some(version));
+    }
+    if lmdb.get_meta(SCHEMA_KEY)?.is_some()
+        || lmdb.get_meta(RECORD_FORMAT_KEY)?.is_some()
+        || !lmdb.collection_names()?.is_empty()
+    {
+        return Ok(Some(2));
+    }
+    if lmdb.count()? == 0 {
+        return Ok(None);
+    }
+    // Format 0 kept vectors inside the records and had no row mapping
+    Ok(Some(if lmdb.live_rows()?.is_empty() { 0 } else { 1 }))
+}
+
+/// Fail unless the environment is in the current format. An empty one is
+/// stamped with it.
+pub fn check_format(lmdb: &LmdbWrapper, path: &str) -> Result<()> {
+    match stored_format(lmdb)? {
+        Some(FORMAT_VERSION) => Ok(()),
+        Some(version) if version > FORMAT_VERSION => anyhow::bail!(
+            "The database at '{}' uses format version {}, but this build of vekta only reads up to {}",
+            path,
+            version,
+            FORMAT_VERSION
+        ),
+        Some(version) => anyhow::bail!(
+            "The database at '{}' uses format version {}; run `vekta migrate` to rewrite it in format {}",
+            path,
+            version,
+            FORMAT_VERSION
+        ),
+        None => {
+            let mut wtxn = lmdb.write_txn()?;
+            lmdb.put_format_version(&mut wtxn, FORMAT_VERSION)?;
+            wtxn.commit()?;
+            Ok(())
+        }
+    }
+}
+
+/// Rewrite every collection of the database at `path` in the current format,
+/// in one write transaction. With `output`, the database is first copied to
+/// that new directory and only the copy is rewritten.
+pub fn migrate(path: &Path, output: Option<&Path>, map_size: usize) -> Result<Migration> {
+    if !path.join("data.mdb").exists() {
+        anyhow::bail!("No database at '{}'", path.display());
+    }
+    let path = match output {
+        Some(output) => {
+            copy_database(path, output, map_size)?;
+            output.to_path_buf()
+        }
+        None => path.to_path_buf(),
+    };
+
+    let lmdb = LmdbWrapper::new(&path, DEFAULT_COLLECTION, map_size)?;
+    let from = stored_format(&lmdb)?;
+    let mut migration = Migration {
+        path,
+        from,
+        collections: 0,
+        records: 0,
+    };
+    match from {
+        Some(version) if version > FORMAT_VERSION => anyhow::bail!(
+            "The database at '{}' uses format version {}, which is newer than this build of vekta",
+            migration.path.display(),
+            version
+        ),
+        Some(FORMAT_VERSION) => return Ok(migration),
+        _ => {}
+    }
+
+    // Every collection's databases are created before the write transaction
+    let mut collections = Vec::new();
+    if let Some(from) = from {
+        let mut names = vec![DEFAULT_COLLECTION.to_string()];
+        names.extend(lmdb.collection_names()?);
+        if names.len() > MAX_COLLECTIONS + 1 {
+            anyhow::bail!(
+                "The database holds {} named collections, but vekta can only migrate up to {} at once",
+                names.len() - 1,
+                MAX_COLLECTIONS
+            );
+        }
+        for name in names {
+            let collection = LmdbWrapper::new(&migration.path, &name, map_size)?;
+            let schema: Option<Schema> = collection
+                .get_meta(SCHEMA_KEY)?
+                .map(|stored| serde_json::from_str(&stored))
+                .transpose()
+                .with_context(|| format!("Failed to parse the schema of collection '{}'", name))?;
+            collections.push((name, collection, schema));
+        }
+        if from == 0 && collections.len() > 1 {
+            anyhow::bail!("Format 0 databases cannot hold named collections");
+        }
+    }
+
+    // A retry after growing the map starts over from the unmigrated records
+    let (migrated, records) = lmdb
+        .with_write_txn(|wtxn| {
+            let mut records = 0;
+            for (name, collection, schema) in &collections {
+                records += match from {
+                    Some(0) => {
+                        let dir = collection_dir(&migration.path, name);
+                        migrate_inline_records(collection, wtxn, &dir)
+                    }
+                    Some(1) => collection.rewrite_records(wtxn, |key, value| {
+                        let record: TypedEntryRecord = bincode::deserialize(value)
+                            .with_context(|| format!("Failed to read record '{}'", key))?;
+                        EntryRecord {
+                            label: record.label,
+                            unique_id: record.unique_id,
+                            row: record.row,
+                            metadata: serde_json::to_value(record.metadata)?,
+                        }
+                        .encode()
+                    }),
+                    // Format 2 records are the current ones without the encoding tag
+                    _ => collection.rewrite_records(wtxn, |key, value| {
+                        let record: EntryRecord = bincode::deserialize(value)
+                            .with_context(|| format!("Failed to read record '{}'", key))?;
+                        record.encode()
+                    }),
+                }
+                .with_context(|| format!("Failed to migrate collection '{}'", name))?;
+                if let Some(schema) = schema {
+                    let mut schema = schema.clone();
+                    schema.format_version = FORMAT_VERSION;
+                    collection.put_meta_in(wtxn, SCHEMA_KEY, &serde_json::to_string(&schema)?)?;
+                }
+            }
+            lmdb.put_format_version(wtxn, FORMAT_VERSION)?;
+            Ok((collections.len(), records))
+        })
+        .context("Failed to write the migrated records")?;
+    migration.collections = migrated;
+    migration.records = records;
+    Ok(migration)
+}
+
+/// Move format 0 vectors out of the records into a new f32 arena, one row
+/// per record in key order. The stored vectors were normalized for cosine
+/// similarity, which the schema written on first open assumes.
+fn migrate_inline_records(lmdb: &LmdbWrapper, wtxn: &mut heed::RwTxn, dir: &Path) -> Result<usize> {
+    let mut arena: Option<VectorArena> = None;
+    let mut keys = Vec::new();
+    let count = lmdb.rewrite_records(wtxn, |key, value| {
+        let bytes: Vec<u8> = bincode::deserialize(value)
+            .with_context(|| format!("Failed to read record '{}'", key))?;
+        let entry: InlineEntry = bincode::deserialize(&bytes)
+            .with_context(|| format!("Failed to read record '{}'", key))?;
+        // The first record decides the dimensions; the arena rejects any other
+        if arena.is_none() {
+            arena = Some(VectorArena::open(
+                dir.join(ARENA_FILE_NAME),
+                entry.vector.len(),
+                Precision::F32,
+                Vec::new(),
+            )?);
+        }
+        let row = keys.len();
+        if let Some(arena) = &mut arena {
+            arena
+                .write(row, &entry.vector)
+                .with_context(|| format!("Failed to move the vector of record '{}'", key))?;
+        }
+        keys.push(key.to_string());
+        EntryRecord {
+            label: entry.label,
+            unique_id: entry.unique_id,
+            row: row as u64,
+            metadata: serde_json::to_value(entry.metadata)?,
+        }
+        .encode()
+    })?;
+    for (row, key) in keys.iter().enumerate() {
+        lmdb.put_row(wtxn, row, key)?;
+    }
+    if let Some(arena) = &arena {
+        arena.sync()?;
+    }
+    Ok(count)
+}
+
+/// Copy the database at `source` into the new directory `target`: a
+/// compacted copy of the LMDB environment, and every collection's vector
+/// and code files as they are.
+fn copy_database(source: &Path, target: &Path, map_size: usize) -> Result<()> {
+    if target.exists() && fs::read_dir(target)?.next().is_some() {
+        anyhow::bail!("'{}' already exists and is not empty", target.display());
+    }
+    if std::path::absolute(target)?.starts_with(fs::canonicalize(source)?) {
+        anyhow::bail!(
+            "Cannot migrate '{}' into a directory inside it",
+            source.display()
+        );
+    }
+    fs::create_dir_all(target)
+        .with_context(|| format!("Failed to create directory '{}'", target.display()))?;
+    LmdbWrapper::copy_environment(source, target, map_size)?;
+    copy_files(source, target)
+}
+
+fn copy_files(source: &Path, target: &Path) -> Result<()> {
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let to = target.join(&name);
+        if entry.file_type()?.is_dir() {
+            fs::create_dir_all(&to)?;
+            copy_files(&entry.path(), &to)?;
+        } else if name != "data.mdb" && name != "lock.mdb" {
+            fs::copy(entry.path(), &to)
+                .with_context(|| format!("Failed to copy '{}'", entry.path().display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    // The fixtures under tests/fixtures were written by earlier builds from
+    // the same twelve 8-dimensional code-chunk entries, `chunk0` to
+    // `chunk11`:
+    //   format0         vectors inside the records, `chunk11` deleted
+    //   format1         vector arena, dot metric, with an HNSW index,
+    //                   `chunk11` deleted
+    //   format2         free-form metadata, with `file_name` indexed,
+    //                   `chunk11` deleted
+    //   format2-schema  schema records, plus a `notes` collection using the
+    //                   l2 metric and f16 storage, holding five entries
+    //   format2-collections
+    //                   `chunk0` to `chunk2` in the default collection, and
+    //                   three each in `alpha` (cosine), `beta` (dot) and
+    //                   `gamma` (l2)
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::config::State;
+    use crate::database::{VectorDatabase, DEFAULT_BATCH_SIZE, DEFAULT_MAP_SIZE};
+    use crate::filter::Filter;
+    use crate::vector_ops::Metric;
+    use crate::{binary, hnsw, ivf, pq};
+
+    const CHUNK0: [Number; 8] = [
+        0.9164, -0.7193, -0.9528, 0.9973, -0.6315, -0.7588, 0.3028, -0.3087,
+    ];
+
+    /// A scratch copy of a fixture, so the checked-in files are never opened.
+    fn fixture(name: &str) -> PathBuf {
+        let source = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name);
+        let path = scratch_dir(name);
+        fs::create_dir_all(&path).unwrap();
+        copy_files(&source, &path).unwrap();
+        fs::copy(source.join("data.mdb"), path.join("data.mdb")).unwrap();
+        path
+    }
+
+    /// A fresh directory path; tests run in parallel, so each call gets its own.
+    fn scratch_dir(name: &str) -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "vekta-{}-{}-{}",
+            name,
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    /// The fixtures' settings, independent of any configuration file or
+    /// environment variable.
+    fn state(path: &Path, collection: &str) -> State {
+        let mut state = State {
+            path: path.to_str().unwrap().to_string(),
+            collection: collection.to_string(),
+            configured_dimensions: Some(8),
+            dimensions: 0,
+            label_size: 64,
+            vector_size: 0,
+            chunk_size: 0,
+            top_k: 10,
+            search_method: "exact".to_string(),
+            similarity_threshold: None,
+            metric: None,
+            precision: None,
+            threads: 1,
+            hnsw_m: hnsw::DEFAULT_M,
+            hnsw_ef_construction: hnsw::DEFAULT_EF_CONSTRUCTION,
+            hnsw_ef_search: hnsw::DEFAULT_EF_SEARCH,
+            ivf_nlist: None,
+            ivf_nprobe: ivf::DEFAULT_NPROBE,
+            ivf_pq_subquantizers: None,
+            ivf_pq_bits: pq::DEFAULT_BITS,
+            ivf_pq_recall_target: pq::DEFAULT_RECALL_TARGET,
+            binary_oversample: binary::DEFAULT_OVERSAMPLE,
+            indexed_fields: None,
+            map_size: DEFAULT_MAP_SIZE,
+            batch_size: DEFAULT_BATCH_SIZE,
+        };
+        VectorDatabase::select_collection(&mut state).unwrap();
+        state
+    }
+
+    fn open(path: &Path, collection: &str) -> VectorDatabase {
+        VectorDatabase::open(&state(path, collection)).unwrap()
+    }
+
+    fn assert_code_chunks(db: &VectorDatabase, chunk0: &[Number]) {
+        assert_eq!(db.count().unwrap(), 11);
+        assert!(db.get_entry_by_label("chunk11").unwrap().is_none());
+        let entry = db.get_entry_by_label("chunk0").unwrap().unwrap();
+        assert_eq!(entry.metadata["file_path"], "src/mod0/file0.rs");
+        assert_eq!(entry.metadata["end_line"], 9);
+        assert_eq!(entry.vector.len(), chunk0.len());
+        for (stored, expected) in entry.vector.iter().zip(chunk0) {
+            assert!(
+                (stored - expected).abs() < 1e-6,
+                "stored {:?}, expected {:?}",
+                entry.vector,
+                chunk0
+            );
+        }
+    }
+
+    fn normalized_chunk0() -> Vec<Number> {
+        let norm = CHUNK0.iter().map(|x| x * x).sum::<Number>().sqrt();
+        CHUNK0.iter().map(|x| x / norm).collect()
+    }
+
+    #[test]
+    fn unmigrated_fixtures_are_refused() {
+        for (name, version) in [
+            ("format0", 0),
+            ("format1", 1),
+            ("format2", 2),
+            ("format2-schema", 2),
+        ] {
+            let path = fixture(name);
+            let error = VectorDatabase::open(&state(&path, DEFAULT_COLLECTION))
+                .err()
+                .unwrap()
+                .to_string();
+            assert!(
+                error.contains(&format!("format version {};", version))
+                    && error.contains("vekta migrate"),
+                "{}: {}",
+                name,
+                error
+            );
+            fs::remove_dir_all(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn migrates_format0_in_place() {
+        let path = fixture("format0");
+        let migration = migrate(&path, None, DEFAULT_MAP_SIZE).unwrap();
+        assert_eq!(migration.from, Some(0));
+        assert_eq!(migration.records, 11);
+
+        // Format 0 normalized vectors before storing them
+        let db = open(&path, DEFAULT_COLLECTION);
+        assert_eq!(db.metric(), Metric::Cosine);
+        assert_eq!(db.schema().format_version, FORMAT_VERSION);
+        assert_code_chunks(&db, &normalized_chunk0());
+        drop(db);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn migrates_format1_into_new_path() {
+        let source = fixture("format1");
+        let target = scratch_dir("format1-migrated");
+        let migration = migrate(&source, Some(&target), DEFAULT_MAP_SIZE).unwrap();
+        assert_eq!(migration.from, Some(1));
+        assert_eq!(migration.records, 11);
+
+        let lmdb = LmdbWrapper::new(&source, DEFAULT_COLLECTION, DEFAULT_MAP_SIZE).unwrap();
+        assert_eq!(stored_format(&lmdb).unwrap(), Some(1));
+        let db = open(&target, DEFAULT_COLLECTION);
+        assert_eq!(db.metric(), Metric::Dot);
+        assert!(db.hnsw_index().is_some());
+        assert_code_chunks(&db, &CHUNK0);
+        drop(db);
+        fs::remove_dir_all(&source).unwrap();
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[test]
+    fn migrates_format2_with_field_index() {
+        let path = fixture("format2");
+        assert_eq!(
+            migrate(&path, None, DEFAULT_MAP_SIZE).unwrap().from,
+            Some(2)
+        );
+
+        let db = open(&path, DEFAULT_COLLECTION);
+        assert_eq!(db.indexed_fields(), ["file_name"]);
+        assert_code_chunks(&db, &normalized_chunk0());
+        let filter = Filter::from_value(
+            serde_json::json!({"eq": {"field": "file_name", "value": "file3.rs"}}),
+        )
+        .unwrap();
+        let rows = db.matching_rows(&[&filter]).unwrap().remove(0);
+        let labels: Vec<String> = rows
+            .iter()
+            .map(|row| db.get_record_by_row(row).unwrap().unwrap().label)
+            .collect();
+        assert_eq!(labels, ["chunk3"]);
+        drop(db);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn migrates_every_collection() {
+        let path = fixture("format2-schema");
+        let migration = migrate(&path, None, DEFAULT_MAP_SIZE).unwrap();
+        assert_eq!(migration.from, Some(2));
+        assert_eq!(migration.collections, 2);
+        assert_eq!(migration.records, 17);
+
+        let db = open(&path, DEFAULT_COLLECTION);
+        assert_eq!(db.count().unwrap(), 12);
+        drop(db);
+        let notes = open(&path, "notes");
+        assert_eq!(notes.schema().format_version, FORMAT_VERSION);
+        assert_eq!(notes.metric(), Metric::L2);
+        assert_eq!(notes.precision(), Precision::F16);
+        assert_eq!(notes.count().unwrap(), 5);
+        let note = notes.get_entry_by_label("note1").unwrap().unwrap();
+        assert_eq!(note.metadata["tags"], serde_json::json!(["a", "b"]));
+        assert_eq!(note.metadata["pinned"], false);
+        drop(notes);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn migrates_several_named_collections() {
+        let path = fixture("format2-collections");
+        let migration = migrate(&path, None, DEFAULT_MAP_SIZE).unwrap();
+        assert_eq!(migration.collections, 4);
+        assert_eq!(migration.records, 12);
+
+        for (collection, metric, first) in [
+            (DEFAULT_COLLECTION, Metric::Cosine, 0),
+            ("alpha", Metric::Cosine, 3),
+            ("beta", Metric::Dot, 6),
+            ("gamma", Metric::L2, 9),
+        ] {
+            let db = open(&path, collection);
+            assert_eq!(db.metric(), metric);
+            assert_eq!(db.count().unwrap(), 3);
+            for i in first..first + 3 {
+                let label = format!("chunk{}", i);
+                let entry = db.get_entry_by_label(&label).unwrap().unwrap();
+                assert_eq!(entry.metadata["file_name"], format!("file{}.rs", i));
+            }
+        }
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn migrating_a_current_database_changes_nothing() {
+        let path = fixture("format1");
+        migrate(&path, None, DEFAULT_MAP_SIZE).unwrap();
+        let migration = migrate(&path, None, DEFAULT_MAP_SIZE).unwrap();
+        assert_eq!(migration.from, Some(FORMAT_VERSION));
+        assert_eq!(migration.records, 0);
+        assert_code_chunks(&open(&path, DEFAULT_COLLECTION), &CHUNK0);
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
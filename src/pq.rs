@@ -0,0 +1,354 @@
+use anyhow::Result;
+use rand::prelude::*;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::config::{Number, State};
+use crate::ivf::{kmeans, IvfIndex};
+use crate::vector_ops::Metric;
+
+const SEED: u64 = 42;
+/// Training points per codeword; larger corpora are sampled down to this.
+const TRAINING_POINTS_PER_CODE: usize = 64;
+const CALIBRATION_QUERIES: usize = 100;
+const RERANK_FACTORS: [usize; 7] = [1, 2, 4, 8, 16, 32, 64];
+
+/// IVF-PQ postings hold an 8-byte row followed by the code, in an LMDB
+/// DUP_SORT database whose values are limited to 511 bytes.
+const MAX_CODE_SIZE: usize = 511 - 8;
+
+pub const DEFAULT_BITS: usize = 8;
+pub const DEFAULT_RECALL_TARGET: Number = 0.95;
+
+/// How to train the PQ codec of an IVF index, and what the re-ranking depth
+/// is calibrated against.
+#[derive(Clone, Copy)]
+pub struct PqSettings {
+    pub subquantizers: usize,
+    pub bits: usize,
+    pub recall_target: Number,
+    pub top_k: usize,
+    pub nprobe: usize,
+}
+
+impl PqSettings {
+    /// `None` when PQ is not configured, so the IVF index stores no codes.
+    pub fn from_state(state: &State) -> Option<Self> {
+        state.ivf_pq_subquantizers.map(|subquantizers| PqSettings {
+            subquantizers,
+            bits: state.ivf_pq_bits,
+            recall_target: state.ivf_pq_recall_target,
+            top_k: state.top_k,
+            nprobe: state.ivf_nprobe,
+        })
+    }
+}
+
+/// Splits vectors into `subquantizers` equal slices and replaces each slice by
+/// the index of its nearest codeword, packed `bits` to a slice, so a vector
+/// costs `subquantizers * bits` bits rounded up to whole bytes. The IVF index
+/// encodes residuals: a vector minus the centroid of its list.
+#[derive(Serialize, Deserialize)]
+pub struct ProductQuantizer {
+    subquantizers: usize,
+    sub_dimensions: usize,
+    bits: usize,
+    /// `codebooks[s][j]` is codeword `j` of slice `s`
+    codebooks: Vec<Vec<Vec<Number>>>,
+}
+
+/// The persisted PQ state of an IVF index.
+#[derive(Serialize, Deserialize)]
+pub struct PqIndex {
+    pub quantizer: ProductQuantizer,
+    /// Approximate candidates re-ranked at full precision, as a multiple of top_k
+    pub rerank_factor: usize,
+    /// Recall@top_k the factor reached on the training sample
+    pub calibrated_recall: Number,
+}
+
+impl ProductQuantizer {
+    pub fn train(residuals: &[Vec<Number>], subquantizers: usize, bits: usize) -> Result<Self> {
+        let dimensions = residuals.first().map_or(0, Vec::len);
+        if dimensions == 0 {
+            anyhow::bail!("No stored vectors to train the PQ codec on");
+        }
+        if subquantizers == 0 || !dimensions.is_multiple_of(subquantizers) {
+            anyhow::bail!(
+                "PQ sub-quantizer count must divide the {} dimensions, got {}",
+                dimensions,
+                subquantizers
+            );
+        }
+        if !(1..=8).contains(&bits) {
+            anyhow::bail!("PQ bits must be between 1 and 8, got {}", bits);
+        }
+        if code_size(subquantizers, bits) > MAX_CODE_SIZE {
+            anyhow::bail!(
+                "PQ codes of {} sub-quantizers at {} bits do not fit in an IVF posting of {} bytes",
+                subquantizers,
+                bits,
+                MAX_CODE_SIZE
+            );
+        }
+
+        let codes = 1usize << bits;
+        let mut rng = StdRng::seed_from_u64(SEED);
+        let sample: Vec<&Vec<Number>> = residuals
+            .choose_multiple(&mut rng, codes * TRAINING_POINTS_PER_CODE)
+            .collect();
+
+        let sub_dimensions = dimensions / subquantizers;
+        let codebooks = (0..subquantizers)
+            .map(|s| {
+                let slices: Vec<&[Number]> = sample
+                    .iter()
+                    .map(|residual| &residual[s * sub_dimensions..(s + 1) * sub_dimensions])
+                    .collect();
+                kmeans(&slices, codes, false)
+            })
+            .collect();
+
+        Ok(ProductQuantizer {
+            subquantizers,
+            sub_dimensions,
+            bits,
+            codebooks,
+        })
+    }
+
+    pub fn encode(&self, residual: &[Number]) -> Vec<u8> {
+        let mut code = vec![0; code_size(self.subquantizers, self.bits)];
+        for (s, (codebook, slice)) in self
+            .codebooks
+            .iter()
+            .zip(residual.chunks_exact(self.sub_dimensions))
+            .enumerate()
+        {
+            let nearest = codebook
+                .iter()
+                .enumerate()
+                .map(|(code, codeword)| (squared_distance(slice, codeword), code))
+                .min_by(|a, b| a.0.total_cmp(&b.0))
+                .map_or(0, |(_, code)| code);
+            pack(&mut code, s, self.bits, nearest);
+        }
+        code
+    }
+
+    /// Per-slice scores of every codeword against the query for one list, so
+    /// a code is scored with one lookup per slice (asymmetric distance).
+    pub fn score_table(&self, metric: Metric, query: &[Number], centroid: &[Number]) -> ScoreTable {
+        let codes = self.codebooks.first().map_or(0, Vec::len);
+        let mut scores = Vec::with_capacity(self.subquantizers * codes);
+        for (s, codebook) in self.codebooks.iter().enumerate() {
+            let range = s * self.sub_dimensions..(s + 1) * self.sub_dimensions;
+            let (query, centroid) = (&query[range.clone()], &centroid[range]);
+            for codeword in codebook {
+                scores.push(partial_score(metric, query, centroid, codeword));
+            }
+        }
+        ScoreTable {
+            subquantizers: self.subquantizers,
+            bits: self.bits,
+            codes,
+            scores,
+        }
+    }
+}
+
+pub struct ScoreTable {
+    subquantizers: usize,
+    bits: usize,
+    codes: usize,
+    scores: Vec<Number>,
+}
+
+impl ScoreTable {
+    /// Approximate score of an encoded vector; higher is better, but the scale
+    /// differs from the metric's own score.
+    pub fn score(&self, code: &[u8]) -> Number {
+        if self.bits == 8 {
+            return code
+                .iter()
+                .enumerate()
+                .map(|(s, &c)| self.scores[s * self.codes + c as usize])
+                .sum();
+        }
+        (0..self.subquantizers)
+            .map(|s| self.scores[s * self.codes + unpack(code, s, self.bits)])
+            .sum()
+    }
+}
+
+/// Bytes a code of `subquantizers` slices at `bits` each takes.
+fn code_size(subquantizers: usize, bits: usize) -> usize {
+    (subquantizers * bits).div_ceil(8)
+}
+
+/// Write the codeword index of slice `s` into its `bits` of `code`, lowest
+/// bits first. A slice spans at most two bytes.
+fn pack(code: &mut [u8], s: usize, bits: usize, index: usize) {
+    let (byte, shift) = (s * bits / 8, s * bits % 8);
+    let shifted = index << shift;
+    code[byte] |= shifted as u8;
+    if shift + bits > 8 {
+        code[byte + 1] |= (shifted >> 8) as u8;
+    }
+}
+
+/// The codeword index of slice `s`, as written by `pack`.
+fn unpack(code: &[u8], s: usize, bits: usize) -> usize {
+    let (byte, shift) = (s * bits / 8, s * bits % 8);
+    let mut word = code[byte] as usize;
+    if shift + bits > 8 {
+        word |= (code[byte + 1] as usize) << 8;
+    }
+    (word >> shift) & ((1 << bits) - 1)
+}
+
+/// One slice's contribution to the score of `centroid + codeword`. Distances
+/// are summed squared (L2) or absolute (L1) per slice, which preserves their
+/// ranking; cosine vectors are normalized, so L2 ranks them the same way.
+fn partial_score(
+    metric: Metric,
+    query: &[Number],
+    centroid: &[Number],
+    codeword: &[Number],
+) -> Number {
+    let reconstructed = centroid.iter().zip(codeword).map(|(c, r)| c + r);
+    match metric {
+        Metric::Dot => query.iter().zip(reconstructed).map(|(q, y)| q * y).sum(),
+        Metric::L1 => -query
+            .iter()
+            .zip(reconstructed)
+            .map(|(q, y)| (q - y).abs())
+            .sum::<Number>(),
+        Metric::Cosine | Metric::L2 => -query
+            .iter()
+            .zip(reconstructed)
+            .map(|(q, y)| (q - y) * (q - y))
+            .sum::<Number>(),
+    }
+}
+
+fn squared_distance(a: &[Number], b: &[Number]) -> Number {
+    a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+/// The residual a vector is encoded as in its list.
+pub fn residual(vector: &[Number], centroid: &[Number]) -> Vec<Number> {
+    vector.iter().zip(centroid).map(|(v, c)| v - c).collect()
+}
+
+/// A stored vector with its IVF list and PQ code, as produced while training.
+pub struct EncodedRow<'a> {
+    pub row: usize,
+    pub vector: &'a [Number],
+    pub list: u32,
+    pub code: Vec<u8>,
+}
+
+/// Pick the smallest re-ranking factor whose recall@top_k, measured on a
+/// sample of stored vectors used as queries, reaches the target. Returns the
+/// factor and the recall it reached.
+pub fn calibrate_rerank(
+    ivf: &IvfIndex,
+    quantizer: &ProductQuantizer,
+    metric: Metric,
+    encoded: &[EncodedRow],
+    settings: &PqSettings,
+) -> (usize, Number) {
+    let top_k = settings.top_k.max(1);
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let queries: Vec<&EncodedRow> = encoded
+        .choose_multiple(&mut rng, CALIBRATION_QUERIES)
+        .collect();
+
+    let mut lists: HashMap<u32, Vec<&EncodedRow>> = HashMap::new();
+    for entry in encoded {
+        lists.entry(entry.list).or_default().push(entry);
+    }
+
+    // For each query: the exact top_k rows, and the probed rows ranked by PQ score
+    let rankings: Vec<(Vec<usize>, Vec<usize>)> = queries
+        .par_iter()
+        .map(|query| {
+            let mut exact: Vec<(Number, usize)> = encoded
+                .iter()
+                .filter_map(|other| Some((metric.score(query.vector, other.vector)?, other.row)))
+                .collect();
+            exact.sort_by(|a, b| b.0.total_cmp(&a.0));
+            let exact = exact.into_iter().take(top_k).map(|(_, row)| row).collect();
+
+            let mut approximate = Vec::new();
+            for list in ivf.probe_lists(query.vector, settings.nprobe) {
+                let table = quantizer.score_table(metric, query.vector, ivf.centroid(list));
+                if let Some(members) = lists.get(&list) {
+                    approximate.extend(members.iter().map(|m| (table.score(&m.code), m.row)));
+                }
+            }
+            approximate.sort_by(|a, b| b.0.total_cmp(&a.0));
+            (exact, approximate.into_iter().map(|(_, row)| row).collect())
+        })
+        .collect();
+
+    // Re-ranking at full precision recovers every exact winner among the
+    // candidates, so recall only depends on how many candidates are kept
+    let recall_at = |factor: usize| -> Number {
+        let depth = factor * top_k;
+        let (found, total) =
+            rankings
+                .iter()
+                .fold((0, 0), |(found, total), (exact, approximate)| {
+                    let kept = &approximate[..depth.min(approximate.len())];
+                    let hits = exact.iter().filter(|row| kept.contains(row)).count();
+                    (found + hits, total + exact.len())
+                });
+        if total == 0 {
+            1.0
+        } else {
+            found as Number / total as Number
+        }
+    };
+
+    let mut best = (RERANK_FACTORS[0], 0.0);
+    for factor in RERANK_FACTORS {
+        let recall = recall_at(factor);
+        best = (factor, recall);
+        if recall >= settings.recall_target {
+            break;
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_pack_to_their_bits() {
+        for bits in 1..=8 {
+            for subquantizers in [1, 3, 8, 13] {
+                let indexes: Vec<usize> = (0..subquantizers)
+                    .map(|s| (s * 37 + 11) % (1 << bits))
+                    .collect();
+                let mut code = vec![0; code_size(subquantizers, bits)];
+                for (s, &index) in indexes.iter().enumerate() {
+                    pack(&mut code, s, bits, index);
+                }
+                let unpacked: Vec<usize> =
+                    (0..subquantizers).map(|s| unpack(&code, s, bits)).collect();
+                assert_eq!(
+                    unpacked, indexes,
+                    "{} slices at {} bits",
+                    subquantizers, bits
+                );
+            }
+        }
+        assert_eq!(code_size(48, 4), 24);
+        assert_eq!(code_size(13, 3), 5);
+    }
+}
@@ -0,0 +1,105 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::config::State;
+use crate::vector_ops::{Metric, Precision};
+
+/// Version of the on-disk format, stamped in the environment and in each
+/// collection's schema. Older formats are rewritten by `vekta migrate`:
+///
+/// - 0: whole entries, vector included, stored as bincode in LMDB
+/// - 1: vectors in the arena, records with typed code-chunk metadata
+/// - 2: free-form metadata as MessagePack, schema record per collection
+/// - 3: format version in the environment, records tagged with their encoding
+pub const FORMAT_VERSION: u32 = 3;
+
+/// What a collection was created with, stored in its meta database as JSON
+/// and checked against the configuration every time it is opened.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Schema {
+    pub format_version: u32,
+    pub dimensions: usize,
+    pub label_size: usize,
+    pub metric: Metric,
+    /// Whether stored vectors were normalized before being written
+    pub normalized: bool,
+    pub precision: Precision,
+}
+
+impl Schema {
+    pub fn new(state: &State, metric: Metric, precision: Precision) -> Self {
+        Self {
+            format_version: FORMAT_VERSION,
+            dimensions: state.dimensions,
+            label_size: state.label_size,
+            metric,
+            normalized: metric.normalizes(),
+            precision,
+        }
+    }
+
+    /// Fail if this build cannot read the stored format, or if a configured
+    /// setting differs from the stored one. Unset metric and precision
+    /// settings take the stored values.
+    pub fn validate(&self, state: &State) -> Result<()> {
+        if self.format_version > FORMAT_VERSION {
+            anyhow::bail!(
+                "Collection '{}' uses format version {}, but this build of vekta only reads up to {}",
+                state.collection,
+                self.format_version,
+                FORMAT_VERSION
+            );
+        }
+
+        let mut mismatches = Vec::new();
+        if state.dimensions != self.dimensions {
+            mismatches.push(format!(
+                "dimensions: stored {}, configured {}",
+                self.dimensions, state.dimensions
+            ));
+        }
+        if state.label_size != self.label_size {
+            mismatches.push(format!(
+                "label_size: stored {}, configured {}",
+                self.label_size, state.label_size
+            ));
+        }
+        if let Some(metric) = state.metric.filter(|&m| m != self.metric) {
+            mismatches.push(format!(
+                "metric: stored {}, configured {}",
+                self.metric, metric
+            ));
+        }
+        if let Some(precision) = state.precision.filter(|&p| p != self.precision) {
+            mismatches.push(format!(
+                "precision: stored {}, configured {}",
+                self.precision, precision
+            ));
+        }
+        if self.normalized != self.metric.normalizes() {
+            mismatches.push(format!(
+                "normalization: stored vectors are {}normalized, but the {} metric expects otherwise",
+                if self.normalized { "" } else { "not " },
+                self.metric
+            ));
+        }
+        if !mismatches.is_empty() {
+            anyhow::bail!(
+                "The configuration does not match the schema of collection '{}' at '{}':\n  {}",
+                state.collection,
+                state.path,
+                mismatches.join("\n  ")
+            );
+        }
+        Ok(())
+    }
+
+    pub fn print(&self) {
+        println!("schema.format_version={}", self.format_version);
+        println!("schema.dimensions={}", self.dimensions);
+        println!("schema.label_size={}", self.label_size);
+        println!("schema.metric={}", self.metric);
+        println!("schema.normalized={}", self.normalized);
+        println!("schema.precision={}", self.precision);
+    }
+}
@@ -1,10 +1,20 @@
-use crate::ann::RandomProjectionIndex;
-use crate::config::{Number, State};
+use crate::config::{verbose_print, Number, State};
 use crate::database::VectorDatabase;
-use crate::vector_entry::{Metadata, VectorEntry};
-use crate::vector_ops::{compute_cosine_similarity_simd, normalize_vector};
-use anyhow::Result;
-use std::time::Instant;
+use crate::filter::{Filter, RowSet};
+use crate::hnsw::HnswParams;
+use crate::pq::PqSettings;
+use crate::top_k::{ScoredRow, TopK};
+use crate::vector_ops::normalize_vector;
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use serde_json::Value;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// Queries `vekta search` evaluates together. 64 queries of 1024 f32
+/// dimensions take 256 KiB, so they stay in cache while the stored rows
+/// stream past.
+pub const BATCH_SIZE: usize = 64;
 
 pub struct SearchTimings {
     pub search_duration: std::time::Duration,
@@ -16,160 +26,416 @@ pub struct SearchResult {
     pub similarity: Number,
     pub label: String,
     pub unique_id: String,
-    pub metadata: Metadata,
+    pub metadata: Value,
+}
+
+/// One query of a batch: the vector and an optional metadata filter.
+pub struct Query {
+    pub vector: Vec<Number>,
+    pub filter: Option<Filter>,
 }
 
 pub struct SearchEngine {
     pub db: VectorDatabase,
-    ann_index: Option<RandomProjectionIndex>,
+    pool: rayon::ThreadPool,
+    /// Live rows, listed on first use; the engine never writes, so they hold
+    /// for every query it runs
+    live_rows: OnceLock<Vec<usize>>,
 }
 
 impl SearchEngine {
-    pub fn new(db: VectorDatabase, state: &State) -> Result<Self> {
-        let ann_index = if state.search_method == "ann" || state.search_method == "hybrid" {
-            Some(Self::initialize_ann_index(&db, state)?)
-        } else {
-            None
-        };
-
-        Ok(Self { db, ann_index })
-    }
-
-    fn initialize_ann_index(db: &VectorDatabase, state: &State) -> Result<RandomProjectionIndex> {
-        let data_size = db.count()?;
-        let index = RandomProjectionIndex::new(state.dimensions, data_size, db);
-
-        println!("Debug: Initializing ANN index");
-        let mut count = 0;
-        for i in 0..data_size {
-            if let Some(entry) = db.get_entry_by_index(i)? {
-                // Vectors should already be normalized in the database
-                index.add(entry.vector.clone(), i);
-                count += 1;
-            }
+    pub fn new(mut db: VectorDatabase, state: &State) -> Result<Self> {
+        let uses_ann = state.search_method == "ann" || state.search_method == "hybrid";
+        if uses_ann && db.ann_index().is_none() {
+            verbose_print("No persisted ANN index found, building one");
+            let count = db.rebuild_ann_index()?;
+            verbose_print(&format!("Added {} vectors to ANN index", count));
+        }
+        if state.search_method == "hnsw" && db.hnsw_index().is_none() {
+            verbose_print("No persisted HNSW index found, building one");
+            let count = db.rebuild_hnsw_index(HnswParams::from_state(state))?;
+            verbose_print(&format!("Added {} vectors to HNSW index", count));
+        }
+        if state.search_method == "ivf" && db.ivf_index().is_none() && db.count()? > 0 {
+            verbose_print("No trained IVF index found, training one");
+            let count = db.train_ivf_index(state.ivf_nlist, PqSettings::from_state(state))?;
+            verbose_print(&format!("Trained IVF index over {} vectors", count));
+        }
+        if state.search_method == "binary" && db.binary_codes().is_none() {
+            verbose_print("No persisted binary index found, building one");
+            let count = db.rebuild_binary_index()?;
+            verbose_print(&format!("Added {} vectors to binary index", count));
         }
-        println!("Debug: Added {} vectors to ANN index", count);
 
-        index.print_hash_tables();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(state.threads)
+            .build()
+            .context("Failed to build search thread pool")?;
 
-        Ok(index)
+        Ok(Self {
+            db,
+            pool,
+            live_rows: OnceLock::new(),
+        })
     }
 
-    pub fn search(
+    /// Run a batch of queries against the same engine. Filters are resolved
+    /// to row sets in one pass over the records. Exact searches scan the
+    /// stored vectors once for the whole batch; the other methods run the
+    /// queries in parallel. Results come back in query order.
+    pub fn search_batch(
         &self,
-        query_vector: &[Number],
+        queries: &[Query],
         state: &State,
-    ) -> Result<(Vec<SearchResult>, SearchTimings)> {
+    ) -> Result<Vec<(Vec<SearchResult>, SearchTimings)>> {
         let start = Instant::now();
 
-        println!("Debug: Using search method: {}", state.search_method);
+        verbose_print(&format!(
+            "Using search method: {} for {} queries",
+            state.search_method,
+            queries.len()
+        ));
 
-        // Normalize the query vector once
-        let mut normalized_query = query_vector.to_vec();
-        normalize_vector(&mut normalized_query);
+        // Normalize the query vectors once, if the metric works on normalized vectors
+        let metric = self.db.metric();
+        let mut normalized_queries: Vec<Vec<Number>> =
+            queries.iter().map(|query| query.vector.clone()).collect();
+        if metric.normalizes() {
+            normalized_queries
+                .iter_mut()
+                .for_each(|q| normalize_vector(q));
+        }
 
-        let all_similarities = match state.search_method.as_str() {
-            "exact" => self.exact_search(&normalized_query)?,
-            "ann" => self.ann_search(&normalized_query, state.top_k)?,
-            "hybrid" => self.hybrid_search(&normalized_query, state)?,
-            _ => anyhow::bail!("Unknown search method: {}", state.search_method),
+        let filters: Vec<&Filter> = queries.iter().filter_map(|q| q.filter.as_ref()).collect();
+        let mut matching = self.db.matching_rows(&filters)?.into_iter();
+        let row_sets: Vec<Option<RowSet>> = queries
+            .iter()
+            .map(|query| query.filter.as_ref().and_then(|_| matching.next()))
+            .collect();
+        for rows in row_sets.iter().flatten() {
+            verbose_print(&format!("Filter matched {} rows", rows.len()));
+        }
+        let row_sets: Vec<Option<&RowSet>> = row_sets.iter().map(Option::as_ref).collect();
+
+        let best: Vec<(TopK, Duration)> = if state.search_method == "exact" {
+            let best = self.exact_search_batch(&normalized_queries, &row_sets, state)?;
+            // The queries share one scan, so they share its duration
+            let elapsed = start.elapsed();
+            best.into_iter().map(|best| (best, elapsed)).collect()
+        } else {
+            self.pool.install(|| {
+                normalized_queries
+                    .par_iter()
+                    .zip(&row_sets)
+                    .map(|(query, &filter)| {
+                        let query_start = Instant::now();
+                        let best = self.search_one(query, filter, state)?;
+                        Ok((best, query_start.elapsed()))
+                    })
+                    .collect::<Result<_>>()
+            })?
         };
 
-        let sort_start = Instant::now();
-        let results = self.sort_and_limit_results(all_similarities, state.top_k);
-        let sort_duration = sort_start.elapsed();
+        let mut outcomes = Vec::with_capacity(best.len());
+        for (best, query_duration) in best {
+            let sort_start = Instant::now();
+            let top_rows = best.into_sorted_vec();
+            let results = self.materialize_results(&top_rows)?;
+            let sort_duration = sort_start.elapsed();
 
-        let search_duration = start.elapsed();
-        println!("Debug: Search completed. Found {} results", results.len());
+            let timings = SearchTimings {
+                search_duration: query_duration + sort_duration,
+                sort_duration,
+                total_duration: start.elapsed(),
+            };
+            outcomes.push((results, timings));
+        }
+        verbose_print(&format!("Search completed for {} queries", outcomes.len()));
 
-        let timings = SearchTimings {
-            search_duration,
-            sort_duration,
-            total_duration: start.elapsed(),
-        };
+        Ok(outcomes)
+    }
+
+    fn search_one(
+        &self,
+        query_vector: &[Number],
+        filter: Option<&RowSet>,
+        state: &State,
+    ) -> Result<TopK> {
+        match state.search_method.as_str() {
+            "exact" => self.exact_search(query_vector, filter, state),
+            "ann" => self.ann_search(query_vector, filter, state),
+            "hybrid" => self.hybrid_search(query_vector, filter, state),
+            "hnsw" => self.hnsw_search(query_vector, filter, state),
+            "ivf" => self.ivf_search(query_vector, filter, state),
+            "binary" => self.binary_search(query_vector, filter, state),
+            _ => anyhow::bail!("Unknown search method: {}", state.search_method),
+        }
+    }
+
+    fn exact_search(
+        &self,
+        query_vector: &[Number],
+        filter: Option<&RowSet>,
+        state: &State,
+    ) -> Result<TopK> {
+        let mut best = self.exact_search_batch(&[query_vector], &[filter], state)?;
+        Ok(best.remove(0))
+    }
 
-        Ok((results, timings))
+    /// Brute-force scan of every live row, split across the thread pool. Each
+    /// row is scored against every query while it is in cache, so a batch
+    /// costs one pass over the arena; a query with a filter skips rows outside
+    /// it. Each worker keeps only the best `top_k` rows per query, and the
+    /// partial heaps are merged.
+    fn exact_search_batch<Q: AsRef<[Number]> + Sync>(
+        &self,
+        query_vectors: &[Q],
+        filters: &[Option<&RowSet>],
+        state: &State,
+    ) -> Result<Vec<TopK>> {
+        verbose_print("Performing exact search");
+        let vectors = self.db.vectors()?;
+        let metric = self.db.metric();
+        let empty = || {
+            query_vectors
+                .iter()
+                .map(|_| TopK::new(state.top_k, state.similarity_threshold))
+                .collect::<Vec<_>>()
+        };
+        let results = self.pool.install(|| {
+            vectors
+                .rows
+                .par_iter()
+                .fold(empty, |mut best, &row| {
+                    if let Some(vector) = vectors.get(row) {
+                        let queries = query_vectors.iter().zip(filters).zip(best.iter_mut());
+                        for ((query, filter), best) in queries {
+                            if filter.is_some_and(|filter| !filter.contains(row)) {
+                                continue;
+                            }
+                            if let Some(score) = metric.score_stored(query.as_ref(), &vector) {
+                                best.push(row, score);
+                            }
+                        }
+                    }
+                    best
+                })
+                .reduce(empty, |a, b| {
+                    a.into_iter().zip(b).map(|(a, b)| a.merge(b)).collect()
+                })
+        });
+        Ok(results)
     }
 
-    fn exact_search(&self, query_vector: &[Number]) -> Result<Vec<SearchResult>> {
-        println!("Debug: Performing exact search");
-        let mut results = Vec::new();
-        for i in 0..self.db.count()? {
-            if let Some(entry) = self.db.get_entry_by_index(i)? {
-                if let Some(result) = self.compute_similarity(query_vector, &entry) {
-                    results.push(result);
-                }
+    /// Score the rows sharing a bucket with the query; a filter is applied to
+    /// the candidates before scoring.
+    fn ann_search(
+        &self,
+        query_vector: &[Number],
+        filter: Option<&RowSet>,
+        state: &State,
+    ) -> Result<TopK> {
+        verbose_print("Performing ANN search");
+        let mut candidates = self.db.ann_candidates(query_vector)?;
+        if let Some(filter) = filter {
+            candidates.retain(|&row| filter.contains(row));
+        }
+        verbose_print(&format!(
+            "ANN search returned {} candidate indices",
+            candidates.len()
+        ));
+
+        let metric = self.db.metric();
+        let mut best = TopK::new(state.top_k, state.similarity_threshold);
+        for row in candidates {
+            let score = self
+                .db
+                .stored_vector(row)
+                .and_then(|vector| metric.score_stored(query_vector, &vector));
+            if let Some(score) = score {
+                best.push(row, score);
             }
         }
-        Ok(results)
+        Ok(best)
     }
 
-    fn ann_search(&self, query_vector: &[Number], top_k: usize) -> Result<Vec<SearchResult>> {
-        println!("Debug: Performing ANN search");
-        let mut results = Vec::new();
-        if let Some(index) = &self.ann_index {
-            let candidate_indices = index.search(query_vector.to_vec(), top_k);
-            println!(
-                "Debug: ANN search returned {} candidate indices",
-                candidate_indices.len()
-            );
-            for &i in &candidate_indices {
-                if let Some(entry) = self.db.get_entry_by_index(i)? {
-                    if let Some(result) = self.compute_similarity(query_vector, &entry) {
-                        results.push(result);
-                    }
-                }
+    /// Score every row in `rows` and nothing else.
+    fn scan_rows(&self, query_vector: &[Number], rows: &RowSet, state: &State) -> TopK {
+        let metric = self.db.metric();
+        let mut best = TopK::new(state.top_k, state.similarity_threshold);
+        for row in rows.iter() {
+            let score = self
+                .db
+                .stored_vector(row)
+                .and_then(|vector| metric.score_stored(query_vector, &vector));
+            if let Some(score) = score {
+                best.push(row, score);
             }
-        } else {
-            anyhow::bail!("ANN index not initialized");
         }
-        Ok(results)
+        best
     }
 
-    fn hybrid_search(&self, query_vector: &[Number], state: &State) -> Result<Vec<SearchResult>> {
-        println!("Debug: Performing hybrid search");
+    fn hybrid_search(
+        &self,
+        query_vector: &[Number],
+        filter: Option<&RowSet>,
+        state: &State,
+    ) -> Result<TopK> {
+        verbose_print("Performing hybrid search");
 
-        let mut results = self.ann_search(query_vector, state.top_k)?;
+        let results = self.ann_search(query_vector, filter, state)?;
 
         if results.len() < state.top_k {
-            println!("Debug: ANN search found fewer than top_k results, performing exact search");
-            results.extend(self.exact_search(query_vector)?);
+            verbose_print("ANN search found fewer than top_k results, performing exact search");
+            return self.exact_search(query_vector, filter, state);
         }
 
         Ok(results)
     }
 
-    fn compute_similarity(
+    /// Walk the HNSW graph. A filter whose rows are few compared to the graph
+    /// would make the walk pass through most of it to collect enough of them,
+    /// so its rows are scored directly instead: the walk visits about
+    /// `ef * nodes / rows` nodes, and the scan `rows`.
+    fn hnsw_search(
         &self,
         query_vector: &[Number],
-        entry: &VectorEntry,
-    ) -> Option<SearchResult> {
-        // Assume entry.vector is already normalized
-        compute_cosine_similarity_simd(query_vector, &entry.vector).map(|similarity| {
-            println!(
-                "Debug: Similarity for entry {}: {}",
-                entry.label, similarity
-            );
-            SearchResult {
-                similarity,
-                label: entry.label.clone(),
-                unique_id: entry.unique_id.clone(),
-                metadata: entry.metadata.clone(),
+        filter: Option<&RowSet>,
+        state: &State,
+    ) -> Result<TopK> {
+        if let Some(filter) = filter {
+            let ef = state.hnsw_ef_search.max(state.top_k);
+            if filter.len().saturating_mul(filter.len()) <= ef.saturating_mul(self.db.count()?) {
+                verbose_print("Filter is selective, scoring its rows instead of walking HNSW");
+                return Ok(self.scan_rows(query_vector, filter, state));
             }
-        })
+        }
+
+        verbose_print("Performing HNSW search");
+        let found = self
+            .db
+            .hnsw_search(query_vector, state.top_k, state.hnsw_ef_search, filter)?;
+
+        let mut best = TopK::new(state.top_k, state.similarity_threshold);
+        for (row, score) in found {
+            best.push(row, score);
+        }
+        Ok(best)
+    }
+
+    /// Scan only the rows in the `ivf_nprobe` lists nearest to the query. With
+    /// PQ codes, the lists are scanned by code and only the best candidates are
+    /// re-ranked against the full-precision vectors. A filter is applied to the
+    /// list members before either.
+    fn ivf_search(
+        &self,
+        query_vector: &[Number],
+        filter: Option<&RowSet>,
+        state: &State,
+    ) -> Result<TopK> {
+        verbose_print("Performing IVF search");
+        if self.db.ivf_index().is_none() {
+            // Nothing has been stored yet, so there was nothing to train on
+            return Ok(TopK::new(state.top_k, state.similarity_threshold));
+        }
+        let candidates = match self.db.ivf_pq() {
+            Some(pq) => self.db.ivf_pq_candidates(
+                query_vector,
+                state.ivf_nprobe,
+                pq.rerank_factor * state.top_k,
+                filter,
+            )?,
+            None => self
+                .db
+                .ivf_candidates(query_vector, state.ivf_nprobe, filter)?,
+        };
+        verbose_print(&format!(
+            "IVF search scanning {} candidate rows",
+            candidates.len()
+        ));
+
+        self.rerank(&candidates, query_vector, state)
     }
 
-    fn sort_and_limit_results(
+    /// Hamming scan over the sign-bit codes of every live row in the filter,
+    /// keeping `binary_oversample * top_k` candidates to re-rank at full
+    /// precision.
+    fn binary_search(
         &self,
-        mut results: Vec<SearchResult>,
-        limit: usize,
-    ) -> Vec<SearchResult> {
-        results.sort_by(|a, b| {
-            b.similarity
-                .partial_cmp(&a.similarity)
-                .unwrap_or(std::cmp::Ordering::Equal)
+        query_vector: &[Number],
+        filter: Option<&RowSet>,
+        state: &State,
+    ) -> Result<TopK> {
+        verbose_print("Performing binary search");
+        let depth = state.binary_oversample.max(1) * state.top_k;
+        // A filter already lists the live rows it matches
+        let filtered: Vec<usize>;
+        let rows = match filter {
+            Some(filter) => {
+                filtered = filter.iter().collect();
+                &filtered
+            }
+            None => self.live_rows()?,
+        };
+        let candidates = self
+            .pool
+            .install(|| self.db.binary_candidates(query_vector, rows, depth))?;
+        verbose_print(&format!(
+            "Binary search re-ranking {} candidate rows",
+            candidates.len()
+        ));
+        self.rerank(&candidates, query_vector, state)
+    }
+
+    fn live_rows(&self) -> Result<&[usize]> {
+        if self.live_rows.get().is_none() {
+            let rows = self.db.live_rows()?;
+            let _ = self.live_rows.set(rows);
+        }
+        Ok(self.live_rows.get().map_or(&[], Vec::as_slice))
+    }
+
+    /// Score candidate rows against the stored vectors, split across the
+    /// thread pool.
+    fn rerank(&self, candidates: &[usize], query_vector: &[Number], state: &State) -> Result<TopK> {
+        let metric = self.db.metric();
+        let results = self.pool.install(|| {
+            candidates
+                .par_iter()
+                .fold(
+                    || TopK::new(state.top_k, state.similarity_threshold),
+                    |mut best, &row| {
+                        let score = self
+                            .db
+                            .stored_vector(row)
+                            .and_then(|vector| metric.score_stored(query_vector, &vector));
+                        if let Some(score) = score {
+                            best.push(row, score);
+                        }
+                        best
+                    },
+                )
+                .reduce(
+                    || TopK::new(state.top_k, state.similarity_threshold),
+                    TopK::merge,
+                )
         });
-        results.truncate(limit);
-        results
+        Ok(results)
+    }
+
+    /// Look up labels and metadata for the winning rows only.
+    fn materialize_results(&self, rows: &[ScoredRow]) -> Result<Vec<SearchResult>> {
+        let mut results = Vec::with_capacity(rows.len());
+        for &(row, similarity) in rows {
+            if let Some(record) = self.db.get_record_by_row(row)? {
+                results.push(SearchResult {
+                    similarity,
+                    label: record.label,
+                    unique_id: record.unique_id,
+                    metadata: record.metadata,
+                });
+            }
+        }
+        Ok(results)
     }
 }
@@ -0,0 +1,1230 @@
+//! Similarity kernels for each SIMD instruction set, picked once at startup
+//! from the features the CPU reports. Every level has kernels for each storage
+//! precision, which widen the stored row to f32 lanes as they load it, so
+//! quantized vectors are scored in place without a decoding pass.
+
+use std::fmt;
+use std::ops::Add;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use half::{bf16, f16};
+
+use crate::config::Number;
+
+/// Instruction sets with their own kernels, from least to most capable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SimdLevel {
+    /// Portable Rust with eight independent accumulators
+    Scalar,
+    /// 128-bit SSE2
+    Sse,
+    /// 256-bit AVX2 with fused multiply-add and F16C half conversion
+    Avx2,
+    /// 512-bit AVX-512F
+    Avx512,
+}
+
+impl SimdLevel {
+    pub const ALL: [SimdLevel; 4] = [
+        SimdLevel::Scalar,
+        SimdLevel::Sse,
+        SimdLevel::Avx2,
+        SimdLevel::Avx512,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SimdLevel::Scalar => "scalar",
+            SimdLevel::Sse => "sse",
+            SimdLevel::Avx2 => "avx2",
+            SimdLevel::Avx512 => "avx512",
+        }
+    }
+
+    /// The most capable level this CPU supports.
+    pub fn detected() -> SimdLevel {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx512f") {
+                return SimdLevel::Avx512;
+            }
+            if is_x86_feature_detected!("avx2")
+                && is_x86_feature_detected!("fma")
+                && is_x86_feature_detected!("f16c")
+            {
+                return SimdLevel::Avx2;
+            }
+            if is_x86_feature_detected!("sse2") {
+                return SimdLevel::Sse;
+            }
+        }
+        SimdLevel::Scalar
+    }
+
+    pub fn is_supported(&self) -> bool {
+        *self <= SimdLevel::detected()
+    }
+}
+
+impl fmt::Display for SimdLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for SimdLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "scalar" => Ok(SimdLevel::Scalar),
+            "sse" => Ok(SimdLevel::Sse),
+            "avx2" => Ok(SimdLevel::Avx2),
+            "avx512" => Ok(SimdLevel::Avx512),
+            _ => anyhow::bail!(
+                "Unknown SIMD level '{}': expected scalar, sse, avx2 or avx512",
+                s
+            ),
+        }
+    }
+}
+
+/// The three sums cosine similarity is computed from.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CosineParts {
+    pub dot: Number,
+    pub a_squared: Number,
+    pub b_squared: Number,
+}
+
+impl Add for CosineParts {
+    type Output = CosineParts;
+
+    fn add(self, other: CosineParts) -> CosineParts {
+        CosineParts {
+            dot: self.dot + other.dot,
+            a_squared: self.a_squared + other.a_squared,
+            b_squared: self.b_squared + other.b_squared,
+        }
+    }
+}
+
+/// A float type vectors can be stored as.
+pub trait Element: Copy + Default {
+    fn to_number(self) -> Number;
+}
+
+impl Element for Number {
+    fn to_number(self) -> Number {
+        self
+    }
+}
+
+impl Element for f16 {
+    fn to_number(self) -> Number {
+        self.to_f32()
+    }
+}
+
+impl Element for bf16 {
+    fn to_number(self) -> Number {
+        self.to_f32()
+    }
+}
+
+pub type SumKernel<T = Number> = fn(&[Number], &[T]) -> Number;
+pub type CosineKernel<T = Number> = fn(&[Number], &[T]) -> CosineParts;
+
+/// Kernels for rows stored as `T`. Both slices must have the same length.
+pub struct RowKernels<T> {
+    pub dot: SumKernel<T>,
+    /// Squared Euclidean distance
+    pub l2_squared: SumKernel<T>,
+    pub l1: SumKernel<T>,
+    pub cosine: CosineKernel<T>,
+}
+
+pub type Int8SumKernel = fn(&[Number], &[i8], &[Number]) -> Number;
+
+/// Kernels for int8 rows, where each code stands for `code * step`. `steps`
+/// holds a single step for the whole row, or one per dimension for `int8_dim`.
+pub struct Int8Kernels {
+    pub dot: Int8SumKernel,
+    /// Squared Euclidean distance
+    pub l2_squared: Int8SumKernel,
+    pub l1: Int8SumKernel,
+    pub cosine: fn(&[Number], &[i8], &[Number]) -> CosineParts,
+}
+
+/// One instruction set's kernels. Both slices must have the same length.
+pub struct Kernels {
+    pub level: SimdLevel,
+    pub dot: SumKernel,
+    /// Squared Euclidean distance
+    pub l2_squared: SumKernel,
+    pub l1: SumKernel,
+    pub cosine: CosineKernel,
+    pub f16: RowKernels<f16>,
+    pub bf16: RowKernels<bf16>,
+    /// Per-vector int8 rows
+    pub int8: Int8Kernels,
+    /// Per-dimension int8 rows
+    pub int8_dim: Int8Kernels,
+}
+
+impl Kernels {
+    /// The kernels for `level`, or `None` if this CPU cannot run them.
+    pub fn for_level(level: SimdLevel) -> Option<Kernels> {
+        if !level.is_supported() {
+            return None;
+        }
+        #[cfg(target_arch = "x86_64")]
+        {
+            match level {
+                SimdLevel::Scalar => {}
+                SimdLevel::Sse => return Some(sse::KERNELS),
+                SimdLevel::Avx2 => return Some(avx2::KERNELS),
+                SimdLevel::Avx512 => return Some(avx512::KERNELS),
+            }
+        }
+        Some(scalar::KERNELS)
+    }
+}
+
+/// The kernels every search uses: the detected level, or a lower one requested
+/// with `VEKTA_SIMD`.
+pub fn kernels() -> &'static Kernels {
+    static KERNELS: OnceLock<Kernels> = OnceLock::new();
+    KERNELS.get_or_init(|| {
+        let detected = SimdLevel::detected();
+        let level = match std::env::var("VEKTA_SIMD") {
+            Ok(requested) => match requested.parse::<SimdLevel>() {
+                Ok(level) if level.is_supported() => level,
+                Ok(level) => {
+                    eprintln!(
+                        "Warning: this CPU does not support {}; using {}.",
+                        level, detected
+                    );
+                    detected
+                }
+                Err(e) => {
+                    eprintln!("Warning: {}; using {}.", e, detected);
+                    detected
+                }
+            },
+            Err(_) => detected,
+        };
+        Kernels::for_level(level).unwrap_or(scalar::KERNELS)
+    })
+}
+
+/// Print the CPU features the kernels depend on and the level in use.
+pub fn print_cpu_info() {
+    println!("arch={}", std::env::consts::ARCH);
+    #[cfg(target_arch = "x86_64")]
+    {
+        let features = [
+            ("sse", is_x86_feature_detected!("sse")),
+            ("sse2", is_x86_feature_detected!("sse2")),
+            ("avx", is_x86_feature_detected!("avx")),
+            ("avx2", is_x86_feature_detected!("avx2")),
+            ("fma", is_x86_feature_detected!("fma")),
+            ("f16c", is_x86_feature_detected!("f16c")),
+            ("avx512f", is_x86_feature_detected!("avx512f")),
+        ];
+        for (name, present) in features {
+            println!("{}={}", name, if present { "yes" } else { "no" });
+        }
+    }
+    println!("detected={}", SimdLevel::detected());
+    println!("active={}", kernels().level);
+    let supported: Vec<&str> = SimdLevel::ALL
+        .iter()
+        .filter(|level| level.is_supported())
+        .map(SimdLevel::as_str)
+        .collect();
+    println!("supported={}", supported.join(","));
+}
+
+/// The reference implementation, also used on CPUs without SIMD support.
+/// Eight accumulators let the compiler vectorize it for the baseline target.
+pub mod scalar {
+    use super::{CosineParts, Element, Int8Kernels, Kernels, RowKernels, SimdLevel};
+    use crate::config::Number;
+
+    const LANES: usize = 8;
+
+    pub const KERNELS: Kernels = Kernels {
+        level: SimdLevel::Scalar,
+        dot,
+        l2_squared,
+        l1,
+        cosine,
+        f16: RowKernels {
+            dot,
+            l2_squared,
+            l1,
+            cosine,
+        },
+        bf16: RowKernels {
+            dot,
+            l2_squared,
+            l1,
+            cosine,
+        },
+        int8: int8_kernels::<false>(),
+        int8_dim: int8_kernels::<true>(),
+    };
+
+    const fn int8_kernels<const PER_DIM: bool>() -> Int8Kernels {
+        Int8Kernels {
+            dot: dot_int8::<PER_DIM>,
+            l2_squared: l2_squared_int8::<PER_DIM>,
+            l1: l1_int8::<PER_DIM>,
+            cosine: cosine_int8::<PER_DIM>,
+        }
+    }
+
+    fn fold<T: Element>(a: &[Number], b: &[T], f: impl Fn(Number, Number) -> Number) -> Number {
+        let mut lanes = [0.0; LANES];
+        let (a_chunks, b_chunks) = (a.chunks_exact(LANES), b.chunks_exact(LANES));
+        let tail: Number = a_chunks
+            .remainder()
+            .iter()
+            .zip(b_chunks.remainder())
+            .map(|(&x, &y)| f(x, y.to_number()))
+            .sum();
+        for (a_chunk, b_chunk) in a_chunks.zip(b_chunks) {
+            for lane in 0..LANES {
+                lanes[lane] += f(a_chunk[lane], b_chunk[lane].to_number());
+            }
+        }
+        lanes.iter().sum::<Number>() + tail
+    }
+
+    pub fn dot<T: Element>(a: &[Number], b: &[T]) -> Number {
+        fold(a, b, |x, y| x * y)
+    }
+
+    pub fn l2_squared<T: Element>(a: &[Number], b: &[T]) -> Number {
+        fold(a, b, |x, y| (x - y) * (x - y))
+    }
+
+    pub fn l1<T: Element>(a: &[Number], b: &[T]) -> Number {
+        fold(a, b, |x, y| (x - y).abs())
+    }
+
+    pub fn cosine<T: Element>(a: &[Number], b: &[T]) -> CosineParts {
+        CosineParts {
+            dot: dot(a, b),
+            a_squared: dot(a, a),
+            b_squared: fold(a, b, |_, y| y * y),
+        }
+    }
+
+    /// The steps for the dimensions from `start` on.
+    pub fn steps_from<const PER_DIM: bool>(steps: &[Number], start: usize) -> &[Number] {
+        if PER_DIM {
+            &steps[start..]
+        } else {
+            steps
+        }
+    }
+
+    fn fold_int8<const PER_DIM: bool>(
+        a: &[Number],
+        codes: &[i8],
+        steps: &[Number],
+        f: impl Fn(Number, Number) -> Number,
+    ) -> Number {
+        let value = |i: usize| {
+            let step = if PER_DIM { steps[i] } else { steps[0] };
+            codes[i] as Number * step
+        };
+        let mut lanes = [0.0; LANES];
+        let end = a.len() - a.len() % LANES;
+        for i in (0..end).step_by(LANES) {
+            for lane in 0..LANES {
+                lanes[lane] += f(a[i + lane], value(i + lane));
+            }
+        }
+        let tail: Number = (end..a.len()).map(|i| f(a[i], value(i))).sum();
+        lanes.iter().sum::<Number>() + tail
+    }
+
+    pub fn dot_int8<const PER_DIM: bool>(a: &[Number], codes: &[i8], steps: &[Number]) -> Number {
+        fold_int8::<PER_DIM>(a, codes, steps, |x, y| x * y)
+    }
+
+    pub fn l2_squared_int8<const PER_DIM: bool>(
+        a: &[Number],
+        codes: &[i8],
+        steps: &[Number],
+    ) -> Number {
+        fold_int8::<PER_DIM>(a, codes, steps, |x, y| (x - y) * (x - y))
+    }
+
+    pub fn l1_int8<const PER_DIM: bool>(a: &[Number], codes: &[i8], steps: &[Number]) -> Number {
+        fold_int8::<PER_DIM>(a, codes, steps, |x, y| (x - y).abs())
+    }
+
+    pub fn cosine_int8<const PER_DIM: bool>(
+        a: &[Number],
+        codes: &[i8],
+        steps: &[Number],
+    ) -> CosineParts {
+        CosineParts {
+            dot: dot_int8::<PER_DIM>(a, codes, steps),
+            a_squared: dot(a, a),
+            b_squared: fold_int8::<PER_DIM>(a, codes, steps, |_, y| y * y),
+        }
+    }
+}
+
+/// Half floats are widened with integer arithmetic, since F16C needs AVX.
+#[cfg(target_arch = "x86_64")]
+mod sse {
+    use std::arch::x86_64::*;
+
+    use half::{bf16, f16};
+
+    use super::scalar::steps_from;
+    use super::{CosineParts, Element, Int8Kernels, Kernels, RowKernels, SimdLevel};
+    use crate::config::Number;
+
+    pub const KERNELS: Kernels = Kernels {
+        level: SimdLevel::Sse,
+        dot: |a, b| unsafe { dot(a, b) },
+        l2_squared: |a, b| unsafe { l2_squared(a, b) },
+        l1: |a, b| unsafe { l1(a, b) },
+        cosine: |a, b| unsafe { cosine(a, b) },
+        f16: row_kernels(),
+        bf16: row_kernels(),
+        int8: int8_kernels::<false>(),
+        int8_dim: int8_kernels::<true>(),
+    };
+
+    const fn row_kernels<T: Widen>() -> RowKernels<T> {
+        RowKernels {
+            dot: |a, b| unsafe { dot(a, b) },
+            l2_squared: |a, b| unsafe { l2_squared(a, b) },
+            l1: |a, b| unsafe { l1(a, b) },
+            cosine: |a, b| unsafe { cosine(a, b) },
+        }
+    }
+
+    const fn int8_kernels<const PER_DIM: bool>() -> Int8Kernels {
+        Int8Kernels {
+            dot: |a, codes, steps| unsafe { dot_int8::<PER_DIM>(a, codes, steps) },
+            l2_squared: |a, codes, steps| unsafe { l2_squared_int8::<PER_DIM>(a, codes, steps) },
+            l1: |a, codes, steps| unsafe { l1_int8::<PER_DIM>(a, codes, steps) },
+            cosine: |a, codes, steps| unsafe { cosine_int8::<PER_DIM>(a, codes, steps) },
+        }
+    }
+
+    const WIDTH: usize = 4;
+
+    /// Element types read four at a time as f32 lanes.
+    trait Widen: Element {
+        /// Elements `i..i + 4` of `b`
+        unsafe fn lanes(b: &[Self], i: usize) -> __m128;
+    }
+
+    impl Widen for Number {
+        #[target_feature(enable = "sse2")]
+        unsafe fn lanes(b: &[Number], i: usize) -> __m128 {
+            _mm_loadu_ps(b.as_ptr().add(i))
+        }
+    }
+
+    impl Widen for f16 {
+        /// Moves exponent and mantissa into f32 position and rebiases the
+        /// exponent; infinities and NaNs get the f32 maximum exponent, and
+        /// subnormals are renormalized by subtracting 2^-14.
+        #[target_feature(enable = "sse2")]
+        unsafe fn lanes(b: &[f16], i: usize) -> __m128 {
+            let halves = _mm_loadl_epi64(b.as_ptr().add(i).cast());
+            let bits = _mm_unpacklo_epi16(halves, _mm_setzero_si128());
+            let sign = _mm_slli_epi32(_mm_and_si128(bits, _mm_set1_epi32(0x8000)), 16);
+            let magnitude = _mm_slli_epi32(_mm_and_si128(bits, _mm_set1_epi32(0x7fff)), 13);
+            let exponent = _mm_and_si128(magnitude, _mm_set1_epi32(0x0f80_0000));
+            let rebias = _mm_set1_epi32(0x3800_0000);
+            let mut value = _mm_add_epi32(magnitude, rebias);
+            let special = _mm_cmpeq_epi32(exponent, _mm_set1_epi32(0x0f80_0000));
+            value = _mm_add_epi32(value, _mm_and_si128(special, rebias));
+            let subnormal = _mm_cmpeq_epi32(exponent, _mm_setzero_si128());
+            let renormalized = _mm_castps_si128(_mm_sub_ps(
+                _mm_castsi128_ps(_mm_add_epi32(value, _mm_set1_epi32(1 << 23))),
+                _mm_castsi128_ps(_mm_set1_epi32(0x3880_0000)),
+            ));
+            value = _mm_or_si128(
+                _mm_and_si128(subnormal, renormalized),
+                _mm_andnot_si128(subnormal, value),
+            );
+            _mm_castsi128_ps(_mm_or_si128(value, sign))
+        }
+    }
+
+    impl Widen for bf16 {
+        #[target_feature(enable = "sse2")]
+        unsafe fn lanes(b: &[bf16], i: usize) -> __m128 {
+            let halves = _mm_loadl_epi64(b.as_ptr().add(i).cast());
+            _mm_castsi128_ps(_mm_unpacklo_epi16(_mm_setzero_si128(), halves))
+        }
+    }
+
+    /// Codes `i..i + 4` times their steps
+    #[target_feature(enable = "sse2")]
+    unsafe fn int8_lanes<const PER_DIM: bool>(codes: &[i8], steps: &[Number], i: usize) -> __m128 {
+        let bytes = _mm_cvtsi32_si128(codes.as_ptr().add(i).cast::<i32>().read_unaligned());
+        let bytes = _mm_unpacklo_epi8(bytes, bytes);
+        let words = _mm_unpacklo_epi16(bytes, bytes);
+        let values = _mm_cvtepi32_ps(_mm_srai_epi32(words, 24));
+        let step = if PER_DIM {
+            _mm_loadu_ps(steps.as_ptr().add(i))
+        } else {
+            _mm_set1_ps(steps[0])
+        };
+        _mm_mul_ps(values, step)
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn sum(v: __m128) -> Number {
+        let mut lanes = [0.0; WIDTH];
+        _mm_storeu_ps(lanes.as_mut_ptr(), v);
+        lanes.iter().sum()
+    }
+
+    /// Run the loops below over the first `end` lanes, reading `b` through `lanes`.
+    #[target_feature(enable = "sse2")]
+    unsafe fn dot_by(a: &[Number], end: usize, lanes: impl Fn(usize) -> __m128) -> Number {
+        let mut acc = _mm_setzero_ps();
+        for i in (0..end).step_by(WIDTH) {
+            let (x, y) = (_mm_loadu_ps(a.as_ptr().add(i)), lanes(i));
+            acc = _mm_add_ps(acc, _mm_mul_ps(x, y));
+        }
+        sum(acc)
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn l2_squared_by(a: &[Number], end: usize, lanes: impl Fn(usize) -> __m128) -> Number {
+        let mut acc = _mm_setzero_ps();
+        for i in (0..end).step_by(WIDTH) {
+            let (x, y) = (_mm_loadu_ps(a.as_ptr().add(i)), lanes(i));
+            let diff = _mm_sub_ps(x, y);
+            acc = _mm_add_ps(acc, _mm_mul_ps(diff, diff));
+        }
+        sum(acc)
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn l1_by(a: &[Number], end: usize, lanes: impl Fn(usize) -> __m128) -> Number {
+        let sign = _mm_set1_ps(-0.0);
+        let mut acc = _mm_setzero_ps();
+        for i in (0..end).step_by(WIDTH) {
+            let (x, y) = (_mm_loadu_ps(a.as_ptr().add(i)), lanes(i));
+            acc = _mm_add_ps(acc, _mm_andnot_ps(sign, _mm_sub_ps(x, y)));
+        }
+        sum(acc)
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn cosine_by(a: &[Number], end: usize, lanes: impl Fn(usize) -> __m128) -> CosineParts {
+        let (mut ab, mut aa, mut bb) = (_mm_setzero_ps(), _mm_setzero_ps(), _mm_setzero_ps());
+        for i in (0..end).step_by(WIDTH) {
+            let (x, y) = (_mm_loadu_ps(a.as_ptr().add(i)), lanes(i));
+            ab = _mm_add_ps(ab, _mm_mul_ps(x, y));
+            aa = _mm_add_ps(aa, _mm_mul_ps(x, x));
+            bb = _mm_add_ps(bb, _mm_mul_ps(y, y));
+        }
+        CosineParts {
+            dot: sum(ab),
+            a_squared: sum(aa),
+            b_squared: sum(bb),
+        }
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn dot<T: Widen>(a: &[Number], b: &[T]) -> Number {
+        let end = a.len() - a.len() % WIDTH;
+        dot_by(a, end, |i| T::lanes(b, i)) + super::scalar::dot(&a[end..], &b[end..])
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn l2_squared<T: Widen>(a: &[Number], b: &[T]) -> Number {
+        let end = a.len() - a.len() % WIDTH;
+        l2_squared_by(a, end, |i| T::lanes(b, i)) + super::scalar::l2_squared(&a[end..], &b[end..])
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn l1<T: Widen>(a: &[Number], b: &[T]) -> Number {
+        let end = a.len() - a.len() % WIDTH;
+        l1_by(a, end, |i| T::lanes(b, i)) + super::scalar::l1(&a[end..], &b[end..])
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn cosine<T: Widen>(a: &[Number], b: &[T]) -> CosineParts {
+        let end = a.len() - a.len() % WIDTH;
+        cosine_by(a, end, |i| T::lanes(b, i)) + super::scalar::cosine(&a[end..], &b[end..])
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn dot_int8<const PER_DIM: bool>(
+        a: &[Number],
+        codes: &[i8],
+        steps: &[Number],
+    ) -> Number {
+        let end = a.len() - a.len() % WIDTH;
+        let tail = (&a[end..], &codes[end..], steps_from::<PER_DIM>(steps, end));
+        dot_by(a, end, |i| int8_lanes::<PER_DIM>(codes, steps, i))
+            + super::scalar::dot_int8::<PER_DIM>(tail.0, tail.1, tail.2)
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn l2_squared_int8<const PER_DIM: bool>(
+        a: &[Number],
+        codes: &[i8],
+        steps: &[Number],
+    ) -> Number {
+        let end = a.len() - a.len() % WIDTH;
+        let tail = (&a[end..], &codes[end..], steps_from::<PER_DIM>(steps, end));
+        l2_squared_by(a, end, |i| int8_lanes::<PER_DIM>(codes, steps, i))
+            + super::scalar::l2_squared_int8::<PER_DIM>(tail.0, tail.1, tail.2)
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn l1_int8<const PER_DIM: bool>(a: &[Number], codes: &[i8], steps: &[Number]) -> Number {
+        let end = a.len() - a.len() % WIDTH;
+        let tail = (&a[end..], &codes[end..], steps_from::<PER_DIM>(steps, end));
+        l1_by(a, end, |i| int8_lanes::<PER_DIM>(codes, steps, i))
+            + super::scalar::l1_int8::<PER_DIM>(tail.0, tail.1, tail.2)
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn cosine_int8<const PER_DIM: bool>(
+        a: &[Number],
+        codes: &[i8],
+        steps: &[Number],
+    ) -> CosineParts {
+        let end = a.len() - a.len() % WIDTH;
+        let tail = (&a[end..], &codes[end..], steps_from::<PER_DIM>(steps, end));
+        cosine_by(a, end, |i| int8_lanes::<PER_DIM>(codes, steps, i))
+            + super::scalar::cosine_int8::<PER_DIM>(tail.0, tail.1, tail.2)
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx2 {
+    use std::arch::x86_64::*;
+
+    use half::{bf16, f16};
+
+    use super::scalar::steps_from;
+    use super::{CosineParts, Element, Int8Kernels, Kernels, RowKernels, SimdLevel};
+    use crate::config::Number;
+
+    pub const KERNELS: Kernels = Kernels {
+        level: SimdLevel::Avx2,
+        dot: |a, b| unsafe { dot(a, b) },
+        l2_squared: |a, b| unsafe { l2_squared(a, b) },
+        l1: |a, b| unsafe { l1(a, b) },
+        cosine: |a, b| unsafe { cosine(a, b) },
+        f16: row_kernels(),
+        bf16: row_kernels(),
+        int8: int8_kernels::<false>(),
+        int8_dim: int8_kernels::<true>(),
+    };
+
+    const fn row_kernels<T: Widen>() -> RowKernels<T> {
+        RowKernels {
+            dot: |a, b| unsafe { dot(a, b) },
+            l2_squared: |a, b| unsafe { l2_squared(a, b) },
+            l1: |a, b| unsafe { l1(a, b) },
+            cosine: |a, b| unsafe { cosine(a, b) },
+        }
+    }
+
+    const fn int8_kernels<const PER_DIM: bool>() -> Int8Kernels {
+        Int8Kernels {
+            dot: |a, codes, steps| unsafe { dot_int8::<PER_DIM>(a, codes, steps) },
+            l2_squared: |a, codes, steps| unsafe { l2_squared_int8::<PER_DIM>(a, codes, steps) },
+            l1: |a, codes, steps| unsafe { l1_int8::<PER_DIM>(a, codes, steps) },
+            cosine: |a, codes, steps| unsafe { cosine_int8::<PER_DIM>(a, codes, steps) },
+        }
+    }
+
+    const WIDTH: usize = 8;
+
+    /// Element types read eight at a time as f32 lanes.
+    trait Widen: Element {
+        /// Elements `i..i + 8` of `b`
+        unsafe fn lanes(b: &[Self], i: usize) -> __m256;
+    }
+
+    impl Widen for Number {
+        #[target_feature(enable = "avx2,fma,f16c")]
+        unsafe fn lanes(b: &[Number], i: usize) -> __m256 {
+            _mm256_loadu_ps(b.as_ptr().add(i))
+        }
+    }
+
+    impl Widen for f16 {
+        #[target_feature(enable = "avx2,fma,f16c")]
+        unsafe fn lanes(b: &[f16], i: usize) -> __m256 {
+            _mm256_cvtph_ps(_mm_loadu_si128(b.as_ptr().add(i).cast()))
+        }
+    }
+
+    impl Widen for bf16 {
+        /// A bf16 is the top half of an f32, so widening is a 16-bit shift.
+        #[target_feature(enable = "avx2,fma,f16c")]
+        unsafe fn lanes(b: &[bf16], i: usize) -> __m256 {
+            let halves = _mm256_cvtepu16_epi32(_mm_loadu_si128(b.as_ptr().add(i).cast()));
+            _mm256_castsi256_ps(_mm256_slli_epi32(halves, 16))
+        }
+    }
+
+    /// Codes `i..i + 8` times their steps
+    #[target_feature(enable = "avx2,fma,f16c")]
+    unsafe fn int8_lanes<const PER_DIM: bool>(codes: &[i8], steps: &[Number], i: usize) -> __m256 {
+        let bytes = _mm_loadl_epi64(codes.as_ptr().add(i).cast());
+        let values = _mm256_cvtepi32_ps(_mm256_cvtepi8_epi32(bytes));
+        let step = if PER_DIM {
+            _mm256_loadu_ps(steps.as_ptr().add(i))
+        } else {
+            _mm256_set1_ps(steps[0])
+        };
+        _mm256_mul_ps(values, step)
+    }
+
+    #[target_feature(enable = "avx2,fma,f16c")]
+    unsafe fn sum(v: __m256) -> Number {
+        let mut lanes = [0.0; WIDTH];
+        _mm256_storeu_ps(lanes.as_mut_ptr(), v);
+        lanes.iter().sum()
+    }
+
+    /// Run the loops below over the first `end` lanes, reading `b` through `lanes`.
+    #[target_feature(enable = "avx2,fma,f16c")]
+    unsafe fn dot_by(a: &[Number], end: usize, lanes: impl Fn(usize) -> __m256) -> Number {
+        let mut acc = _mm256_setzero_ps();
+        for i in (0..end).step_by(WIDTH) {
+            let x = _mm256_loadu_ps(a.as_ptr().add(i));
+            acc = _mm256_fmadd_ps(x, lanes(i), acc);
+        }
+        sum(acc)
+    }
+
+    #[target_feature(enable = "avx2,fma,f16c")]
+    unsafe fn l2_squared_by(a: &[Number], end: usize, lanes: impl Fn(usize) -> __m256) -> Number {
+        let mut acc = _mm256_setzero_ps();
+        for i in (0..end).step_by(WIDTH) {
+            let x = _mm256_loadu_ps(a.as_ptr().add(i));
+            let diff = _mm256_sub_ps(x, lanes(i));
+            acc = _mm256_fmadd_ps(diff, diff, acc);
+        }
+        sum(acc)
+    }
+
+    #[target_feature(enable = "avx2,fma,f16c")]
+    unsafe fn l1_by(a: &[Number], end: usize, lanes: impl Fn(usize) -> __m256) -> Number {
+        let sign = _mm256_set1_ps(-0.0);
+        let mut acc = _mm256_setzero_ps();
+        for i in (0..end).step_by(WIDTH) {
+            let x = _mm256_loadu_ps(a.as_ptr().add(i));
+            acc = _mm256_add_ps(acc, _mm256_andnot_ps(sign, _mm256_sub_ps(x, lanes(i))));
+        }
+        sum(acc)
+    }
+
+    #[target_feature(enable = "avx2,fma,f16c")]
+    unsafe fn cosine_by(a: &[Number], end: usize, lanes: impl Fn(usize) -> __m256) -> CosineParts {
+        let (mut ab, mut aa, mut bb) = (
+            _mm256_setzero_ps(),
+            _mm256_setzero_ps(),
+            _mm256_setzero_ps(),
+        );
+        for i in (0..end).step_by(WIDTH) {
+            let x = _mm256_loadu_ps(a.as_ptr().add(i));
+            let y = lanes(i);
+            ab = _mm256_fmadd_ps(x, y, ab);
+            aa = _mm256_fmadd_ps(x, x, aa);
+            bb = _mm256_fmadd_ps(y, y, bb);
+        }
+        CosineParts {
+            dot: sum(ab),
+            a_squared: sum(aa),
+            b_squared: sum(bb),
+        }
+    }
+
+    #[target_feature(enable = "avx2,fma,f16c")]
+    unsafe fn dot<T: Widen>(a: &[Number], b: &[T]) -> Number {
+        let end = a.len() - a.len() % WIDTH;
+        dot_by(a, end, |i| T::lanes(b, i)) + super::scalar::dot(&a[end..], &b[end..])
+    }
+
+    #[target_feature(enable = "avx2,fma,f16c")]
+    unsafe fn l2_squared<T: Widen>(a: &[Number], b: &[T]) -> Number {
+        let end = a.len() - a.len() % WIDTH;
+        l2_squared_by(a, end, |i| T::lanes(b, i)) + super::scalar::l2_squared(&a[end..], &b[end..])
+    }
+
+    #[target_feature(enable = "avx2,fma,f16c")]
+    unsafe fn l1<T: Widen>(a: &[Number], b: &[T]) -> Number {
+        let end = a.len() - a.len() % WIDTH;
+        l1_by(a, end, |i| T::lanes(b, i)) + super::scalar::l1(&a[end..], &b[end..])
+    }
+
+    #[target_feature(enable = "avx2,fma,f16c")]
+    unsafe fn cosine<T: Widen>(a: &[Number], b: &[T]) -> CosineParts {
+        let end = a.len() - a.len() % WIDTH;
+        cosine_by(a, end, |i| T::lanes(b, i)) + super::scalar::cosine(&a[end..], &b[end..])
+    }
+
+    #[target_feature(enable = "avx2,fma,f16c")]
+    unsafe fn dot_int8<const PER_DIM: bool>(
+        a: &[Number],
+        codes: &[i8],
+        steps: &[Number],
+    ) -> Number {
+        let end = a.len() - a.len() % WIDTH;
+        let tail = (&a[end..], &codes[end..], steps_from::<PER_DIM>(steps, end));
+        dot_by(a, end, |i| int8_lanes::<PER_DIM>(codes, steps, i))
+            + super::scalar::dot_int8::<PER_DIM>(tail.0, tail.1, tail.2)
+    }
+
+    #[target_feature(enable = "avx2,fma,f16c")]
+    unsafe fn l2_squared_int8<const PER_DIM: bool>(
+        a: &[Number],
+        codes: &[i8],
+        steps: &[Number],
+    ) -> Number {
+        let end = a.len() - a.len() % WIDTH;
+        let tail = (&a[end..], &codes[end..], steps_from::<PER_DIM>(steps, end));
+        l2_squared_by(a, end, |i| int8_lanes::<PER_DIM>(codes, steps, i))
+            + super::scalar::l2_squared_int8::<PER_DIM>(tail.0, tail.1, tail.2)
+    }
+
+    #[target_feature(enable = "avx2,fma,f16c")]
+    unsafe fn l1_int8<const PER_DIM: bool>(a: &[Number], codes: &[i8], steps: &[Number]) -> Number {
+        let end = a.len() - a.len() % WIDTH;
+        let tail = (&a[end..], &codes[end..], steps_from::<PER_DIM>(steps, end));
+        l1_by(a, end, |i| int8_lanes::<PER_DIM>(codes, steps, i))
+            + super::scalar::l1_int8::<PER_DIM>(tail.0, tail.1, tail.2)
+    }
+
+    #[target_feature(enable = "avx2,fma,f16c")]
+    unsafe fn cosine_int8<const PER_DIM: bool>(
+        a: &[Number],
+        codes: &[i8],
+        steps: &[Number],
+    ) -> CosineParts {
+        let end = a.len() - a.len() % WIDTH;
+        let tail = (&a[end..], &codes[end..], steps_from::<PER_DIM>(steps, end));
+        cosine_by(a, end, |i| int8_lanes::<PER_DIM>(codes, steps, i))
+            + super::scalar::cosine_int8::<PER_DIM>(tail.0, tail.1, tail.2)
+    }
+}
+
+/// Tails are read with masked loads, so there is no scalar remainder. Stored
+/// rows narrower than f32 are copied into a zeroed buffer for their last
+/// partial block instead, since masked 8- and 16-bit loads need AVX-512BW.
+#[cfg(target_arch = "x86_64")]
+mod avx512 {
+    use std::arch::x86_64::*;
+
+    use half::{bf16, f16};
+
+    use super::{CosineParts, Element, Int8Kernels, Kernels, RowKernels, SimdLevel};
+    use crate::config::Number;
+
+    pub const KERNELS: Kernels = Kernels {
+        level: SimdLevel::Avx512,
+        dot: |a, b| unsafe { dot(a, b) },
+        l2_squared: |a, b| unsafe { l2_squared(a, b) },
+        l1: |a, b| unsafe { l1(a, b) },
+        cosine: |a, b| unsafe { cosine(a, b) },
+        f16: row_kernels(),
+        bf16: row_kernels(),
+        int8: int8_kernels::<false>(),
+        int8_dim: int8_kernels::<true>(),
+    };
+
+    const fn row_kernels<T: Widen>() -> RowKernels<T> {
+        RowKernels {
+            dot: |a, b| unsafe { dot(a, b) },
+            l2_squared: |a, b| unsafe { l2_squared(a, b) },
+            l1: |a, b| unsafe { l1(a, b) },
+            cosine: |a, b| unsafe { cosine(a, b) },
+        }
+    }
+
+    const fn int8_kernels<const PER_DIM: bool>() -> Int8Kernels {
+        Int8Kernels {
+            dot: |a, codes, steps| unsafe { dot_int8::<PER_DIM>(a, codes, steps) },
+            l2_squared: |a, codes, steps| unsafe { l2_squared_int8::<PER_DIM>(a, codes, steps) },
+            l1: |a, codes, steps| unsafe { l1_int8::<PER_DIM>(a, codes, steps) },
+            cosine: |a, codes, steps| unsafe { cosine_int8::<PER_DIM>(a, codes, steps) },
+        }
+    }
+
+    const WIDTH: usize = 16;
+
+    /// Lanes `i..i + 16` of `a`, zero past the end.
+    #[target_feature(enable = "avx512f")]
+    unsafe fn load(a: &[Number], i: usize) -> __m512 {
+        let remaining = a.len() - i;
+        if remaining >= WIDTH {
+            _mm512_loadu_ps(a.as_ptr().add(i))
+        } else {
+            let mask: __mmask16 = (1 << remaining) - 1;
+            _mm512_maskz_loadu_ps(mask, a.as_ptr().add(i))
+        }
+    }
+
+    /// Call `read` on elements `i..i + 16` of `b`, zero past the end.
+    #[target_feature(enable = "avx512f")]
+    unsafe fn padded<T: Copy + Default, R>(b: &[T], i: usize, read: impl Fn(*const T) -> R) -> R {
+        let remaining = b.len() - i;
+        if remaining >= WIDTH {
+            read(b.as_ptr().add(i))
+        } else {
+            let mut tail = [T::default(); WIDTH];
+            tail[..remaining].copy_from_slice(&b[i..]);
+            read(tail.as_ptr())
+        }
+    }
+
+    /// Element types read sixteen at a time as f32 lanes.
+    trait Widen: Element {
+        /// Elements `i..i + 16` of `b`, zero past the end
+        unsafe fn lanes(b: &[Self], i: usize) -> __m512;
+    }
+
+    impl Widen for Number {
+        #[target_feature(enable = "avx512f")]
+        unsafe fn lanes(b: &[Number], i: usize) -> __m512 {
+            load(b, i)
+        }
+    }
+
+    impl Widen for f16 {
+        #[target_feature(enable = "avx512f")]
+        unsafe fn lanes(b: &[f16], i: usize) -> __m512 {
+            padded(b, i, |p| _mm512_cvtph_ps(_mm256_loadu_si256(p.cast())))
+        }
+    }
+
+    impl Widen for bf16 {
+        /// A bf16 is the top half of an f32, so widening is a 16-bit shift.
+        #[target_feature(enable = "avx512f")]
+        unsafe fn lanes(b: &[bf16], i: usize) -> __m512 {
+            padded(b, i, |p| {
+                let halves = _mm512_cvtepu16_epi32(_mm256_loadu_si256(p.cast()));
+                _mm512_castsi512_ps(_mm512_slli_epi32(halves, 16))
+            })
+        }
+    }
+
+    /// Codes `i..i + 16` times their steps, zero past the end
+    #[target_feature(enable = "avx512f")]
+    unsafe fn int8_lanes<const PER_DIM: bool>(codes: &[i8], steps: &[Number], i: usize) -> __m512 {
+        let values = padded(codes, i, |p| {
+            _mm512_cvtepi32_ps(_mm512_cvtepi8_epi32(_mm_loadu_si128(p.cast())))
+        });
+        let step = if PER_DIM {
+            load(steps, i)
+        } else {
+            _mm512_set1_ps(steps[0])
+        };
+        _mm512_mul_ps(values, step)
+    }
+
+    /// Run the loops below over all of `a`, reading `b` through `lanes`.
+    #[target_feature(enable = "avx512f")]
+    unsafe fn dot_by(a: &[Number], lanes: impl Fn(usize) -> __m512) -> Number {
+        let mut acc = _mm512_setzero_ps();
+        for i in (0..a.len()).step_by(WIDTH) {
+            acc = _mm512_fmadd_ps(load(a, i), lanes(i), acc);
+        }
+        _mm512_reduce_add_ps(acc)
+    }
+
+    #[target_feature(enable = "avx512f")]
+    unsafe fn l2_squared_by(a: &[Number], lanes: impl Fn(usize) -> __m512) -> Number {
+        let mut acc = _mm512_setzero_ps();
+        for i in (0..a.len()).step_by(WIDTH) {
+            let diff = _mm512_sub_ps(load(a, i), lanes(i));
+            acc = _mm512_fmadd_ps(diff, diff, acc);
+        }
+        _mm512_reduce_add_ps(acc)
+    }
+
+    #[target_feature(enable = "avx512f")]
+    unsafe fn l1_by(a: &[Number], lanes: impl Fn(usize) -> __m512) -> Number {
+        let mut acc = _mm512_setzero_ps();
+        for i in (0..a.len()).step_by(WIDTH) {
+            acc = _mm512_add_ps(acc, _mm512_abs_ps(_mm512_sub_ps(load(a, i), lanes(i))));
+        }
+        _mm512_reduce_add_ps(acc)
+    }
+
+    #[target_feature(enable = "avx512f")]
+    unsafe fn cosine_by(a: &[Number], lanes: impl Fn(usize) -> __m512) -> CosineParts {
+        let (mut ab, mut aa, mut bb) = (
+            _mm512_setzero_ps(),
+            _mm512_setzero_ps(),
+            _mm512_setzero_ps(),
+        );
+        for i in (0..a.len()).step_by(WIDTH) {
+            let (x, y) = (load(a, i), lanes(i));
+            ab = _mm512_fmadd_ps(x, y, ab);
+            aa = _mm512_fmadd_ps(x, x, aa);
+            bb = _mm512_fmadd_ps(y, y, bb);
+        }
+        CosineParts {
+            dot: _mm512_reduce_add_ps(ab),
+            a_squared: _mm512_reduce_add_ps(aa),
+            b_squared: _mm512_reduce_add_ps(bb),
+        }
+    }
+
+    #[target_feature(enable = "avx512f")]
+    unsafe fn dot<T: Widen>(a: &[Number], b: &[T]) -> Number {
+        dot_by(a, |i| T::lanes(b, i))
+    }
+
+    #[target_feature(enable = "avx512f")]
+    unsafe fn l2_squared<T: Widen>(a: &[Number], b: &[T]) -> Number {
+        l2_squared_by(a, |i| T::lanes(b, i))
+    }
+
+    #[target_feature(enable = "avx512f")]
+    unsafe fn l1<T: Widen>(a: &[Number], b: &[T]) -> Number {
+        l1_by(a, |i| T::lanes(b, i))
+    }
+
+    #[target_feature(enable = "avx512f")]
+    unsafe fn cosine<T: Widen>(a: &[Number], b: &[T]) -> CosineParts {
+        cosine_by(a, |i| T::lanes(b, i))
+    }
+
+    #[target_feature(enable = "avx512f")]
+    unsafe fn dot_int8<const PER_DIM: bool>(
+        a: &[Number],
+        codes: &[i8],
+        steps: &[Number],
+    ) -> Number {
+        dot_by(a, |i| int8_lanes::<PER_DIM>(codes, steps, i))
+    }
+
+    #[target_feature(enable = "avx512f")]
+    unsafe fn l2_squared_int8<const PER_DIM: bool>(
+        a: &[Number],
+        codes: &[i8],
+        steps: &[Number],
+    ) -> Number {
+        l2_squared_by(a, |i| int8_lanes::<PER_DIM>(codes, steps, i))
+    }
+
+    #[target_feature(enable = "avx512f")]
+    unsafe fn l1_int8<const PER_DIM: bool>(a: &[Number], codes: &[i8], steps: &[Number]) -> Number {
+        l1_by(a, |i| int8_lanes::<PER_DIM>(codes, steps, i))
+    }
+
+    #[target_feature(enable = "avx512f")]
+    unsafe fn cosine_int8<const PER_DIM: bool>(
+        a: &[Number],
+        codes: &[i8],
+        steps: &[Number],
+    ) -> CosineParts {
+        cosine_by(a, |i| int8_lanes::<PER_DIM>(codes, steps, i))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::prelude::*;
+
+    use super::*;
+    use crate::vector_ops::{quantize_int8, DimScaledInt8, Lanes, ScaledInt8, StoredVector};
+
+    /// Lengths around every kernel width, so each tail path is exercised.
+    const LENGTHS: [usize; 16] = [0, 1, 3, 4, 5, 7, 8, 9, 15, 16, 17, 31, 33, 64, 384, 1001];
+
+    fn random_vector(rng: &mut StdRng, len: usize) -> Vec<Number> {
+        (0..len).map(|_| rng.gen_range(-1.0..1.0)).collect()
+    }
+
+    fn assert_close(level: SimdLevel, what: &str, len: usize, expected: Number, actual: Number) {
+        let tolerance = 1e-4 * (1.0 + expected.abs());
+        assert!(
+            (expected - actual).abs() <= tolerance,
+            "{} {} at length {}: expected {}, got {}",
+            level,
+            what,
+            len,
+            expected,
+            actual
+        );
+    }
+
+    fn supported_kernels() -> Vec<Kernels> {
+        SimdLevel::ALL
+            .into_iter()
+            .filter_map(Kernels::for_level)
+            .collect()
+    }
+
+    #[test]
+    fn scalar_matches_naive_sums() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for len in LENGTHS {
+            let (a, b) = (random_vector(&mut rng, len), random_vector(&mut rng, len));
+            let pairs = || a.iter().zip(&b).map(|(&x, &y)| (x as f64, y as f64));
+            let dot: f64 = pairs().map(|(x, y)| x * y).sum();
+            let l2: f64 = pairs().map(|(x, y)| (x - y) * (x - y)).sum();
+            let l1: f64 = pairs().map(|(x, y)| (x - y).abs()).sum();
+            let level = SimdLevel::Scalar;
+            assert_close(level, "dot", len, dot as Number, scalar::dot(&a, &b));
+            assert_close(level, "l2", len, l2 as Number, scalar::l2_squared(&a, &b));
+            assert_close(level, "l1", len, l1 as Number, scalar::l1(&a, &b));
+        }
+    }
+
+    #[test]
+    fn kernels_agree_with_scalar() {
+        let mut rng = StdRng::seed_from_u64(2);
+        for kernels in supported_kernels() {
+            for len in LENGTHS {
+                let (a, b) = (random_vector(&mut rng, len), random_vector(&mut rng, len));
+                let level = kernels.level;
+                assert_close(
+                    level,
+                    "dot",
+                    len,
+                    scalar::dot(&a, &b),
+                    (kernels.dot)(&a, &b),
+                );
+                assert_close(
+                    level,
+                    "l2",
+                    len,
+                    scalar::l2_squared(&a, &b),
+                    (kernels.l2_squared)(&a, &b),
+                );
+                assert_close(level, "l1", len, scalar::l1(&a, &b), (kernels.l1)(&a, &b));
+
+                let (expected, actual) = (scalar::cosine(&a, &b), (kernels.cosine)(&a, &b));
+                assert_close(level, "cosine dot", len, expected.dot, actual.dot);
+                assert_close(level, "|a|^2", len, expected.a_squared, actual.a_squared);
+                assert_close(level, "|b|^2", len, expected.b_squared, actual.b_squared);
+            }
+        }
+    }
+
+    /// Every stored precision's kernels have to match the scalar f32 kernels on
+    /// the decoded vector.
+    fn check_stored<B: Lanes + ?Sized>(a: &[Number], b: &B, decoded: &[Number]) {
+        for kernels in supported_kernels() {
+            let (level, len) = (kernels.level, a.len());
+            let dot = b.dot(&kernels, a);
+            assert_close(level, "stored dot", len, scalar::dot(a, decoded), dot);
+            let l2 = b.l2_squared(&kernels, a);
+            let expected = scalar::l2_squared(a, decoded);
+            assert_close(level, "stored l2", len, expected, l2);
+            let l1 = b.l1(&kernels, a);
+            assert_close(level, "stored l1", len, scalar::l1(a, decoded), l1);
+            let cosine = b.cosine(&kernels, a);
+            let expected = scalar::cosine(a, decoded);
+            assert_close(level, "stored cosine", len, expected.dot, cosine.dot);
+            assert_close(
+                level,
+                "stored |a|^2",
+                len,
+                expected.a_squared,
+                cosine.a_squared,
+            );
+            assert_close(
+                level,
+                "stored |b|^2",
+                len,
+                expected.b_squared,
+                cosine.b_squared,
+            );
+        }
+    }
+
+    #[test]
+    fn quantized_kernels_agree_with_scalar() {
+        let mut rng = StdRng::seed_from_u64(3);
+        for len in LENGTHS {
+            let (a, b) = (random_vector(&mut rng, len), random_vector(&mut rng, len));
+
+            let halves: Vec<f16> = b.iter().map(|&x| f16::from_f32(x)).collect();
+            let decoded = StoredVector::F16(&halves).to_vec();
+            check_stored(&a, halves.as_slice(), &decoded);
+            let brains: Vec<bf16> = b.iter().map(|&x| bf16::from_f32(x)).collect();
+            let decoded = StoredVector::Bf16(&brains).to_vec();
+            check_stored(&a, brains.as_slice(), &decoded);
+
+            let absmax = b.iter().fold(0.0 as Number, |max, x| max.max(x.abs()));
+            let codes: Vec<i8> = b.iter().map(|&x| quantize_int8(x, absmax)).collect();
+            let row = || ScaledInt8 {
+                codes: &codes,
+                absmax,
+            };
+            check_stored(&a, &row(), &StoredVector::Int8(row()).to_vec());
+
+            let ranges: Vec<Number> = (0..len).map(|_| rng.gen_range(0.5..2.0)).collect();
+            let steps: Vec<Number> = ranges.iter().map(|r| r / 127.0).collect();
+            let codes: Vec<i8> = b
+                .iter()
+                .zip(&ranges)
+                .map(|(&x, &range)| quantize_int8(x, range))
+                .collect();
+            let row = || DimScaledInt8 {
+                codes: &codes,
+                absmax: &ranges,
+                steps: &steps,
+            };
+            check_stored(&a, &row(), &StoredVector::Int8Dim(row()).to_vec());
+        }
+    }
+
+    /// Widening has to be exact for every class of half float, including the
+    /// subnormals the SSE kernels convert with integer arithmetic.
+    #[test]
+    fn half_widening_is_exact() {
+        let values: Vec<f16> = [
+            0.0, -0.0, 1.0, -1.5, 65504.0, -65504.0, 6.0e-8, -3.0e-5, 6.1e-5, 0.333, 1e-3, 2.0e4,
+            -7.0, 0.5, 1e-7, -1e-6, 42.0,
+        ]
+        .iter()
+        .map(|&x| f16::from_f32(x))
+        .collect();
+        let brains: Vec<bf16> = values.iter().map(|x| bf16::from_f32(x.to_f32())).collect();
+        for kernels in supported_kernels() {
+            for i in 0..values.len() {
+                let mut unit = vec![0.0; values.len()];
+                unit[i] = 1.0;
+                assert_eq!(
+                    (kernels.f16.dot)(&unit, &values),
+                    values[i].to_f32(),
+                    "{} f16 lane {}",
+                    kernels.level,
+                    i
+                );
+                assert_eq!(
+                    (kernels.bf16.dot)(&unit, &brains),
+                    brains[i].to_f32(),
+                    "{} bf16 lane {}",
+                    kernels.level,
+                    i
+                );
+            }
+        }
+    }
+}
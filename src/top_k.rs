@@ -0,0 +1,90 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+use crate::config::Number;
+
+/// A row id in the vector arena and its similarity to the query.
+pub type ScoredRow = (usize, Number);
+
+#[derive(Clone, Copy, PartialEq)]
+struct Candidate(ScoredRow);
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0
+             .1
+            .total_cmp(&other.0 .1)
+            .then_with(|| other.0 .0.cmp(&self.0 .0))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Bounded selection of the `k` best-scoring rows. The worst current winner sits
+/// at the top of a min-heap, so each push costs at most O(log k) and memory
+/// stays O(k) however many rows are scanned. Rows scoring below `floor` are
+/// never admitted.
+pub struct TopK {
+    k: usize,
+    floor: Number,
+    heap: BinaryHeap<Reverse<Candidate>>,
+}
+
+impl TopK {
+    pub fn new(k: usize, floor: Option<Number>) -> Self {
+        TopK {
+            k,
+            floor: floor.unwrap_or(Number::NEG_INFINITY),
+            heap: BinaryHeap::with_capacity(k + 1),
+        }
+    }
+
+    /// The score a new row has to reach to have any chance of being kept.
+    pub fn bound(&self) -> Number {
+        match self.heap.peek() {
+            Some(Reverse(worst)) if self.heap.len() == self.k => worst.0 .1.max(self.floor),
+            _ => self.floor,
+        }
+    }
+
+    pub fn push(&mut self, row: usize, score: Number) {
+        if self.k == 0 || score < self.bound() {
+            return;
+        }
+        let candidate = Candidate((row, score));
+        if self.heap.len() < self.k {
+            self.heap.push(Reverse(candidate));
+        } else if let Some(Reverse(worst)) = self.heap.peek() {
+            if candidate > *worst {
+                self.heap.pop();
+                self.heap.push(Reverse(candidate));
+            }
+        }
+    }
+
+    pub fn merge(mut self, other: TopK) -> TopK {
+        for Reverse(Candidate((row, score))) in other.heap {
+            self.push(row, score);
+        }
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    /// Winners ordered from best to worst.
+    pub fn into_sorted_vec(self) -> Vec<ScoredRow> {
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(Candidate(scored))| scored)
+            .collect()
+    }
+}
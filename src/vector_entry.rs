@@ -1,16 +1,31 @@
-use crate::config::Number;
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::config::Number;
+
+/// Tag in front of every stored record naming how the rest is encoded, so a
+/// later encoding can be added next to this one instead of replacing it.
+const RECORD_ENCODING_BINCODE: u8 = 1;
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct VectorEntry {
     pub label: String,
     pub unique_id: String,
     pub vector: Vec<Number>,
-    pub metadata: Metadata,
+    /// Free-form JSON object; missing metadata is stored as `{}`.
+    #[serde(default = "empty_metadata")]
+    pub metadata: Value,
+}
+
+pub fn empty_metadata() -> Value {
+    Value::Object(Map::new())
 }
 
+/// Typed view of the code-chunk metadata vekta was first written for.
 #[derive(Deserialize, Serialize, Clone)]
-pub struct Metadata {
+#[serde(deny_unknown_fields)]
+pub struct CodeChunk {
     pub file_path: String,
     pub file_name: String,
     pub chunk_index: usize,
@@ -18,3 +33,90 @@ pub struct Metadata {
     pub end_line: usize,
     pub content_preview: String,
 }
+
+impl CodeChunk {
+    /// The view of `metadata`, if it holds exactly the code-chunk fields.
+    pub fn from_metadata(metadata: &Value) -> Option<Self> {
+        serde_json::from_value(metadata.clone()).ok()
+    }
+}
+
+/// Look up a dotted path such as `author.name` or `tags.0` in a metadata
+/// object. Numeric segments index into arrays.
+pub fn metadata_field<'a>(metadata: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(metadata, |value, segment| match value {
+            Value::Object(map) => map.get(segment),
+            Value::Array(items) => segment.parse::<usize>().ok().and_then(|i| items.get(i)),
+            _ => None,
+        })
+}
+
+/// What LMDB stores for an entry; the vector itself lives in the arena at `row`.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct EntryRecord {
+    pub label: String,
+    pub unique_id: String,
+    pub row: u64,
+    #[serde(with = "msgpack")]
+    pub metadata: Value,
+}
+
+impl EntryRecord {
+    pub fn from_entry(entry: &VectorEntry, row: usize) -> Self {
+        EntryRecord {
+            label: entry.label.clone(),
+            unique_id: entry.unique_id.clone(),
+            row: row as u64,
+            metadata: entry.metadata.clone(),
+        }
+    }
+
+    pub fn into_entry(self, vector: Vec<Number>) -> VectorEntry {
+        VectorEntry {
+            label: self.label,
+            unique_id: self.unique_id,
+            vector,
+            metadata: self.metadata,
+        }
+    }
+
+    /// The bytes stored in LMDB: the encoding tag, then the record.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut bytes = vec![RECORD_ENCODING_BINCODE];
+        bincode::serialize_into(&mut bytes, self)?;
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        match bytes.split_first() {
+            Some((&RECORD_ENCODING_BINCODE, record)) => {
+                bincode::deserialize(record).context("Failed to read a stored record")
+            }
+            Some((encoding, _)) => anyhow::bail!(
+                "Stored record has unknown encoding {}; it was written by a newer vekta",
+                encoding
+            ),
+            None => anyhow::bail!("Stored record is empty"),
+        }
+    }
+}
+
+/// Metadata is kept inside the bincode record as a MessagePack blob, since
+/// bincode cannot describe arbitrary JSON.
+mod msgpack {
+    use serde::de::Error as _;
+    use serde::ser::Error as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde_json::Value;
+
+    pub fn serialize<S: Serializer>(value: &Value, serializer: S) -> Result<S::Ok, S::Error> {
+        let bytes = rmp_serde::to_vec(value).map_err(S::Error::custom)?;
+        serializer.serialize_bytes(&bytes)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Value, D::Error> {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        rmp_serde::from_slice(&bytes).map_err(D::Error::custom)
+    }
+}
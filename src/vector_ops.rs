@@ -1,68 +1,375 @@
-use crate::config::{Number, EPSILON};
-use wide::f32x8;
+use crate::config::{verbose_print, Number, EPSILON};
+use crate::simd::{self, CosineParts, Kernels};
+use half::slice::HalfFloatSliceExt;
+use half::{bf16, f16};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// How vectors are compared. Every metric is turned into a score where higher
+/// means more similar, so distance metrics report the negated distance.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Metric {
+    /// Cosine similarity remapped to `[0, 1]`; vectors are stored normalized
+    Cosine,
+    /// Raw inner product, for models trained for maximum inner product search
+    Dot,
+    /// Negated Euclidean distance
+    L2,
+    /// Negated Manhattan distance
+    L1,
+}
+
+impl Metric {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Metric::Cosine => "cosine",
+            Metric::Dot => "dot",
+            Metric::L2 => "l2",
+            Metric::L1 => "l1",
+        }
+    }
+
+    /// Whether vectors should be normalized before they are stored or queried.
+    pub fn normalizes(&self) -> bool {
+        matches!(self, Metric::Cosine)
+    }
+
+    pub fn score<B: Lanes + ?Sized>(&self, a: &[Number], b: &B) -> Option<Number> {
+        match self {
+            Metric::Cosine => compute_cosine_similarity_simd(a, b),
+            Metric::Dot => compute_dot_product_simd(a, b),
+            Metric::L2 => compute_l2_distance_simd(a, b).map(|d| 0.0 - d),
+            Metric::L1 => compute_l1_distance_simd(a, b).map(|d| 0.0 - d),
+        }
+    }
+
+    /// Score a query against a vector in whatever precision it is stored in.
+    pub fn score_stored(&self, a: &[Number], b: &StoredVector) -> Option<Number> {
+        match b {
+            StoredVector::F32(b) => self.score(a, *b),
+            StoredVector::F16(b) => self.score(a, *b),
+            StoredVector::Bf16(b) => self.score(a, *b),
+            StoredVector::Int8(b) => self.score(a, b),
+            StoredVector::Int8Dim(b) => self.score(a, b),
+        }
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Metric {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "cosine" => Ok(Metric::Cosine),
+            "dot" => Ok(Metric::Dot),
+            "l2" => Ok(Metric::L2),
+            "l1" => Ok(Metric::L1),
+            _ => anyhow::bail!("Unknown metric '{}': expected cosine, dot, l2 or l1", s),
+        }
+    }
+}
+
+/// How vectors are stored on disk. Fixed per database when it is created.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Precision {
+    /// 32-bit floats, stored as given
+    F32,
+    /// IEEE half precision
+    F16,
+    /// bfloat16: the f32 exponent range with a 7-bit mantissa
+    Bf16,
+    /// One byte per dimension, scaled by the vector's largest magnitude
+    Int8,
+    /// One byte per dimension, scaled by a per-dimension range fitted to the data
+    Int8Dim,
+}
+
+impl Precision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Precision::F32 => "f32",
+            Precision::F16 => "f16",
+            Precision::Bf16 => "bf16",
+            Precision::Int8 => "int8",
+            Precision::Int8Dim => "int8-dim",
+        }
+    }
+
+    /// Bytes one stored vector takes. Per-vector int8 rows start with the
+    /// vector's scale as an f32, which keeps every row 4-byte aligned.
+    pub fn row_bytes(&self, dimensions: usize) -> usize {
+        match self {
+            Precision::F32 => dimensions * 4,
+            Precision::F16 | Precision::Bf16 => dimensions * 2,
+            Precision::Int8 => 4 + dimensions,
+            Precision::Int8Dim => dimensions,
+        }
+    }
+}
+
+impl fmt::Display for Precision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Precision {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "f32" => Ok(Precision::F32),
+            "f16" => Ok(Precision::F16),
+            "bf16" => Ok(Precision::Bf16),
+            "int8" => Ok(Precision::Int8),
+            "int8-dim" => Ok(Precision::Int8Dim),
+            _ => anyhow::bail!(
+                "Unknown precision '{}': expected f32, f16, bf16, int8 or int8-dim",
+                s
+            ),
+        }
+    }
+}
+
+/// Map an int8 code back to a value in `[-absmax, absmax]`. Dividing by 127
+/// first keeps the extreme codes exact, so re-encoding a decoded vector gives
+/// back the same codes.
+pub fn dequantize_int8(code: i8, absmax: Number) -> Number {
+    absmax * (code as Number / 127.0)
+}
+
+pub fn quantize_int8(value: Number, absmax: Number) -> i8 {
+    if absmax <= 0.0 {
+        return 0;
+    }
+    ((value / absmax).clamp(-1.0, 1.0) * 127.0).round() as i8
+}
+
+/// A vector borrowed from storage in its stored precision.
+pub enum StoredVector<'a> {
+    F32(&'a [Number]),
+    F16(&'a [f16]),
+    Bf16(&'a [bf16]),
+    Int8(ScaledInt8<'a>),
+    Int8Dim(DimScaledInt8<'a>),
+}
+
+impl StoredVector<'_> {
+    pub fn to_vec(&self) -> Vec<Number> {
+        match self {
+            StoredVector::F32(v) => v.to_vec(),
+            StoredVector::F16(v) => v.to_f32_vec(),
+            StoredVector::Bf16(v) => v.to_f32_vec(),
+            StoredVector::Int8(v) => v
+                .codes
+                .iter()
+                .map(|&code| dequantize_int8(code, v.absmax))
+                .collect(),
+            StoredVector::Int8Dim(v) => v
+                .codes
+                .iter()
+                .zip(v.absmax)
+                .map(|(&code, &absmax)| dequantize_int8(code, absmax))
+                .collect(),
+        }
+    }
+}
+
+pub struct ScaledInt8<'a> {
+    pub codes: &'a [i8],
+    pub absmax: Number,
+}
+
+pub struct DimScaledInt8<'a> {
+    pub codes: &'a [i8],
+    /// Largest magnitude per dimension
+    pub absmax: &'a [Number],
+    /// `absmax / 127` per dimension, precomputed for the kernels
+    pub steps: &'a [Number],
+}
+
+/// The right-hand side of a similarity kernel: any stored vector, read in
+/// place by the kernels for its precision.
+pub trait Lanes {
+    fn dimensions(&self) -> usize;
+    fn dot(&self, kernels: &Kernels, a: &[Number]) -> Number;
+    /// Squared Euclidean distance
+    fn l2_squared(&self, kernels: &Kernels, a: &[Number]) -> Number;
+    fn l1(&self, kernels: &Kernels, a: &[Number]) -> Number;
+    fn cosine(&self, kernels: &Kernels, a: &[Number]) -> CosineParts;
+}
+
+impl Lanes for [Number] {
+    fn dimensions(&self) -> usize {
+        self.len()
+    }
+
+    fn dot(&self, kernels: &Kernels, a: &[Number]) -> Number {
+        (kernels.dot)(a, self)
+    }
+
+    fn l2_squared(&self, kernels: &Kernels, a: &[Number]) -> Number {
+        (kernels.l2_squared)(a, self)
+    }
+
+    fn l1(&self, kernels: &Kernels, a: &[Number]) -> Number {
+        (kernels.l1)(a, self)
+    }
+
+    fn cosine(&self, kernels: &Kernels, a: &[Number]) -> CosineParts {
+        (kernels.cosine)(a, self)
+    }
+}
+
+impl Lanes for [f16] {
+    fn dimensions(&self) -> usize {
+        self.len()
+    }
+
+    fn dot(&self, kernels: &Kernels, a: &[Number]) -> Number {
+        (kernels.f16.dot)(a, self)
+    }
+
+    fn l2_squared(&self, kernels: &Kernels, a: &[Number]) -> Number {
+        (kernels.f16.l2_squared)(a, self)
+    }
+
+    fn l1(&self, kernels: &Kernels, a: &[Number]) -> Number {
+        (kernels.f16.l1)(a, self)
+    }
+
+    fn cosine(&self, kernels: &Kernels, a: &[Number]) -> CosineParts {
+        (kernels.f16.cosine)(a, self)
+    }
+}
+
+impl Lanes for [bf16] {
+    fn dimensions(&self) -> usize {
+        self.len()
+    }
+
+    fn dot(&self, kernels: &Kernels, a: &[Number]) -> Number {
+        (kernels.bf16.dot)(a, self)
+    }
+
+    fn l2_squared(&self, kernels: &Kernels, a: &[Number]) -> Number {
+        (kernels.bf16.l2_squared)(a, self)
+    }
+
+    fn l1(&self, kernels: &Kernels, a: &[Number]) -> Number {
+        (kernels.bf16.l1)(a, self)
+    }
+
+    fn cosine(&self, kernels: &Kernels, a: &[Number]) -> CosineParts {
+        (kernels.bf16.cosine)(a, self)
+    }
+}
+
+impl ScaledInt8<'_> {
+    /// The value one code step stands for, as the single-entry step slice the
+    /// int8 kernels take.
+    fn step(&self) -> [Number; 1] {
+        [self.absmax / 127.0]
+    }
+}
+
+impl Lanes for ScaledInt8<'_> {
+    fn dimensions(&self) -> usize {
+        self.codes.len()
+    }
+
+    fn dot(&self, kernels: &Kernels, a: &[Number]) -> Number {
+        (kernels.int8.dot)(a, self.codes, &self.step())
+    }
+
+    fn l2_squared(&self, kernels: &Kernels, a: &[Number]) -> Number {
+        (kernels.int8.l2_squared)(a, self.codes, &self.step())
+    }
+
+    fn l1(&self, kernels: &Kernels, a: &[Number]) -> Number {
+        (kernels.int8.l1)(a, self.codes, &self.step())
+    }
+
+    fn cosine(&self, kernels: &Kernels, a: &[Number]) -> CosineParts {
+        (kernels.int8.cosine)(a, self.codes, &self.step())
+    }
+}
+
+impl Lanes for DimScaledInt8<'_> {
+    fn dimensions(&self) -> usize {
+        self.codes.len()
+    }
+
+    fn dot(&self, kernels: &Kernels, a: &[Number]) -> Number {
+        (kernels.int8_dim.dot)(a, self.codes, self.steps)
+    }
+
+    fn l2_squared(&self, kernels: &Kernels, a: &[Number]) -> Number {
+        (kernels.int8_dim.l2_squared)(a, self.codes, self.steps)
+    }
+
+    fn l1(&self, kernels: &Kernels, a: &[Number]) -> Number {
+        (kernels.int8_dim.l1)(a, self.codes, self.steps)
+    }
+
+    fn cosine(&self, kernels: &Kernels, a: &[Number]) -> CosineParts {
+        (kernels.int8_dim.cosine)(a, self.codes, self.steps)
+    }
+}
 
 /// Compute cosine similarity between two pre-normalized vectors using SIMD operations.
 /// Both input vectors `a` and `b` are expected to be normalized before calling this function.
-pub fn compute_cosine_similarity_simd(a: &[Number], b: &[Number]) -> Option<Number> {
-    if a.len() != b.len() {
-        println!("Debug: Vector length mismatch: {} vs {}", a.len(), b.len());
+pub fn compute_cosine_similarity_simd<B: Lanes + ?Sized>(a: &[Number], b: &B) -> Option<Number> {
+    if a.len() != b.dimensions() {
+        verbose_print(&format!(
+            "Vector length mismatch: {} vs {}",
+            a.len(),
+            b.dimensions()
+        ));
         return None;
     }
 
-    let mut dot_product = f32x8::splat(0.0);
-    let mut mag_a = f32x8::splat(0.0);
-    let mut mag_b = f32x8::splat(0.0);
-
-    let len = a.len();
-    let simd_len = len - (len % 8);
-
-    // SIMD loop
-    for i in (0..simd_len).step_by(8) {
-        let va = f32x8::new([
-            a[i],
-            a[i + 1],
-            a[i + 2],
-            a[i + 3],
-            a[i + 4],
-            a[i + 5],
-            a[i + 6],
-            a[i + 7],
-        ]);
-        let vb = f32x8::new([
-            b[i],
-            b[i + 1],
-            b[i + 2],
-            b[i + 3],
-            b[i + 4],
-            b[i + 5],
-            b[i + 6],
-            b[i + 7],
-        ]);
-        dot_product += va * vb;
-        mag_a += va * va;
-        mag_b += vb * vb;
-    }
-
-    let mut scalar_dot_product = dot_product.reduce_add();
-    let mut scalar_mag_a = mag_a.reduce_add();
-    let mut scalar_mag_b = mag_b.reduce_add();
-
-    // Handle remaining elements
-    for i in simd_len..len {
-        scalar_dot_product += a[i] * b[i];
-        scalar_mag_a += a[i] * a[i];
-        scalar_mag_b += b[i] * b[i];
-    }
-
-    let denominator = (scalar_mag_a * scalar_mag_b).sqrt();
+    let parts = b.cosine(simd::kernels(), a);
+    let denominator = (parts.a_squared * parts.b_squared).sqrt();
     if denominator < EPSILON {
-        println!("Debug: Denominator too small: {}", denominator);
+        verbose_print(&format!("Denominator too small: {}", denominator));
         Some(0.0)
     } else {
-        let similarity = ((scalar_dot_product / denominator).clamp(-1.0, 1.0) + 1.0) / 2.0;
-        println!("Debug: Computed similarity: {}", similarity);
-        Some(similarity)
+        Some(((parts.dot / denominator).clamp(-1.0, 1.0) + 1.0) / 2.0)
+    }
+}
+
+/// Inner product of two vectors using SIMD operations.
+pub fn compute_dot_product_simd<B: Lanes + ?Sized>(a: &[Number], b: &B) -> Option<Number> {
+    if a.len() != b.dimensions() {
+        return None;
+    }
+    Some(b.dot(simd::kernels(), a))
+}
+
+/// Euclidean distance between two vectors using SIMD operations.
+pub fn compute_l2_distance_simd<B: Lanes + ?Sized>(a: &[Number], b: &B) -> Option<Number> {
+    if a.len() != b.dimensions() {
+        return None;
+    }
+    Some(b.l2_squared(simd::kernels(), a).sqrt())
+}
+
+/// Manhattan distance between two vectors using SIMD operations.
+pub fn compute_l1_distance_simd<B: Lanes + ?Sized>(a: &[Number], b: &B) -> Option<Number> {
+    if a.len() != b.dimensions() {
+        return None;
     }
+    Some(b.l1(simd::kernels(), a))
 }
 
 pub fn normalize_vector(vector: &mut [Number]) {